@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use chrono::prelude::*;
+use chrono::Duration;
+
+#[derive(Debug, PartialEq)]
+pub enum ArgError {
+    MissingValue(String),
+    UnknownFlag(String),
+    InvalidDuration(String),
+    InvalidTime(String),
+}
+
+impl fmt::Display for ArgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArgError::MissingValue(flag) => write!(f, "`--{}` needs a value", flag),
+            ArgError::UnknownFlag(flag) => write!(f, "`--{}` is not supported here", flag),
+            ArgError::InvalidDuration(value) => write!(
+                f,
+                "`{}` is not a valid duration, use a form like `30m`, `48h` or `2d`",
+                value
+            ),
+            ArgError::InvalidTime(value) => write!(
+                f,
+                "`{}` is not a valid time, use a form like `2022-06-20 08:00` or `monday`",
+                value
+            ),
+        }
+    }
+}
+
+/// Slash command text split into positional arguments and `--flag value` pairs
+#[derive(Debug, PartialEq, Default)]
+pub struct CommandArgs {
+    pub positional: Vec<String>,
+    pub flags: HashMap<String, String>,
+}
+
+impl CommandArgs {
+    pub fn parse(text: &str) -> Result<Self, ArgError> {
+        let mut args = CommandArgs::default();
+        let mut tokens = tokenize(text).into_iter();
+        while let Some(token) = tokens.next() {
+            match token.strip_prefix("--") {
+                Some(flag) => {
+                    let value = tokens
+                        .next()
+                        .ok_or_else(|| ArgError::MissingValue(flag.to_string()))?;
+                    args.flags.insert(flag.to_string(), value);
+                }
+                None => args.positional.push(token),
+            }
+        }
+        Ok(args)
+    }
+
+    /// Fails if any flag outside `allowed` was given
+    pub fn only_flags(&self, allowed: &[&str]) -> Result<(), ArgError> {
+        match self
+            .flags
+            .keys()
+            .find(|flag| !allowed.contains(&flag.as_str()))
+        {
+            Some(flag) => Err(ArgError::UnknownFlag(flag.clone())),
+            None => Ok(()),
+        }
+    }
+
+    pub fn flag(&self, name: &str) -> Option<&str> {
+        self.flags.get(name).map(|value| value.as_str())
+    }
+}
+
+// Splits on whitespace, keeping quoted strings together. Slack may send curly quotes.
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in text.chars() {
+        match c {
+            '"' | '\u{201c}' | '\u{201d}' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+/// Parses durations such as `45m`, `48h`, `2d` or `1d12h`
+pub fn parse_duration(value: &str) -> Result<Duration, ArgError> {
+    let invalid = || ArgError::InvalidDuration(value.to_string());
+    let mut total = Duration::zero();
+    let mut number = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let n: i64 = number.parse().map_err(|_| invalid())?;
+        number.clear();
+        total = total
+            + match c {
+                's' => Duration::seconds(n),
+                'm' => Duration::minutes(n),
+                'h' => Duration::hours(n),
+                'd' => Duration::days(n),
+                'w' => Duration::weeks(n),
+                _ => return Err(invalid()),
+            };
+    }
+    if !number.is_empty() || total <= Duration::zero() {
+        return Err(invalid());
+    }
+    Ok(total)
+}
+
+/// Parses an absolute time in the timezone of `now`. Accepts RFC 3339, `YYYY-MM-DD HH:MM`,
+/// `YYYY-MM-DD` (midnight) and a weekday name with an optional time, such as `monday` or
+/// `monday 07:30`, which means the next such day at 08:00 unless a time is given.
+pub fn parse_time<Tz: TimeZone>(
+    value: &str,
+    now: &DateTime<Tz>,
+) -> Result<DateTime<Utc>, ArgError> {
+    let invalid = || ArgError::InvalidTime(value.to_string());
+    let value = value.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let naive = ["%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()
+                .map(|date| date.and_hms(0, 0, 0))
+        });
+    let naive = match naive {
+        Some(naive) => naive,
+        None => next_weekday(value, now).ok_or_else(invalid)?,
+    };
+    let time = now
+        .timezone()
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(invalid)?;
+    Ok(time.with_timezone(&Utc))
+}
+
+fn next_weekday<Tz: TimeZone>(value: &str, now: &DateTime<Tz>) -> Option<NaiveDateTime> {
+    let mut parts = value.split_whitespace();
+    let weekday: Weekday = parts.next()?.parse().ok()?;
+    let time = match parts.next() {
+        Some(time) => NaiveTime::parse_from_str(time, "%H:%M").ok()?,
+        None => NaiveTime::from_hms(8, 0, 0),
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    let today = now.naive_local().date();
+    let days_ahead = (7 + weekday.num_days_from_monday() as i64
+        - today.weekday().num_days_from_monday() as i64)
+        % 7;
+    // "monday" on a monday means next week
+    let days_ahead = if days_ahead == 0 { 7 } else { days_ahead };
+    Some((today + Duration::days(days_ahead)).and_time(time))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse_args() {
+        let args = CommandArgs::parse("2-114 A12 --for 48h --until \"2022-06-20 08:00\"").unwrap();
+        assert_eq!(args.positional, vec!["2-114", "A12"]);
+        assert_eq!(args.flag("for"), Some("48h"));
+        assert_eq!(args.flag("until"), Some("2022-06-20 08:00"));
+        assert_eq!(
+            args.only_flags(&["for"]),
+            Err(ArgError::UnknownFlag("until".to_string()))
+        );
+        assert_eq!(
+            CommandArgs::parse("2-114 --for"),
+            Err(ArgError::MissingValue("for".to_string()))
+        );
+    }
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("48h").unwrap(), Duration::hours(48));
+        assert_eq!(parse_duration("1d12h").unwrap(), Duration::hours(36));
+        assert_eq!(parse_duration("90m").unwrap(), Duration::minutes(90));
+        assert!(parse_duration("48").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("3y").is_err());
+    }
+    #[test]
+    fn test_parse_time() {
+        // A Wednesday
+        let now = Utc.ymd(2022, 6, 15).and_hms(10, 0, 0);
+        assert_eq!(
+            parse_time("2022-06-20 08:00", &now).unwrap(),
+            Utc.ymd(2022, 6, 20).and_hms(8, 0, 0)
+        );
+        assert_eq!(
+            parse_time("2022-06-20T08:00:00+02:00", &now).unwrap(),
+            Utc.ymd(2022, 6, 20).and_hms(6, 0, 0)
+        );
+        assert_eq!(
+            parse_time("monday", &now).unwrap(),
+            Utc.ymd(2022, 6, 20).and_hms(8, 0, 0)
+        );
+        assert_eq!(
+            parse_time("Wed 07:30", &now).unwrap(),
+            Utc.ymd(2022, 6, 22).and_hms(7, 30, 0)
+        );
+        assert!(parse_time("someday", &now).is_err());
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::cvp::{self, CloudVisionError, Device, ElementType, TagQuery};
+use crate::tags::{self, Assignment};
+
+/// Status of a device that is streaming to CloudVision
+const STREAMING: &str = "STREAMING_STATUS_ACTIVE";
+
+#[derive(Deserialize, Debug, PartialEq, Default)]
+pub struct AuditConfig {
+    // Wall jacks that do not match are reported, for example "^[0-9]+-[0-9]+$"
+    #[serde(default)]
+    pub jack_pattern: Option<String>,
+}
+
+/// Problems found in the wall jack tags
+#[derive(Debug, Default, PartialEq)]
+pub struct AuditReport {
+    pub assignments: usize,
+    // Assigned to a device CloudVision no longer has in its inventory
+    pub orphans: Vec<Assignment>,
+    // Tags that are not assigned to any interface
+    pub unused_tags: Vec<String>,
+    // Jacks assigned to more than one interface
+    pub duplicate_jacks: BTreeMap<String, Vec<Assignment>>,
+    // Interfaces with more than one jack
+    pub shared_ports: BTreeMap<(String, String), Vec<String>>,
+    // Assigned to a device that is not streaming, so changes to it will fail
+    pub not_streaming: Vec<Assignment>,
+    // Jacks the port commands cannot address or that break the site's naming pattern
+    pub bad_format: Vec<Assignment>,
+}
+
+impl AuditReport {
+    pub fn problems(&self) -> usize {
+        self.orphans.len()
+            + self.unused_tags.len()
+            + self.duplicate_jacks.len()
+            + self.shared_ports.len()
+            + self.not_streaming.len()
+            + self.bad_format.len()
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{} wall jack assignments checked, {} problems: {} orphaned, {} unused tags, {} duplicate jacks, {} interfaces with several jacks, {} on devices not streaming, {} badly formatted",
+            self.assignments,
+            self.problems(),
+            self.orphans.len(),
+            self.unused_tags.len(),
+            self.duplicate_jacks.len(),
+            self.shared_ports.len(),
+            self.not_streaming.len(),
+            self.bad_format.len()
+        )
+    }
+
+    /// The full report as plain text
+    pub fn render(&self) -> String {
+        let line = |a: &Assignment| format!("  {} {} {}", a.value, a.device_id, a.interface_id);
+        let mut out = vec![self.summary()];
+        let mut section = |title: &str, lines: Vec<String>| {
+            if !lines.is_empty() {
+                out.push(String::new());
+                out.push(title.to_string());
+                out.extend(lines);
+            }
+        };
+        section(
+            "Orphaned, device not in inventory:",
+            self.orphans.iter().map(line).collect(),
+        );
+        section(
+            "Tags not assigned to any interface:",
+            self.unused_tags
+                .iter()
+                .map(|t| format!("  {}", t))
+                .collect(),
+        );
+        section(
+            "Jacks on more than one interface:",
+            self.duplicate_jacks.values().flatten().map(line).collect(),
+        );
+        section(
+            "Interfaces with more than one jack:",
+            self.shared_ports
+                .iter()
+                .map(|((device, interface), jacks)| {
+                    format!("  {} {} {}", device, interface, jacks.join(", "))
+                })
+                .collect(),
+        );
+        section(
+            "On devices that are not streaming:",
+            self.not_streaming.iter().map(line).collect(),
+        );
+        section(
+            "Badly formatted:",
+            self.bad_format.iter().map(line).collect(),
+        );
+        out.join("\n")
+    }
+}
+
+// Jacks the slash commands cannot address, the list syntax splits on these
+fn addressable(jack: &str) -> bool {
+    !jack.is_empty()
+        && !jack.contains("..")
+        && !jack.contains(|c: char| c == ',' || c == '=' || c.is_whitespace())
+}
+
+/// Joins the tags, their interface assignments and the device inventory
+pub fn audit(
+    tag_values: &[String],
+    assignments: &[Assignment],
+    devices: &[Device],
+    pattern: Option<&Regex>,
+) -> AuditReport {
+    let devices: HashMap<&str, &Device> = devices
+        .iter()
+        .map(|d| (d.key.device_id.as_str(), d))
+        .collect();
+    let mut report = AuditReport {
+        assignments: assignments.len(),
+        ..AuditReport::default()
+    };
+    let mut by_jack: BTreeMap<String, Vec<Assignment>> = BTreeMap::new();
+    let mut by_port: BTreeMap<(String, String), Vec<String>> = BTreeMap::new();
+    for assignment in assignments {
+        match devices.get(assignment.device_id.as_str()) {
+            None => report.orphans.push(assignment.clone()),
+            Some(device) if device.streaming_status != STREAMING => {
+                report.not_streaming.push(assignment.clone())
+            }
+            Some(_) => {}
+        }
+        let matches_pattern = pattern.is_none_or(|p| p.is_match(&assignment.value));
+        if !addressable(&assignment.value) || !matches_pattern {
+            report.bad_format.push(assignment.clone());
+        }
+        by_jack
+            .entry(assignment.value.clone())
+            .or_default()
+            .push(assignment.clone());
+        by_port
+            .entry((
+                assignment.device_id.clone(),
+                assignment.interface_id.clone(),
+            ))
+            .or_default()
+            .push(assignment.value.clone());
+    }
+    let assigned: HashSet<&str> = assignments.iter().map(|a| a.value.as_str()).collect();
+    report.unused_tags = tag_values
+        .iter()
+        .filter(|t| !assigned.contains(t.as_str()))
+        .cloned()
+        .collect();
+    report.unused_tags.sort();
+    report.unused_tags.dedup();
+    report.duplicate_jacks = by_jack.into_iter().filter(|(_, a)| a.len() > 1).collect();
+    report.shared_ports = by_port.into_iter().filter(|(_, j)| j.len() > 1).collect();
+    report
+}
+
+/// Reads the tags, assignments and inventory from CloudVision and audits them
+pub async fn run(cv: &cvp::Host, pattern: Option<&Regex>) -> Result<AuditReport, CloudVisionError> {
+    let query = TagQuery::interface().label(tags::WALL_JACK);
+    let tag_responses: Vec<cvp::TagResponse> = cvp::parse_stream(&cv.get_tags(&query).await?)?;
+    let tag_values: Vec<String> = tag_responses
+        .into_iter()
+        .map(|t| t.result.value.key)
+        .filter(|key| {
+            key.label == tags::WALL_JACK
+                && key.workspace_id.is_empty()
+                && key.element_type == ElementType::Interface
+        })
+        .map(|key| key.value)
+        .collect();
+    let assignments = tags::assignments(cv, tags::WALL_JACK, None, None).await?;
+    let devices = cv.get_all_devices().await?;
+    Ok(audit(&tag_values, &assignments, &devices, pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn device(id: &str, streaming_status: &str) -> Device {
+        let mut device = Device::default();
+        device.key.device_id = id.to_string();
+        device.streaming_status = streaming_status.to_string();
+        device
+    }
+    #[test]
+    fn test_audit() {
+        let devices = vec![
+            device("JPE1", STREAMING),
+            device("JPE2", "STREAMING_STATUS_INACTIVE"),
+        ];
+        let assignments = vec![
+            Assignment::new("2-101", "JPE1", "Ethernet1"),
+            Assignment::new("2-101", "JPE1", "Ethernet2"),
+            Assignment::new("2-102", "JPE1", "Ethernet2"),
+            Assignment::new("2-103", "JPE2", "Ethernet1"),
+            Assignment::new("2-104", "GONE1", "Ethernet1"),
+            Assignment::new("room 4", "JPE1", "Ethernet9"),
+        ];
+        let tag_values = vec!["2-101".to_string(), "2-199".to_string()];
+        let pattern = Regex::new("^[0-9]+-[0-9]+$").unwrap();
+        let report = audit(&tag_values, &assignments, &devices, Some(&pattern));
+        assert_eq!(report.orphans, vec![assignments[4].clone()]);
+        assert_eq!(report.not_streaming, vec![assignments[3].clone()]);
+        assert_eq!(report.unused_tags, vec!["2-199"]);
+        assert_eq!(report.duplicate_jacks["2-101"].len(), 2);
+        assert_eq!(
+            report.shared_ports[&("JPE1".to_string(), "Ethernet2".to_string())],
+            vec!["2-101", "2-102"]
+        );
+        assert_eq!(report.bad_format, vec![assignments[5].clone()]);
+        assert_eq!(report.problems(), 6);
+        assert!(report
+            .render()
+            .contains("Orphaned, device not in inventory:\n  2-104 GONE1 Ethernet1"));
+    }
+    #[test]
+    fn test_addressable() {
+        assert!(addressable("2-114"));
+        assert!(!addressable("A1..A3"));
+        assert!(!addressable("room 4"));
+        assert!(!addressable("desk=4"));
+    }
+}
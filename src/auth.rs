@@ -0,0 +1,195 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use chrono::prelude::*;
+use chrono::Duration;
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::cvp;
+use crate::secret::Secret;
+use crate::slack;
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct TokenConfig {
+    // Hours before a token expires to renew it, or to warn when it cannot be renewed
+    #[serde(default = "default_renew_hours")]
+    pub renew_hours: i64,
+    // Slack channel told about tokens that are about to expire or could not be renewed
+    #[serde(default)]
+    pub admin_channel: Option<String>,
+}
+
+fn default_renew_hours() -> i64 {
+    72
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        TokenConfig {
+            renew_hours: default_renew_hours(),
+            admin_channel: None,
+        }
+    }
+}
+
+/// Where the token of a CloudVision cluster comes from
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenSource {
+    // Given in the config or on the command line
+    Fixed(Secret),
+    // A service account token file, read again when it changes
+    File(PathBuf),
+    // A session token from logging in, renewed by logging in again
+    Login { username: String, password: Secret },
+}
+
+/// When a JWT expires, from its `exp` claim. None for tokens that are not JWTs or never expire.
+pub fn expiry(token: &str) -> Option<DateTime<Utc>> {
+    let payload = token.split('.').nth(1)?;
+    let json =
+        base64::decode_config(payload.trim_end_matches('='), base64::URL_SAFE_NO_PAD).ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&json).ok()?;
+    Utc.timestamp_opt(claims.get("exp")?.as_i64()?, 0).single()
+}
+
+// A token is renewed once it is within `renew_before` of expiring
+fn renewal_due(expires: Option<DateTime<Utc>>, renew_before: Duration, now: DateTime<Utc>) -> bool {
+    expires.is_some_and(|at| at - renew_before <= now)
+}
+
+/// Keeps the token of a cluster current
+#[derive(Debug)]
+pub struct TokenWatch {
+    site: String,
+    source: TokenSource,
+    // When the token file was last read
+    modified: Option<SystemTime>,
+    // Expiry the admin channel was warned about, so each token is only warned about once
+    warned: Option<DateTime<Utc>>,
+    // A renewal failed and the admin channel was told
+    failing: bool,
+}
+
+impl TokenWatch {
+    pub fn new(site: &str, source: TokenSource) -> Self {
+        TokenWatch {
+            site: site.to_string(),
+            source,
+            modified: None,
+            warned: None,
+            failing: false,
+        }
+    }
+
+    /// Reads a changed token file, logs in again when a session token is about to expire, and
+    /// returns a warning for the admin channel about a token that needs a person to replace it
+    pub async fn check(&mut self, cv: &cvp::Host, renew_before: Duration) -> Option<String> {
+        match &self.source {
+            TokenSource::Fixed(token) => {
+                if cv.token().is_none() && !token.is_empty() {
+                    cv.set_token(token.clone());
+                }
+            }
+            TokenSource::File(path) => {
+                let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+                if cv.token().is_none() || modified != self.modified {
+                    if let Err(err) = cv.get_token_from_file(path.display().to_string()) {
+                        return self.fail(format!(
+                            "Could not read the CloudVision token for {} from {}: {}",
+                            self.site,
+                            path.display(),
+                            err
+                        ));
+                    }
+                    info!(site = %self.site, path = %path.display(), "Read the CloudVision token");
+                    self.modified = modified;
+                    self.failing = false;
+                }
+            }
+            TokenSource::Login { username, password } => {
+                let token = cv.token();
+                let expires = token.as_ref().and_then(|t| expiry(t.expose()));
+                if token.is_none() || renewal_due(expires, renew_before, Utc::now()) {
+                    if let Err(err) = cv.login(username, password).await {
+                        return self.fail(format!(
+                            "Could not renew the CloudVision token for {}: {}",
+                            self.site, err
+                        ));
+                    }
+                    info!(site = %self.site, "Logged in to CloudVision");
+                    self.failing = false;
+                }
+                return None;
+            }
+        }
+        let expires = expiry(cv.token()?.expose())?;
+        let now = Utc::now();
+        if !renewal_due(Some(expires), renew_before, now) || self.warned == Some(expires) {
+            return None;
+        }
+        self.warned = Some(expires);
+        let when = if expires <= now { "expired" } else { "expires" };
+        Some(format!(
+            "The CloudVision service account token for {} {} {}, replace it before port changes fail",
+            self.site,
+            when,
+            slack::format_date(&expires)
+        ))
+    }
+
+    // Reports a failure once until the token works again
+    fn fail(&mut self, text: String) -> Option<String> {
+        warn!("{}", text);
+        if self.failing {
+            return None;
+        }
+        self.failing = true;
+        Some(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn jwt(exp: i64) -> String {
+        let claims = format!("{{\"sub\":\"svc-slack\",\"exp\":{}}}", exp);
+        format!(
+            "eyJhbGciOiJIUzI1NiJ9.{}.sig",
+            base64::encode_config(claims, base64::URL_SAFE_NO_PAD)
+        )
+    }
+    #[test]
+    fn test_expiry() {
+        let at = Utc.ymd(2022, 6, 20).and_hms(8, 0, 0);
+        assert_eq!(expiry(&jwt(at.timestamp())), Some(at));
+        assert_eq!(expiry("not-a-jwt"), None);
+        assert_eq!(expiry("eyJhbGciOiJIUzI1NiJ9.e30.sig"), None);
+
+        let now = Utc.ymd(2022, 6, 17).and_hms(8, 0, 0);
+        assert!(renewal_due(Some(at), Duration::hours(72), now));
+        assert!(!renewal_due(Some(at), Duration::hours(71), now));
+        assert!(!renewal_due(None, Duration::hours(72), now));
+    }
+    #[test]
+    fn test_token_file() {
+        let filename = std::env::temp_dir().join("slack-port-config-watch-token.txt");
+        let expires = Utc::now() + Duration::hours(1);
+        fs::write(&filename, jwt(expires.timestamp())).unwrap();
+        let cv = cvp::Host::new("foo", 443);
+        let mut watch = TokenWatch::new("emea", TokenSource::File(filename.clone()));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let warning = runtime.block_on(watch.check(&cv, Duration::hours(72)));
+        assert!(warning.unwrap().contains("token for emea expires"));
+        assert_eq!(cv.token().unwrap().expose(), jwt(expires.timestamp()));
+        // The same token is only warned about once
+        assert!(runtime
+            .block_on(watch.check(&cv, Duration::hours(72)))
+            .is_none());
+        fs::remove_file(&filename).unwrap();
+        watch.modified = Some(SystemTime::UNIX_EPOCH);
+        let failed = runtime.block_on(watch.check(&cv, Duration::hours(72)));
+        assert!(failed.unwrap().starts_with("Could not read"));
+    }
+}
@@ -0,0 +1,347 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use chrono::prelude::*;
+
+use crate::{PortAction, PortOptions};
+
+/// Upper bound on the number of jacks a single bulk request can touch
+pub const MAX_JACKS: usize = 256;
+/// Rows per table chunk, keeps each section under Slack's 3000 character limit
+const ROWS_PER_CHUNK: usize = 40;
+
+#[derive(Debug, PartialEq)]
+pub enum JackListError {
+    Empty,
+    InvalidRange(String),
+    TooMany(usize),
+}
+
+impl fmt::Display for JackListError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JackListError::Empty => write!(f, "No wall jacks were given"),
+            JackListError::InvalidRange(range) => write!(
+                f,
+                "`{}` is not a valid range, use the form `2-101..2-124`",
+                range
+            ),
+            JackListError::TooMany(count) => write!(
+                f,
+                "{} wall jacks requested, the limit is {}",
+                count, MAX_JACKS
+            ),
+        }
+    }
+}
+
+/// Splits a `label=value` jack such as `desk=4F-22`, a bare jack has no label
+pub fn split_label(jack: &str) -> (Option<&str>, &str) {
+    match jack.split_once('=') {
+        Some((label, value)) => (Some(label), value),
+        None => (None, jack),
+    }
+}
+
+/// Expands a wall jack list such as `A12,A13,A15` or a range such as `2-101..2-124`.
+/// Items may be separated by commas or whitespace, duplicates are dropped. A label given as
+/// `desk=4F-20..4F-22` applies to every jack in the range.
+pub fn parse_jack_list(spec: &str) -> Result<Vec<String>, JackListError> {
+    let mut jacks: Vec<String> = Vec::new();
+    for item in spec
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|item| !item.is_empty())
+    {
+        let (label, value) = split_label(item);
+        let mut expanded = match value.split_once("..") {
+            Some((start, end)) => expand_range(start, end)
+                .ok_or_else(|| JackListError::InvalidRange(item.to_string()))?,
+            None => vec![value.to_string()],
+        };
+        if let Some(label) = label {
+            for jack in &mut expanded {
+                *jack = format!("{}={}", label, jack);
+            }
+        }
+        for jack in expanded {
+            if !jacks.contains(&jack) {
+                jacks.push(jack);
+            }
+        }
+        if jacks.len() > MAX_JACKS {
+            return Err(JackListError::TooMany(jacks.len()));
+        }
+    }
+    if jacks.is_empty() {
+        return Err(JackListError::Empty);
+    }
+    Ok(jacks)
+}
+
+// Splits a jack into its prefix and trailing number, "2-101" becomes ("2-", "101")
+fn split_number(jack: &str) -> Option<(&str, &str)> {
+    let digits = jack
+        .chars()
+        .rev()
+        .take_while(|c| c.is_ascii_digit())
+        .count();
+    if digits == 0 {
+        return None;
+    }
+    Some(jack.split_at(jack.len() - digits))
+}
+
+fn expand_range(start: &str, end: &str) -> Option<Vec<String>> {
+    let (start_prefix, start_num) = split_number(start)?;
+    let (end_prefix, end_num) = split_number(end)?;
+    if start_prefix != end_prefix {
+        return None;
+    }
+    let first: usize = start_num.parse().ok()?;
+    let last: usize = end_num.parse().ok()?;
+    if first > last || last - first >= MAX_JACKS {
+        return None;
+    }
+    // Keep zero padding such as A01..A12
+    let width = if start_num.starts_with('0') {
+        start_num.len()
+    } else {
+        0
+    };
+    Some(
+        (first..=last)
+            .map(|n| format!("{}{:0width$}", start_prefix, n, width = width))
+            .collect(),
+    )
+}
+
+/// Outcome of looking up a single wall jack in CloudVision
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lookup {
+    Found {
+        device_id: String,
+        interface_id: String,
+    },
+    NotFound,
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct JackTarget {
+    pub jack: String,
+    pub lookup: Lookup,
+}
+
+/// A bulk port change waiting for the user to confirm it
+#[derive(Debug)]
+pub struct BulkRequest {
+    pub action: PortAction,
+    pub targets: Vec<JackTarget>,
+    pub created: DateTime<Utc>,
+    pub options: PortOptions,
+    // Device id to hostname, for the replies
+    pub hostnames: HashMap<String, String>,
+}
+
+impl BulkRequest {
+    pub fn new(action: PortAction, targets: Vec<JackTarget>, options: PortOptions) -> Self {
+        BulkRequest {
+            action,
+            targets,
+            created: Utc::now(),
+            options,
+            hostnames: HashMap::new(),
+        }
+    }
+
+    /// Interfaces to change, grouped by device so each device can run as its own stage
+    pub fn ports_by_device(&self) -> BTreeMap<String, Vec<String>> {
+        let mut devices: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for target in &self.targets {
+            if let Lookup::Found {
+                device_id,
+                interface_id,
+            } = &target.lookup
+            {
+                let interfaces = devices.entry(device_id.clone()).or_default();
+                if !interfaces.contains(interface_id) {
+                    interfaces.push(interface_id.clone());
+                }
+            }
+        }
+        devices
+    }
+}
+
+// Hostname of a device, its serial number when the hostname is not known
+fn switch_name<'a>(hostnames: &'a HashMap<String, String>, device_id: &'a str) -> &'a str {
+    hostnames
+        .get(device_id)
+        .map(|h| h.as_str())
+        .filter(|h| !h.is_empty())
+        .unwrap_or(device_id)
+}
+
+/// Renders the jack lookups as fixed width tables, split into chunks that fit in a section
+pub fn summary_tables(targets: &[JackTarget], hostnames: &HashMap<String, String>) -> Vec<String> {
+    targets
+        .chunks(ROWS_PER_CHUNK)
+        .map(|chunk| {
+            let mut table = format!("```{:<12} {:<14} {}\n", "Jack", "Switch", "Interface");
+            for target in chunk {
+                let row = match &target.lookup {
+                    Lookup::Found {
+                        device_id,
+                        interface_id,
+                    } => format!(
+                        "{:<12} {:<14} {}",
+                        target.jack,
+                        switch_name(hostnames, device_id),
+                        interface_id
+                    ),
+                    Lookup::NotFound => format!("{:<12} {:<14} not found", target.jack, "-"),
+                    Lookup::Failed(err) => {
+                        format!("{:<12} {:<14} lookup failed: {}", target.jack, "-", err)
+                    }
+                };
+                table.push_str(&row);
+                table.push('\n');
+            }
+            table.push_str("```");
+            table
+        })
+        .collect()
+}
+
+/// Per-jack results once the change control for a bulk request has run
+pub fn result_lines(request: &BulkRequest, outcome: &Result<String, String>) -> Vec<String> {
+    let done = match request.options.start_at {
+        Some(_) => format!("will be {}", request.action.past_tense()),
+        None => request.action.past_tense().to_string(),
+    };
+    request
+        .targets
+        .iter()
+        .map(|target| match (&target.lookup, outcome) {
+            (
+                Lookup::Found {
+                    interface_id,
+                    device_id,
+                },
+                Ok(_),
+            ) => format!(
+                ":white_check_mark: {} ({} on {}) {}",
+                target.jack,
+                interface_id,
+                switch_name(&request.hostnames, device_id),
+                done
+            ),
+            (
+                Lookup::Found {
+                    interface_id,
+                    device_id,
+                },
+                Err(err),
+            ) => format!(
+                ":x: {} ({} on {}) failed: {}",
+                target.jack,
+                interface_id,
+                switch_name(&request.hostnames, device_id),
+                err
+            ),
+            (Lookup::NotFound, _) => format!(":warning: {} was not found, skipped", target.jack),
+            (Lookup::Failed(err), _) => {
+                format!(":warning: {} lookup failed, skipped: {}", target.jack, err)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse_jack_list() {
+        let jacks = parse_jack_list("A12,A13, A15").unwrap();
+        assert_eq!(jacks, vec!["A12", "A13", "A15"]);
+        let jacks = parse_jack_list("2-114").unwrap();
+        assert_eq!(jacks, vec!["2-114"]);
+    }
+    #[test]
+    fn test_parse_jack_range() {
+        let jacks = parse_jack_list("2-101..2-104,A1").unwrap();
+        assert_eq!(jacks, vec!["2-101", "2-102", "2-103", "2-104", "A1"]);
+        let jacks = parse_jack_list("A08..A10").unwrap();
+        assert_eq!(jacks, vec!["A08", "A09", "A10"]);
+    }
+    #[test]
+    fn test_parse_jack_labels() {
+        let jacks = parse_jack_list("desk=4F-21..4F-22 room=101 2-114").unwrap();
+        assert_eq!(jacks, vec!["desk=4F-21", "desk=4F-22", "room=101", "2-114"]);
+        assert_eq!(split_label("desk=4F-21"), (Some("desk"), "4F-21"));
+        assert_eq!(split_label("2-114"), (None, "2-114"));
+    }
+    #[test]
+    fn test_parse_jack_list_errors() {
+        assert_eq!(parse_jack_list(" "), Err(JackListError::Empty));
+        assert_eq!(
+            parse_jack_list("2-110..2-101"),
+            Err(JackListError::InvalidRange("2-110..2-101".to_string()))
+        );
+        assert_eq!(
+            parse_jack_list("A1..B4"),
+            Err(JackListError::InvalidRange("A1..B4".to_string()))
+        );
+        assert_eq!(
+            parse_jack_list("1..1000"),
+            Err(JackListError::InvalidRange("1..1000".to_string()))
+        );
+    }
+    #[test]
+    fn test_ports_by_device() {
+        let found = |jack: &str, device: &str, interface: &str| JackTarget {
+            jack: jack.to_string(),
+            lookup: Lookup::Found {
+                device_id: device.to_string(),
+                interface_id: interface.to_string(),
+            },
+        };
+        let targets = vec![
+            found("A1", "JPE1", "Ethernet1"),
+            found("A2", "JPE2", "Ethernet1"),
+            found("A3", "JPE1", "Ethernet2"),
+            JackTarget {
+                jack: "A4".to_string(),
+                lookup: Lookup::NotFound,
+            },
+        ];
+        let request = BulkRequest::new(PortAction::Shut, targets, PortOptions::default());
+        let devices = request.ports_by_device();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices["JPE1"], vec!["Ethernet1", "Ethernet2"]);
+        assert_eq!(devices["JPE2"], vec!["Ethernet1"]);
+    }
+    #[test]
+    fn test_hostnames() {
+        let targets = vec![JackTarget {
+            jack: "A1".to_string(),
+            lookup: Lookup::Found {
+                device_id: "JPE1".to_string(),
+                interface_id: "Ethernet1".to_string(),
+            },
+        }];
+        let mut request = BulkRequest::new(PortAction::Shut, targets, PortOptions::default());
+        // Without a hostname the serial number is shown
+        let tables = summary_tables(&request.targets, &request.hostnames);
+        assert!(tables[0].contains("A1           JPE1"));
+        request
+            .hostnames
+            .insert("JPE1".to_string(), "sw-2f-1".to_string());
+        let tables = summary_tables(&request.targets, &request.hostnames);
+        assert!(tables[0].contains("A1           sw-2f-1"));
+        assert_eq!(
+            result_lines(&request, &Ok("cc1".to_string())),
+            vec![":white_check_mark: A1 (Ethernet1 on sw-2f-1) shut down"]
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::prelude::*;
+use chrono::Duration;
+use serde::Deserialize;
+use tokio::sync::Notify;
+
+use crate::cvp::{self, CloudVisionError};
+use crate::tags::{self, Assignment};
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct CacheConfig {
+    // Seconds between full reloads, 0 turns the cache off
+    #[serde(default = "default_refresh")]
+    pub refresh_secs: u64,
+}
+
+fn default_refresh() -> u64 {
+    300
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            refresh_secs: default_refresh(),
+        }
+    }
+}
+
+/// Label and value to interface and device to hostname, as loaded from CloudVision
+#[derive(Debug)]
+pub struct JackIndex {
+    jacks: HashMap<(String, String), Assignment>,
+    hostnames: HashMap<String, String>,
+    loaded: DateTime<Utc>,
+}
+
+impl JackIndex {
+    pub fn new(hostnames: HashMap<String, String>) -> Self {
+        JackIndex {
+            jacks: HashMap::new(),
+            hostnames,
+            loaded: Utc::now(),
+        }
+    }
+
+    /// Adds the assignments of a lookup label
+    pub fn with_label(mut self, label: &str, assignments: Vec<Assignment>) -> Self {
+        for assignment in assignments {
+            // A jack tagged twice keeps its first interface, as a live lookup would
+            self.jacks
+                .entry((label.to_string(), assignment.value.clone()))
+                .or_insert(assignment);
+        }
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.jacks.len()
+    }
+}
+
+/// Reads every assignment of the lookup labels and the device inventory
+pub async fn load(cv: &cvp::Host, labels: &[String]) -> Result<JackIndex, CloudVisionError> {
+    let devices = cv.get_all_devices().await?;
+    let hostnames = devices
+        .into_iter()
+        .map(|d| (d.key.device_id, d.hostname))
+        .collect();
+    let mut index = JackIndex::new(hostnames);
+    for label in labels {
+        let assignments = tags::assignments(cv, label, None, None).await?;
+        index = index.with_label(label, assignments);
+    }
+    Ok(index)
+}
+
+/// Shared cache of the jack index. Lookups that miss fall back to CloudVision, so an empty or
+/// invalidated cache only costs speed.
+#[derive(Debug, Default)]
+pub struct JackCache {
+    index: Mutex<Option<JackIndex>>,
+    invalidated: Notify,
+}
+
+impl JackCache {
+    /// The interface a jack is on and how old that information is
+    pub fn get(&self, label: &str, jack: &str) -> Option<(Assignment, Duration)> {
+        let index = self.index.lock().unwrap();
+        let index = index.as_ref()?;
+        let assignment = index
+            .jacks
+            .get(&(label.to_string(), jack.to_string()))?
+            .clone();
+        Some((assignment, Utc::now() - index.loaded))
+    }
+
+    /// Adds a jack found by a live lookup
+    pub fn insert(&self, label: &str, assignment: Assignment) {
+        if let Some(index) = self.index.lock().unwrap().as_mut() {
+            index
+                .jacks
+                .insert((label.to_string(), assignment.value.clone()), assignment);
+        }
+    }
+
+    pub fn hostname(&self, device_id: &str) -> Option<String> {
+        let index = self.index.lock().unwrap();
+        index.as_ref()?.hostnames.get(device_id).cloned()
+    }
+
+    pub fn replace(&self, index: JackIndex) {
+        *self.index.lock().unwrap() = Some(index);
+    }
+
+    /// Drops the index after the bot changed tags and asks for a reload
+    pub fn invalidate(&self) {
+        *self.index.lock().unwrap() = None;
+        self.invalidated.notify_one();
+    }
+
+    pub async fn invalidated(&self) {
+        self.invalidated.notified().await
+    }
+}
+
+/// Short form of a cache age such as `45s`, `12m` or `3h`
+pub fn format_age(age: Duration) -> String {
+    if age < Duration::minutes(1) {
+        format!("{}s", age.num_seconds().max(0))
+    } else if age < Duration::hours(1) {
+        format!("{}m", age.num_minutes())
+    } else {
+        format!("{}h", age.num_hours())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_cache() {
+        let cache = JackCache::default();
+        let assignment = Assignment::new("2-114", "JPE1", "Ethernet1");
+        // Nothing is cached before the first load
+        cache.insert(tags::WALL_JACK, assignment.clone());
+        assert!(cache.get(tags::WALL_JACK, "2-114").is_none());
+
+        let mut hostnames = HashMap::new();
+        hostnames.insert("JPE1".to_string(), "sw-2f-1".to_string());
+        let duplicate = Assignment::new("2-114", "JPE1", "Ethernet9");
+        let desk = Assignment::new("2-114", "JPE1", "Ethernet3");
+        cache.replace(
+            JackIndex::new(hostnames)
+                .with_label(tags::WALL_JACK, vec![assignment.clone(), duplicate])
+                .with_label("desk", vec![desk.clone()]),
+        );
+        let (found, age) = cache.get(tags::WALL_JACK, "2-114").unwrap();
+        assert_eq!(found, assignment);
+        assert!(age < Duration::seconds(5));
+        assert_eq!(cache.get("desk", "2-114").unwrap().0, desk);
+        assert_eq!(cache.hostname("JPE1").as_deref(), Some("sw-2f-1"));
+
+        cache.insert(
+            tags::WALL_JACK,
+            Assignment::new("2-115", "JPE1", "Ethernet2"),
+        );
+        assert!(cache.get(tags::WALL_JACK, "2-115").is_some());
+        assert!(cache.get("desk", "2-115").is_none());
+        cache.invalidate();
+        assert!(cache.get(tags::WALL_JACK, "2-114").is_none());
+    }
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(Duration::seconds(45)), "45s");
+        assert_eq!(format_age(Duration::seconds(754)), "12m");
+        assert_eq!(format_age(Duration::minutes(185)), "3h");
+    }
+}
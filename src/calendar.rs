@@ -0,0 +1,368 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use chrono::prelude::*;
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// Which devices a freeze or maintenance window applies to. An empty scope applies to every
+/// device, otherwise a device matches if its serial is listed or it carries one of the tags,
+/// written as `label:value`.
+#[derive(Deserialize, Debug, PartialEq, Default, Clone)]
+pub struct Scope {
+    #[serde(default)]
+    pub devices: Vec<String>,
+    #[serde(default)]
+    pub device_tags: Vec<String>,
+}
+
+impl Scope {
+    pub fn matches(&self, device_id: &str, device_tags: &[String]) -> bool {
+        if self.devices.is_empty() && self.device_tags.is_empty() {
+            return true;
+        }
+        self.devices.iter().any(|d| d == device_id)
+            || self.device_tags.iter().any(|t| device_tags.contains(t))
+    }
+}
+
+/// A period where no changes are allowed
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub struct Freeze {
+    pub name: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    #[serde(flatten)]
+    pub scope: Scope,
+}
+
+/// A weekly maintenance window in the bot's local time. A window may wrap past midnight, for
+/// example 22:00 to 06:00, in which case `days` are the days it opens on.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub struct Window {
+    pub name: String,
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    #[serde(flatten)]
+    pub scope: Scope,
+}
+
+impl Window {
+    fn is_open(&self, now: NaiveDateTime) -> bool {
+        let day = now.date().weekday();
+        let time = now.time();
+        if self.start <= self.end {
+            self.days.contains(&day) && time >= self.start && time < self.end
+        } else {
+            (self.days.contains(&day) && time >= self.start)
+                || (self.days.contains(&day.pred()) && time < self.end)
+        }
+    }
+
+    // Times the window opens in the week after `now`
+    fn opens(&self, now: NaiveDateTime) -> impl Iterator<Item = NaiveDateTime> + '_ {
+        (0..=7)
+            .map(move |days| now.date() + Duration::days(days))
+            .filter(|date| self.days.contains(&date.weekday()))
+            .map(|date| date.and_time(self.start))
+            .filter(move |open| *open > now)
+    }
+
+    fn next_open(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        self.opens(now).next()
+    }
+}
+
+/// Freeze periods and maintenance windows that gate every change control the bot creates
+#[derive(Deserialize, Debug, PartialEq, Default)]
+pub struct ChangeCalendar {
+    #[serde(default)]
+    pub freeze: Vec<Freeze>,
+    #[serde(default)]
+    pub window: Vec<Window>,
+    // Slack user ids allowed to use --override
+    #[serde(default)]
+    pub override_users: Vec<String>,
+    // Where overrides and their justification are recorded
+    #[serde(default)]
+    pub override_log: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Verdict {
+    Allowed,
+    Frozen {
+        name: String,
+        until: DateTime<Utc>,
+    },
+    /// Outside every maintenance window that applies, `next_open` is None if none will open
+    OutsideWindow {
+        next_open: Option<DateTime<Utc>>,
+    },
+}
+
+impl ChangeCalendar {
+    pub fn is_empty(&self) -> bool {
+        self.freeze.is_empty() && self.window.is_empty()
+    }
+
+    /// True if any scope needs the device tags to be looked up
+    pub fn uses_device_tags(&self) -> bool {
+        self.freeze
+            .iter()
+            .map(|f| &f.scope)
+            .chain(self.window.iter().map(|w| &w.scope))
+            .any(|scope| !scope.device_tags.is_empty())
+    }
+
+    pub fn can_override(&self, user_id: &str) -> bool {
+        self.override_users.iter().any(|u| u == user_id)
+    }
+
+    /// Checks whether a change to `device_id` may run at `now`, windows are evaluated in the
+    /// timezone of `now`
+    pub fn check<Tz: TimeZone>(
+        &self,
+        now: &DateTime<Tz>,
+        device_id: &str,
+        device_tags: &[String],
+    ) -> Verdict {
+        let utc = now.with_timezone(&Utc);
+        if let Some(freeze) = self
+            .freeze
+            .iter()
+            .filter(|f| f.scope.matches(device_id, device_tags))
+            .filter(|f| f.start <= utc && utc < f.end)
+            .max_by_key(|f| f.end)
+        {
+            return Verdict::Frozen {
+                name: freeze.name.clone(),
+                until: freeze.end,
+            };
+        }
+        let windows: Vec<&Window> = self
+            .window
+            .iter()
+            .filter(|w| w.scope.matches(device_id, device_tags))
+            .collect();
+        let local = now.naive_local();
+        if windows.is_empty() || windows.iter().any(|w| w.is_open(local)) {
+            return Verdict::Allowed;
+        }
+        let next_open = windows
+            .iter()
+            .filter_map(|w| w.next_open(local))
+            .min()
+            .and_then(|open| now.timezone().from_local_datetime(&open).earliest())
+            .map(|open| open.with_timezone(&Utc));
+        Verdict::OutsideWindow { next_open }
+    }
+
+    /// Checks a change to several devices, given with their tags. A freeze on any device wins,
+    /// otherwise a change outside a window waits for a time when the windows of every device
+    /// are open at once, `next_open` is None if there is no such time.
+    pub fn check_devices<Tz: TimeZone>(
+        &self,
+        now: &DateTime<Tz>,
+        devices: &[(String, Vec<String>)],
+    ) -> Verdict {
+        let verdicts: Vec<Verdict> = devices
+            .iter()
+            .map(|(device_id, tags)| self.check(now, device_id, tags))
+            .collect();
+        let mut frozen = verdicts.iter().filter_map(|verdict| match verdict {
+            Verdict::Frozen { name, until } => Some((name, *until)),
+            _ => None,
+        });
+        if let Some((name, until)) = frozen.next() {
+            return Verdict::Frozen {
+                name: name.clone(),
+                until: frozen.map(|(_, until)| until).fold(until, DateTime::max),
+            };
+        }
+        if verdicts.iter().all(|verdict| *verdict == Verdict::Allowed) {
+            return Verdict::Allowed;
+        }
+        // Every device is open together from one of the windows opening
+        let local = now.naive_local();
+        let mut opens: Vec<NaiveDateTime> = self
+            .window
+            .iter()
+            .filter(|w| devices.iter().any(|(id, tags)| w.scope.matches(id, tags)))
+            .flat_map(|w| w.opens(local))
+            .collect();
+        opens.sort();
+        let next_open = opens
+            .iter()
+            .filter_map(|open| now.timezone().from_local_datetime(open).earliest())
+            .find(|open| {
+                devices
+                    .iter()
+                    .all(|(id, tags)| self.check(open, id, tags) == Verdict::Allowed)
+            })
+            .map(|open| open.with_timezone(&Utc));
+        Verdict::OutsideWindow { next_open }
+    }
+
+    /// Appends an override and its justification to the override log
+    pub fn record_override(&self, record: &OverrideRecord) -> io::Result<()> {
+        warn!(
+            user = %record.user_id,
+            justification = %record.justification,
+            overridden = %record.overridden,
+            devices = ?record.devices,
+            "Change calendar override"
+        );
+        if let Some(path) = &self.override_log {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", serde_json::to_string(record)?)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct OverrideRecord {
+    pub time: DateTime<Utc>,
+    pub user_id: String,
+    pub justification: String,
+    pub devices: Vec<String>,
+    // What was overridden, such as the freeze name
+    pub overridden: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn calendar() -> ChangeCalendar {
+        let toml_str = r#"
+        override_users = ["U123"]
+        [[freeze]]
+        name = "Quarter end"
+        start = "2022-06-24T00:00:00Z"
+        end = "2022-07-04T00:00:00Z"
+        [[window]]
+        name = "Weeknights"
+        days = ["Mon", "Tue", "Wed", "Thu", "Fri"]
+        start = "22:00:00"
+        end = "06:00:00"
+        device_tags = ["site:nyc"]
+        "#;
+        toml::from_str(toml_str).unwrap()
+    }
+    #[test]
+    fn test_freeze() {
+        let calendar = calendar();
+        let now = Utc.ymd(2022, 6, 27).and_hms(12, 0, 0);
+        assert_eq!(
+            calendar.check(&now, "JPE1999", &[]),
+            Verdict::Frozen {
+                name: "Quarter end".to_string(),
+                until: Utc.ymd(2022, 7, 4).and_hms(0, 0, 0)
+            }
+        );
+        assert!(calendar.can_override("U123"));
+        assert!(!calendar.can_override("U456"));
+    }
+    #[test]
+    fn test_window() {
+        let calendar = calendar();
+        let nyc = vec!["site:nyc".to_string()];
+        // Wednesday afternoon, only nyc devices are limited to the window
+        let now = Utc.ymd(2022, 6, 15).and_hms(14, 0, 0);
+        assert_eq!(calendar.check(&now, "JPE1999", &[]), Verdict::Allowed);
+        assert_eq!(
+            calendar.check(&now, "JPE1999", &nyc),
+            Verdict::OutsideWindow {
+                next_open: Some(Utc.ymd(2022, 6, 15).and_hms(22, 0, 0))
+            }
+        );
+        // Saturday early morning is still inside Friday night's window
+        let now = Utc.ymd(2022, 6, 18).and_hms(5, 0, 0);
+        assert_eq!(calendar.check(&now, "JPE1999", &nyc), Verdict::Allowed);
+        // Saturday afternoon waits for Monday night
+        let now = Utc.ymd(2022, 6, 18).and_hms(14, 0, 0);
+        assert_eq!(
+            calendar.check(&now, "JPE1999", &nyc),
+            Verdict::OutsideWindow {
+                next_open: Some(Utc.ymd(2022, 6, 20).and_hms(22, 0, 0))
+            }
+        );
+    }
+    #[test]
+    fn test_combine() {
+        let calendar = calendar();
+        let plain = ("JPE1".to_string(), Vec::new());
+        let nyc = ("JPE2".to_string(), vec!["site:nyc".to_string()]);
+        let now = Utc.ymd(2022, 6, 15).and_hms(14, 0, 0);
+        let closed = Verdict::OutsideWindow {
+            next_open: Some(Utc.ymd(2022, 6, 15).and_hms(22, 0, 0)),
+        };
+        assert_eq!(
+            calendar.check_devices(&now, &[plain.clone(), nyc.clone()]),
+            closed
+        );
+        assert_eq!(
+            calendar.check_devices(&now, std::slice::from_ref(&plain)),
+            Verdict::Allowed
+        );
+        let now = Utc.ymd(2022, 6, 27).and_hms(12, 0, 0);
+        assert_eq!(
+            calendar.check_devices(&now, &[plain, nyc]),
+            Verdict::Frozen {
+                name: "Quarter end".to_string(),
+                until: Utc.ymd(2022, 7, 4).and_hms(0, 0, 0)
+            }
+        );
+
+        let toml_str = r#"
+        [[window]]
+        name = "Core"
+        days = ["Mon", "Tue"]
+        start = "01:00:00"
+        end = "02:00:00"
+        devices = ["JPE1"]
+        [[window]]
+        name = "Core late"
+        days = ["Tue"]
+        start = "02:00:00"
+        end = "05:00:00"
+        devices = ["JPE1"]
+        [[window]]
+        name = "Access"
+        days = ["Mon", "Tue"]
+        start = "03:00:00"
+        end = "04:00:00"
+        devices = ["JPE2"]
+        "#;
+        let calendar: ChangeCalendar = toml::from_str(toml_str).unwrap();
+        let devices = [
+            ("JPE1".to_string(), Vec::new()),
+            ("JPE2".to_string(), Vec::new()),
+        ];
+        // Monday's windows do not overlap, Tuesday's late core window covers the access one
+        let now = Utc.ymd(2022, 6, 20).and_hms(0, 0, 0);
+        assert_eq!(
+            calendar.check_devices(&now, &devices),
+            Verdict::OutsideWindow {
+                next_open: Some(Utc.ymd(2022, 6, 21).and_hms(3, 0, 0))
+            }
+        );
+        // Windows that never overlap give no time to queue for
+        let calendar = ChangeCalendar {
+            window: calendar.window[..1]
+                .iter()
+                .chain(&calendar.window[2..])
+                .cloned()
+                .collect(),
+            ..ChangeCalendar::default()
+        };
+        assert_eq!(
+            calendar.check_devices(&now, &devices),
+            Verdict::OutsideWindow { next_open: None }
+        );
+    }
+}
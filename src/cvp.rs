@@ -1,15 +1,37 @@
+use chrono::prelude::*;
 use reqwest::header::*;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs};
+use std::{collections::HashMap, fmt, fs, sync::RwLock, time::Instant};
+use tracing::{debug, instrument};
 use url::Url;
 use uuid::Uuid;
 
+use crate::metrics::{self, Outcome};
+use crate::secret::{self, Secret};
+
 /// Wraps error types when working with CloudVision APIs or parsing
 #[derive(Debug)]
 pub enum CloudVisionError {
     NoToken,
     Request(reqwest::Error),
     JsonParse(serde_json::Error),
+    Workspace(String),
+    Login(String),
+}
+
+impl fmt::Display for CloudVisionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloudVisionError::NoToken => write!(f, "no CloudVision token configured"),
+            CloudVisionError::Request(err) => write!(f, "CloudVision request failed: {}", err),
+            CloudVisionError::JsonParse(err) => {
+                write!(f, "could not parse CloudVision response: {}", err)
+            }
+            CloudVisionError::Workspace(err) => write!(f, "CloudVision workspace error: {}", err),
+            CloudVisionError::Login(err) => write!(f, "CloudVision login failed: {}", err),
+        }
+    }
 }
 
 impl From<reqwest::Error> for CloudVisionError {
@@ -23,6 +45,7 @@ impl From<serde_json::Error> for CloudVisionError {
     }
 }
 
+#[derive(Deserialize, Debug, PartialEq)]
 pub struct Config {
     pub hostname: String,
     pub port: u16,
@@ -37,9 +60,11 @@ impl Config {
             token,
         }
     }
-    pub fn from_file(filename: String) -> Self {
-        // readfile
-        unimplemented!();
+    /// Reads the hostname, port and token from a TOML file
+    pub fn from_file(filename: String) -> Result<Self, std::io::Error> {
+        let toml_str = fs::read_to_string(filename)?;
+        toml::from_str(&toml_str)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
     }
     pub fn from_env() -> Self {
         // read env
@@ -51,7 +76,8 @@ impl Config {
 pub struct Host {
     hostname: String,
     port: u16,
-    pub token: Option<String>,
+    // Replaced while the bot runs when the token is renewed or its file changes
+    token: RwLock<Option<Secret>>,
     pub base_url: String,
 }
 
@@ -64,30 +90,134 @@ pub struct CookieResponse {
     #[serde(rename = "Value")]
     value: String,
 }
-#[derive(Serialize, Deserialize, Debug)]
+// The value of an `access_token=...; Path=/` Set-Cookie header
+fn access_token_cookie(header: &str) -> Option<String> {
+    let (name, value) = header.split(';').next()?.split_once('=')?;
+    (name.trim() == "access_token").then(|| value.trim().to_string())
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct PartialEqFilter {
     pub partial_eq_filter: Vec<Tag>,
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Tag {
     pub key: TagKey,
 }
 
-// TODO: reduce pub fields
-#[derive(Serialize, Deserialize, Debug)]
+/// Filter key for the tag `all` endpoints, fields left as None match anything
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct TagKey {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub workspace_id: Option<String>,
-    pub element_type: Option<String>,
-    //TODO make elementtype enum
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub element_type: Option<ElementType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface_id: Option<String>,
 }
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum ElementType {
-    Unspecified,
+    #[serde(rename = "ELEMENT_TYPE_DEVICE")]
     Device,
+    #[serde(rename = "ELEMENT_TYPE_INTERFACE")]
     Interface,
+    // Also any element type added to CloudVision later
+    #[default]
+    #[serde(rename = "ELEMENT_TYPE_UNSPECIFIED", other)]
+    Unspecified,
+}
+
+/// Builds the filters for reading tags and tag assignments, for example
+/// `TagQuery::interface().label("wall_jack").value("2-114")`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TagQuery {
+    key: TagKey,
+    // Each value is its own filter, the results are the union
+    values: Vec<String>,
+}
+
+impl TagQuery {
+    /// Tags of any element type
+    pub fn all() -> Self {
+        TagQuery::default()
+    }
+    pub fn device() -> Self {
+        TagQuery::element_type(ElementType::Device)
+    }
+    pub fn interface() -> Self {
+        TagQuery::element_type(ElementType::Interface)
+    }
+    pub fn element_type(element_type: ElementType) -> Self {
+        let mut query = TagQuery::default();
+        query.key.element_type = Some(element_type);
+        query
+    }
+    pub fn label(mut self, label: &str) -> Self {
+        self.key.label = Some(label.to_string());
+        self
+    }
+    pub fn value(self, value: &str) -> Self {
+        self.values([value])
+    }
+    /// Matches any of the values
+    pub fn values<I, S>(mut self, values: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.values
+            .extend(values.into_iter().map(|v| v.as_ref().to_string()));
+        self
+    }
+    /// Only assignments on this device, ignored when reading tags
+    pub fn device_id(mut self, device_id: &str) -> Self {
+        self.key.device_id = Some(device_id.to_string());
+        self
+    }
+    pub fn interface_id(mut self, interface_id: &str) -> Self {
+        self.key.interface_id = Some(interface_id.to_string());
+        self
+    }
+    /// Tags in a workspace instead of every workspace and mainline
+    pub fn workspace(mut self, workspace_id: &str) -> Self {
+        self.key.workspace_id = Some(workspace_id.to_string());
+        self
+    }
+
+    pub fn filter(&self) -> PartialEqFilter {
+        let partial_eq_filter = if self.values.is_empty() {
+            vec![Tag {
+                key: self.key.clone(),
+            }]
+        } else {
+            self.values
+                .iter()
+                .map(|value| Tag {
+                    key: TagKey {
+                        value: Some(value.clone()),
+                        ..self.key.clone()
+                    },
+                })
+                .collect()
+        };
+        PartialEqFilter { partial_eq_filter }
+    }
+
+    // The Tag resource has no device or interface in its key
+    fn tag_filter(&self) -> PartialEqFilter {
+        let mut filter = self.filter();
+        for tag in &mut filter.partial_eq_filter {
+            tag.key.device_id = None;
+            tag.key.interface_id = None;
+        }
+        filter
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -113,14 +243,114 @@ pub struct Interface {
 #[serde(rename_all = "camelCase")]
 pub struct InterfaceKey {
     pub workspace_id: String,
-    pub element_type: String,
+    pub element_type: ElementType,
     pub label: String,
     pub value: String,
     pub device_id: String,
+    // Empty for device tag assignments
+    #[serde(default)]
     pub interface_id: String,
 }
+/// Creates or removes a tag assignment inside a workspace
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TagAssignmentConfig {
+    pub key: InterfaceKey,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub remove: bool,
+}
+
+/// Creates a tag inside a workspace
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TagConfig {
+    pub key: TagConfigKey,
+}
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TagConfigKey {
+    pub workspace_id: String,
+    pub element_type: ElementType,
+    pub label: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkspaceKey {
+    pub workspace_id: String,
+}
+
+/// Creates a workspace, or with `request` set asks it to build, submit or abandon
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkspaceConfig {
+    pub key: WorkspaceKey,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_params: Option<RequestParams>,
+}
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
+pub struct RequestParams {
+    pub request_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WorkspaceResponse {
+    pub value: WorkspaceState,
+}
+/// State of a workspace, including the outcome of each build or submit request
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct WorkspaceState {
+    #[serde(default)]
+    pub state: String,
+    #[serde(default)]
+    pub responses: WorkspaceResponses,
+}
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct WorkspaceResponses {
+    #[serde(default)]
+    pub values: HashMap<String, RequestResponse>,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RequestResponse {
+    // RESPONSE_STATUS_SUCCESS or RESPONSE_STATUS_FAIL
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub message: String,
+}
+
+/// A tag from the Tag resource
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TagResponse {
+    pub result: TagResult,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TagResult {
+    pub value: TagValue,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TagValue {
+    pub key: TagConfigKey,
+}
+
+// One device from Device/all
+#[derive(Serialize, Deserialize, Debug)]
+struct DeviceResult {
+    result: DeviceResponse,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeviceResponse {
+    pub value: Device,
+}
+// Inventory leaves fields out for devices that have never streamed
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase", default)]
 pub struct Device {
     pub key: DeviceKey,
     pub software_version: String,
@@ -133,12 +363,40 @@ pub struct Device {
     pub boot_time: String,
     pub streaming_status: String,
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct DeviceKey {
     #[serde(rename = "deviceId")]
     pub device_id: String,
 }
 
+/// Notifications from the telemetry REST API, the state of a device's Sysdb paths
+#[derive(Deserialize, Debug, Default)]
+pub struct TelemetryResponse {
+    #[serde(default)]
+    pub notifications: Vec<Notification>,
+}
+#[derive(Deserialize, Debug)]
+pub struct Notification {
+    #[serde(default)]
+    pub path_elements: Vec<String>,
+    #[serde(default)]
+    pub updates: HashMap<String, Update>,
+}
+#[derive(Deserialize, Debug)]
+pub struct Update {
+    pub value: serde_json::Value,
+}
+
+impl Notification {
+    /// The path element after `collection`, such as the interface in `.../intfStatus/Ethernet1`
+    pub fn entity(&self, collection: &str) -> Option<&str> {
+        // Elements are JSON encoded strings
+        let mut elements = self.path_elements.iter().map(|e| e.trim_matches('"'));
+        elements.find(|e| *e == collection)?;
+        elements.next()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChangeAction {
     change: Change,
@@ -153,6 +411,9 @@ pub struct ChangeConfig {
     pub id: String,
     pub name: String,
     pub root_stage: RootStage,
+    // RFC 3339 time for CloudVision to start an approved change, None to start it ourselves
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub schedule: Option<String>,
 }
 impl ChangeConfig {
     pub fn new(name: String, root_stage: RootStage) -> Self {
@@ -161,8 +422,13 @@ impl ChangeConfig {
             id,
             name,
             root_stage,
+            schedule: None,
         }
     }
+    pub fn with_schedule(mut self, start: DateTime<Utc>) -> Self {
+        self.schedule = Some(start.to_rfc3339_opts(SecondsFormat::Secs, true));
+        self
+    }
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RootStage {
@@ -214,109 +480,362 @@ pub struct StartChange {
     pub cc_id: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeleteChange {
+    pub cc_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChangeControlResponse {
+    pub value: ChangeControlState,
+}
+
+/// State of a change control from the changecontrol resource API
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeControlState {
+    // CHANGE_CONTROL_STATUS_SCHEDULED, _RUNNING, _COMPLETED, ...
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl ChangeControlState {
+    pub fn is_completed(&self) -> bool {
+        self.status == "CHANGE_CONTROL_STATUS_COMPLETED"
+    }
+}
+
+// Reads the body of a response, timing the request and counting it as failed when it did not
+// complete or CloudVision answered with an error status
+async fn record(
+    path: &str,
+    started: Instant,
+    response: Result<reqwest::Response, reqwest::Error>,
+) -> Result<String, CloudVisionError> {
+    let result = match response {
+        Ok(response) => {
+            let status = response.status();
+            response.text().await.map(|body| (status, body))
+        }
+        Err(err) => Err(err),
+    };
+    let ok = result
+        .as_ref()
+        .is_ok_and(|(status, _)| !status.is_client_error() && !status.is_server_error());
+    metrics::metrics().cloudvision_request(path, started.elapsed(), ok);
+    if !ok {
+        metrics::command_outcome(Outcome::Error);
+    }
+    let (status, body) = result?;
+    debug!(status = status.as_u16(), body = %secret::redact(&body), "CloudVision response");
+    Ok(body)
+}
+
+/// How many times to check on a workspace build or submit, two seconds apart
+const WORKSPACE_POLLS: u32 = 90;
+
+/// Parses the stream of JSON objects returned by the resource APIs `all` endpoints.
+/// An empty body yields an empty list.
+pub fn parse_stream<T: DeserializeOwned>(body: &str) -> Result<Vec<T>, CloudVisionError> {
+    let results = serde_json::Deserializer::from_str(body)
+        .into_iter::<T>()
+        .collect::<Result<Vec<T>, _>>()?;
+    Ok(results)
+}
+
 impl Host {
     pub fn new(hostname: &str, port: u16) -> Self {
         Host {
             hostname: hostname.to_string(),
             port,
-            token: None,
+            token: RwLock::new(None),
             //base_url: format!("https://{}:{}", hostname, port),
             base_url: format!("https://{}", hostname),
         }
     }
+    /// The URL of an API path, which can end in a `?query`. `set_path` would percent encode
+    /// the `?`, so the query is set on its own.
     pub fn build_url(&self, path: &str) -> String {
         let mut url = Url::parse(&self.base_url).unwrap();
-        url.set_path(path);
-        url.set_port(Some(self.port));
+        match path.split_once('?') {
+            Some((path, query)) => {
+                url.set_path(path);
+                url.set_query(Some(query));
+            }
+            None => url.set_path(path),
+        }
+        url.set_port(Some(self.port))
+            .expect("base url cannot have a port");
         url.as_str().to_string()
         //format!("{}{}", self.base_url, path)
     }
 
-    pub fn get_token_from_file(&mut self, filename: String) -> Result<(), std::io::Error> {
-        let t = fs::read_to_string(filename)?;
-        let token = t.trim().to_string();
-        self.token = Some(token);
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// `host:port` of the API
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.hostname, self.port)
+    }
+
+    pub fn token(&self) -> Option<Secret> {
+        self.token.read().unwrap().clone()
+    }
+
+    pub fn set_token(&self, token: Secret) {
+        *self.token.write().unwrap() = Some(token);
+    }
+
+    pub fn get_token_from_file(&self, filename: String) -> Result<(), std::io::Error> {
+        let t = Secret::new(fs::read_to_string(filename)?);
+        self.set_token(Secret::new(t.expose().trim().to_string()));
+        Ok(())
+    }
+
+    /// Logs in with a username and password and uses the session token CloudVision returns,
+    /// which comes as the `access_token` cookie
+    #[instrument(skip(self, password), fields(host = %self.hostname))]
+    pub async fn login(&self, username: &str, password: &Secret) -> Result<(), CloudVisionError> {
+        let path = "/cvpservice/login/authenticate.do";
+        let url = self.build_url(path);
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()?;
+        let body = serde_json::json!({ "userId": username, "password": password.expose() });
+        let started = Instant::now();
+        let response = client
+            .post(url)
+            .header(ACCEPT, "application/json")
+            .json(&body)
+            .send()
+            .await;
+        let ok = response
+            .as_ref()
+            .is_ok_and(|response| response.status().is_success());
+        metrics::metrics().cloudvision_request(path, started.elapsed(), ok);
+        let response = response?;
+        if !response.status().is_success() {
+            return Err(CloudVisionError::Login(format!(
+                "CloudVision answered {}",
+                response.status()
+            )));
+        }
+        let cookie = response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .find_map(access_token_cookie);
+        let token = match cookie {
+            Some(token) => token,
+            // Older releases only return the token in the body
+            None => {
+                let body: TokenResponse = response.json().await?;
+                body.cookie.value
+            }
+        };
+        if token.is_empty() {
+            return Err(CloudVisionError::Login("no token was returned".to_string()));
+        }
+        self.set_token(Secret::new(token));
         Ok(())
     }
 
+    #[instrument(skip(self), fields(host = %self.hostname))]
     pub async fn get(&self, path: &str) -> Result<String, CloudVisionError> {
-        if let Some(token) = &self.token {
+        if let Some(token) = &self.token() {
             let url = self.build_url(path);
             let client = reqwest::Client::builder()
                 .danger_accept_invalid_certs(true)
                 .build()?;
+            let started = Instant::now();
             let response = client
                 .get(url)
                 .header(ACCEPT, "application/json")
-                .bearer_auth(token.to_string())
+                .bearer_auth(token.expose())
                 .send()
-                .await?
-                .text()
-                .await?;
+                .await;
+            let response = record(path, started, response).await?;
             Ok(response)
         } else {
             Err(CloudVisionError::NoToken)
         }
     }
+    #[instrument(skip(self, body), fields(host = %self.hostname))]
     async fn post(&self, path: &str, body: String) -> Result<String, CloudVisionError> {
-        if let Some(token) = &self.token {
+        if let Some(token) = &self.token() {
             let url = self.build_url(path);
             let client = reqwest::Client::builder()
                 .danger_accept_invalid_certs(true)
                 .build()?;
+            debug!(body = %secret::redact(&body), "CloudVision request");
+            let started = Instant::now();
             let response = client
                 .post(url)
                 .header(ACCEPT, "application/json")
-                .bearer_auth(token.to_string())
+                .bearer_auth(token.expose())
                 .body(body)
                 .send()
-                .await?
-                .text()
-                .await?;
-            println!("POST response: {}", &response);
+                .await;
+            let response = record(path, started, response).await?;
             Ok(response)
         } else {
             Err(CloudVisionError::NoToken)
         }
     }
-    pub async fn get_tags(&self) -> Result<String, CloudVisionError> {
+    pub async fn get_tags(&self, query: &TagQuery) -> Result<String, CloudVisionError> {
         let path = "/api/resources/tag/v2/Tag/all";
         // TODO: replace this with the url above when cvaas is fixed
         // let path = "/api/v3/services/arista.tag.v2.Tag/GetAll";
-        let workspace_key = TagKey {
-            workspace_id: None,
-            element_type: None,
-            label: None,
-            value: None,
-        };
-        let filter = Tag { key: workspace_key };
-        let data = PartialEqFilter {
-            partial_eq_filter: vec![filter],
-        };
-        let json_data = serde_json::to_string(&data)?;
+        let json_data = serde_json::to_string(&query.tag_filter())?;
         self.post(path, json_data).await
     }
 
     // TODO rework these to return proper values, will need introspection on json deserialization
     pub async fn get_tag_assignment_config(
         &self,
-        partial_eq_filter: PartialEqFilter,
+        query: &TagQuery,
     ) -> Result<String, CloudVisionError> {
         let path = "/api/resources/tag/v2/TagAssignmentConfig/all";
-        let json_data = serde_json::to_string(&partial_eq_filter)?;
+        let json_data = serde_json::to_string(&query.filter())?;
         self.post(path, json_data).await
     }
 
-    pub async fn get_all_devices(&self) -> Result<String, CloudVisionError> {
+    /// Tags assigned to a device, as `label:value` strings
+    pub async fn get_device_tags(&self, device_id: &str) -> Result<Vec<String>, CloudVisionError> {
+        let query = TagQuery::device().device_id(device_id);
+        let body = self.get_tag_assignment_config(&query).await?;
+        let results: Vec<TagAssignmentConfigResponse> = parse_stream(&body)?;
+        Ok(results
+            .into_iter()
+            .map(|r| format!("{}:{}", r.result.value.key.label, r.result.value.key.value))
+            .collect())
+    }
+
+    pub async fn set_tag(&self, tag: TagConfig) -> Result<String, CloudVisionError> {
+        let path = "/api/resources/tag/v2/TagConfig";
+        self.post(path, serde_json::to_string(&tag)?).await
+    }
+
+    pub async fn set_tag_assignment(
+        &self,
+        assignment: TagAssignmentConfig,
+    ) -> Result<String, CloudVisionError> {
+        let path = "/api/resources/tag/v2/TagAssignmentConfig";
+        self.post(path, serde_json::to_string(&assignment)?).await
+    }
+
+    pub async fn create_workspace(
+        &self,
+        workspace_id: &str,
+        display_name: &str,
+        description: &str,
+    ) -> Result<String, CloudVisionError> {
+        let config = WorkspaceConfig {
+            key: WorkspaceKey {
+                workspace_id: workspace_id.to_string(),
+            },
+            display_name: Some(display_name.to_string()),
+            description: Some(description.to_string()),
+            request: None,
+            request_params: None,
+        };
+        let path = "/api/resources/workspace/v1/WorkspaceConfig";
+        self.post(path, serde_json::to_string(&config)?).await
+    }
+
+    pub async fn get_workspace(
+        &self,
+        workspace_id: &str,
+    ) -> Result<WorkspaceState, CloudVisionError> {
+        let path = format!(
+            "/api/resources/workspace/v1/Workspace?key.workspaceId={}",
+            workspace_id
+        );
+        let body = self.get(&path).await?;
+        let response: WorkspaceResponse = serde_json::from_str(&body)?;
+        Ok(response.value)
+    }
+
+    // Sends a workspace request such as REQUEST_START_BUILD and waits for its outcome
+    async fn workspace_request(
+        &self,
+        workspace_id: &str,
+        request: &str,
+    ) -> Result<(), CloudVisionError> {
+        let request_id = Uuid::new_v4().to_string();
+        let config = WorkspaceConfig {
+            key: WorkspaceKey {
+                workspace_id: workspace_id.to_string(),
+            },
+            display_name: None,
+            description: None,
+            request: Some(request.to_string()),
+            request_params: Some(RequestParams {
+                request_id: request_id.clone(),
+            }),
+        };
+        let path = "/api/resources/workspace/v1/WorkspaceConfig";
+        self.post(path, serde_json::to_string(&config)?).await?;
+        for _ in 0..WORKSPACE_POLLS {
+            tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+            let workspace = self.get_workspace(workspace_id).await?;
+            if let Some(response) = workspace.responses.values.get(&request_id) {
+                return match response.status.as_str() {
+                    "RESPONSE_STATUS_SUCCESS" => Ok(()),
+                    _ => Err(CloudVisionError::Workspace(format!(
+                        "{} failed: {}",
+                        request, response.message
+                    ))),
+                };
+            }
+        }
+        Err(CloudVisionError::Workspace(format!(
+            "{} did not finish in time",
+            request
+        )))
+    }
+
+    pub async fn build_workspace(&self, workspace_id: &str) -> Result<(), CloudVisionError> {
+        self.workspace_request(workspace_id, "REQUEST_START_BUILD")
+            .await
+    }
+    pub async fn submit_workspace(&self, workspace_id: &str) -> Result<(), CloudVisionError> {
+        self.workspace_request(workspace_id, "REQUEST_SUBMIT").await
+    }
+    pub async fn abandon_workspace(&self, workspace_id: &str) -> Result<(), CloudVisionError> {
+        self.workspace_request(workspace_id, "REQUEST_ABANDON")
+            .await
+    }
+
+    pub async fn get_all_devices(&self) -> Result<Vec<Device>, CloudVisionError> {
         let path = "/api/resources/inventory/v1/Device/all";
-        self.get(path).await
+        let body = self.get(path).await?;
+        let devices: Vec<DeviceResult> = parse_stream(&body)?;
+        Ok(devices.into_iter().map(|d| d.result.value).collect())
     }
-    pub async fn get_device(&self, device_id: &str) -> Result<String, CloudVisionError> {
+    pub async fn get_device(&self, device_id: &str) -> Result<Device, CloudVisionError> {
         let path = format!(
             "/api/resources/inventory/v1/Device?key.deviceId={}",
             device_id
         );
-        self.get(&path).await
+        let body = self.get(&path).await?;
+        let response: DeviceResponse = serde_json::from_str(&body)?;
+        Ok(response.value)
+    }
+    /// Telemetry for a Sysdb path on a device, such as `/Sysdb/interface/status/eth/phy/slice/1/intfStatus`
+    pub async fn get_telemetry(
+        &self,
+        device_id: &str,
+        path: &str,
+    ) -> Result<Vec<Notification>, CloudVisionError> {
+        let path = format!("/api/v1/rest/{}{}", device_id, path);
+        let body = self.get(&path).await?;
+        let response: TelemetryResponse = serde_json::from_str(&body)?;
+        Ok(response.notifications)
     }
     pub async fn post_change_control(&self, change: String) -> Result<String, CloudVisionError> {
         let path = "/api/v3/services/ccapi.ChangeControl/Update".to_string();
@@ -329,7 +848,6 @@ impl Host {
     ) -> Result<String, CloudVisionError> {
         let approval_json = serde_json::to_string(&approval)?;
         let path = "/api/v3/services/ccapi.ChangeControl/AddApproval".to_string();
-        println!("Approving: {}", &approval_json);
         self.post(&path, approval_json).await
     }
     pub async fn execute_change_control(
@@ -340,6 +858,26 @@ impl Host {
         let path = "/api/v3/services/ccapi.ChangeControl/Start".to_string();
         self.post(&path, start_json).await
     }
+    pub async fn delete_change_control(
+        &self,
+        delete: DeleteChange,
+    ) -> Result<String, CloudVisionError> {
+        let delete_json = serde_json::to_string(&delete)?;
+        let path = "/api/v3/services/ccapi.ChangeControl/Delete".to_string();
+        self.post(&path, delete_json).await
+    }
+    pub async fn get_change_control(
+        &self,
+        cc_id: &str,
+    ) -> Result<ChangeControlState, CloudVisionError> {
+        let path = format!(
+            "/api/resources/changecontrol/v1/ChangeControl?key.id={}",
+            cc_id
+        );
+        let body = self.get(&path).await?;
+        let response: ChangeControlResponse = serde_json::from_str(&body)?;
+        Ok(response.value)
+    }
 }
 
 #[cfg(test)]
@@ -347,19 +885,161 @@ mod tests {
     use super::*;
     #[test]
     fn test_get_token_from_file() {
-        let mut cv = Host::new("foo", 443);
-        cv.get_token_from_file("tokens/token.txt".to_string())
+        let filename = std::env::temp_dir().join("slack-port-config-token.txt");
+        fs::write(&filename, "eyJhbGciOiJIUzI1NiJ9.e30.sig\n").unwrap();
+        let cv = Host::new("foo", 443);
+        cv.get_token_from_file(filename.to_str().unwrap().to_string())
             .unwrap();
-        if let Some(token) = cv.token {
-            assert!(token.starts_with("ey"));
+        if let Some(token) = cv.token() {
+            assert!(token.expose().starts_with("ey"));
         } else {
             panic!("did not read file");
         }
     }
     #[test]
+    fn test_config_from_file() {
+        let filename = std::env::temp_dir().join(format!("cvp-{}.toml", Uuid::new_v4()));
+        fs::write(
+            &filename,
+            "hostname = \"cvp\"\nport = 443\ntoken = \"ey\"\n",
+        )
+        .unwrap();
+        let config = Config::from_file(filename.to_str().unwrap().to_string()).unwrap();
+        assert_eq!(
+            config,
+            Config::new("cvp".to_string(), 443, "ey".to_string())
+        );
+        fs::write(&filename, "hostname = \"cvp\"\n").unwrap();
+        assert!(Config::from_file(filename.to_str().unwrap().to_string()).is_err());
+        fs::remove_file(&filename).unwrap();
+    }
+    #[test]
+    fn test_access_token_cookie() {
+        assert_eq!(
+            access_token_cookie("access_token=eyJhb.e30.sig; Path=/; Secure").as_deref(),
+            Some("eyJhb.e30.sig")
+        );
+        assert_eq!(access_token_cookie("session_id=abc; Path=/"), None);
+    }
+    #[test]
+    fn test_parse_stream() {
+        let body = r#"{"result":{"value":{"key":{"workspaceId":"","elementType":"ELEMENT_TYPE_INTERFACE","label":"wall_jack","value":"2-114","deviceId":"JPE1999","interfaceId":"Ethernet1"}}}}
+{"result":{"value":{"key":{"workspaceId":"","elementType":"ELEMENT_TYPE_INTERFACE","label":"wall_jack","value":"2-114","deviceId":"JPE2000","interfaceId":"Ethernet2"}}}}"#;
+        let results: Vec<TagAssignmentConfigResponse> = parse_stream(body).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].result.value.key.device_id, "JPE2000");
+        let empty: Vec<TagAssignmentConfigResponse> = parse_stream("").unwrap();
+        assert!(empty.is_empty());
+    }
+    #[test]
+    fn test_change_schedule() {
+        let root_stage = RootStage::new("root".to_string(), vec![]);
+        let start = Utc.ymd(2022, 6, 20).and_hms(22, 0, 0);
+        let config = ChangeConfig::new("change".to_string(), root_stage).with_schedule(start);
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(json["schedule"], "2022-06-20T22:00:00Z");
+        let unscheduled = ChangeConfig::new(
+            "change".to_string(),
+            RootStage::new("root".to_string(), vec![]),
+        );
+        assert!(serde_json::to_value(&unscheduled)
+            .unwrap()
+            .get("schedule")
+            .is_none());
+    }
+    #[test]
+    fn test_tag_assignment_config() {
+        let key = InterfaceKey {
+            workspace_id: "ws1".to_string(),
+            element_type: ElementType::Interface,
+            label: "wall_jack".to_string(),
+            value: "2-114".to_string(),
+            device_id: "JPE1999".to_string(),
+            interface_id: "Ethernet1".to_string(),
+        };
+        let remove = TagAssignmentConfig { key, remove: true };
+        let json = serde_json::to_value(&remove).unwrap();
+        assert_eq!(json["key"]["workspaceId"], "ws1");
+        assert_eq!(json["key"]["interfaceId"], "Ethernet1");
+        assert_eq!(json["remove"], true);
+        let add = TagAssignmentConfig {
+            remove: false,
+            ..remove
+        };
+        assert!(serde_json::to_value(&add).unwrap().get("remove").is_none());
+    }
+    #[test]
+    fn test_tag_query() {
+        let query = TagQuery::interface().label("wall_jack").value("2-114");
+        assert_eq!(
+            serde_json::to_value(query.filter()).unwrap(),
+            serde_json::json!({"partial_eq_filter": [{"key": {
+                "element_type": "ELEMENT_TYPE_INTERFACE",
+                "label": "wall_jack",
+                "value": "2-114"
+            }}]})
+        );
+        assert_eq!(
+            serde_json::to_value(TagQuery::all().filter()).unwrap(),
+            serde_json::json!({"partial_eq_filter": [{"key": {}}]})
+        );
+    }
+    #[test]
+    fn test_tag_query_values() {
+        let query = TagQuery::device()
+            .label("floor")
+            .values(["2", "3"])
+            .device_id("JPE1")
+            .workspace("ws1");
+        let json = serde_json::to_value(query.filter()).unwrap();
+        let filters = json["partial_eq_filter"].as_array().unwrap();
+        assert_eq!(filters.len(), 2);
+        assert_eq!(filters[1]["key"]["value"], "3");
+        assert_eq!(filters[1]["key"]["element_type"], "ELEMENT_TYPE_DEVICE");
+        assert_eq!(filters[1]["key"]["workspace_id"], "ws1");
+        assert_eq!(filters[1]["key"]["device_id"], "JPE1");
+        // Tags themselves are not per device
+        let json = serde_json::to_value(query.tag_filter()).unwrap();
+        assert!(json["partial_eq_filter"][0]["key"]
+            .get("device_id")
+            .is_none());
+        let unknown: ElementType = serde_json::from_str("\"ELEMENT_TYPE_NEW\"").unwrap();
+        assert_eq!(unknown, ElementType::Unspecified);
+    }
+    #[test]
+    fn test_workspace_response() {
+        let body = r#"{"value":{"key":{"workspaceId":"ws1"},"state":"WORKSPACE_STATE_PENDING","responses":{"values":{"req1":{"status":"RESPONSE_STATUS_FAIL","message":"build failed"}}}}}"#;
+        let response: WorkspaceResponse = serde_json::from_str(body).unwrap();
+        let request = &response.value.responses.values["req1"];
+        assert_eq!(request.status, "RESPONSE_STATUS_FAIL");
+        assert_eq!(request.message, "build failed");
+    }
+    #[test]
+    fn test_parse_devices() {
+        let body = r#"{"result":{"value":{"key":{"deviceId":"JPE1999"},"softwareVersion":"4.28.0F","modelName":"CCS-720XP-48ZC2","hostname":"sw-2f-1","streamingStatus":"STREAMING_STATUS_ACTIVE"}}}
+{"result":{"value":{"key":{"deviceId":"JPE2000"},"streamingStatus":"STREAMING_STATUS_INACTIVE"}}}"#;
+        let devices: Vec<DeviceResult> = parse_stream(body).unwrap();
+        let first = &devices[0].result.value;
+        assert_eq!(first.key.device_id, "JPE1999");
+        assert_eq!(first.hostname, "sw-2f-1");
+        assert_eq!(first.model_name, "CCS-720XP-48ZC2");
+        // Fields a device never reported are left empty
+        assert_eq!(devices[1].result.value.hostname, "");
+    }
+    #[test]
     fn test_build_url() {
         let cv = Host::new("foo", 8000);
         let url = cv.build_url("/bar");
         assert_eq!(url, "https://foo:8000/bar");
+        let url = cv.build_url("/api/resources/workspace/v1/Workspace?key.workspaceId=ws-1");
+        assert_eq!(
+            url,
+            "https://foo:8000/api/resources/workspace/v1/Workspace?key.workspaceId=ws-1"
+        );
+        let url = cv.build_url("/api/resources/inventory/v1/Device?key.deviceId=JPE123");
+        assert_eq!(
+            url,
+            "https://foo:8000/api/resources/inventory/v1/Device?key.deviceId=JPE123"
+        );
     }
 }
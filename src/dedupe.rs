@@ -0,0 +1,145 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long an envelope is remembered, Slack stops retrying well before this
+pub const TTL: Duration = Duration::from_secs(600);
+/// Most keys remembered at once, the oldest are forgotten first
+pub const CAPACITY: usize = 2000;
+
+/// What is known about an envelope
+#[derive(Debug, Clone, PartialEq)]
+pub enum Seen<T> {
+    New,
+    // The first delivery is still being handled
+    InFlight,
+    // Handled, with the reply that was sent if there was one
+    Done(Option<T>),
+}
+
+#[derive(Debug)]
+struct Entry<T> {
+    at: Instant,
+    result: Option<Option<T>>,
+}
+
+#[derive(Debug)]
+struct Inner<T> {
+    entries: HashMap<String, Entry<T>>,
+    order: VecDeque<String>,
+}
+
+/// Envelope ids, trigger ids and action timestamps already handled, so a delivery Slack retries
+/// gets the first reply instead of running the command again
+#[derive(Debug)]
+pub struct Dedupe<T> {
+    ttl: Duration,
+    capacity: usize,
+    inner: Mutex<Inner<T>>,
+}
+
+impl<T: Clone> Default for Dedupe<T> {
+    fn default() -> Self {
+        Dedupe::new(TTL, CAPACITY)
+    }
+}
+
+impl<T: Clone> Dedupe<T> {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Dedupe {
+            ttl,
+            capacity,
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Looks up the keys of a delivery. When none of them has been seen they are remembered as
+    /// in flight and the delivery is New.
+    pub fn check(&self, keys: &[String]) -> Seen<T> {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        inner.expire(now, self.ttl, self.capacity);
+        if let Some(entry) = keys.iter().find_map(|key| inner.entries.get(key)) {
+            return match &entry.result {
+                Some(result) => Seen::Done(result.clone()),
+                None => Seen::InFlight,
+            };
+        }
+        for key in keys {
+            inner.order.push_back(key.clone());
+            inner.entries.insert(
+                key.clone(),
+                Entry {
+                    at: now,
+                    result: None,
+                },
+            );
+        }
+        Seen::New
+    }
+
+    /// Records the reply to a delivery for its retries
+    pub fn finish(&self, keys: &[String], result: Option<T>) {
+        let mut inner = self.inner.lock().unwrap();
+        for key in keys {
+            if let Some(entry) = inner.entries.get_mut(key) {
+                entry.result = Some(result.clone());
+            }
+        }
+    }
+}
+
+impl<T> Inner<T> {
+    // Keys are added in time order, so expired ones are at the front
+    fn expire(&mut self, now: Instant, ttl: Duration, capacity: usize) {
+        while let Some(key) = self.order.front() {
+            let expired = self
+                .entries
+                .get(key)
+                .is_none_or(|entry| now.duration_since(entry.at) >= ttl);
+            if !expired && self.order.len() < capacity {
+                break;
+            }
+            if let Some(key) = self.order.pop_front() {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+    #[test]
+    fn test_dedupe() {
+        let seen: Dedupe<String> = Dedupe::default();
+        let first = keys(&["envelope:e1", "trigger:t1"]);
+        assert_eq!(seen.check(&first), Seen::New);
+        assert_eq!(seen.check(&first), Seen::InFlight);
+        seen.finish(&first, Some("Wall jack 2-114 shut down".to_string()));
+        // A retry has the same envelope, a second delivery of the command the same trigger
+        assert_eq!(
+            seen.check(&keys(&["envelope:e2", "trigger:t1"])),
+            Seen::Done(Some("Wall jack 2-114 shut down".to_string()))
+        );
+        assert_eq!(seen.check(&keys(&["envelope:e3", "trigger:t3"])), Seen::New);
+    }
+    #[test]
+    fn test_expire() {
+        let seen: Dedupe<()> = Dedupe::new(Duration::from_millis(20), 3);
+        assert_eq!(seen.check(&keys(&["a"])), Seen::New);
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(seen.check(&keys(&["a"])), Seen::New);
+        // Over capacity the oldest key is forgotten
+        seen.check(&keys(&["b", "c"]));
+        seen.check(&keys(&["d"]));
+        assert_eq!(seen.check(&keys(&["a"])), Seen::New);
+        assert_eq!(seen.check(&keys(&["d"])), Seen::InFlight);
+    }
+}
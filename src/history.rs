@@ -0,0 +1,314 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::PortAction;
+
+/// How many change controls are remembered for undo
+pub const MAX_RECORDS: usize = 200;
+
+/// A change control the bot ran, remembered so it can be undone
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChangeRecord {
+    pub site: String,
+    pub cc_id: String,
+    pub user_id: String,
+    // None when the change only replaced settings
+    pub action: Option<PortAction>,
+    #[serde(default)]
+    pub settings: Vec<Setting>,
+    pub walljacks: Vec<String>,
+    // Interfaces changed, grouped by device
+    pub ports: BTreeMap<String, Vec<String>>,
+    pub created: DateTime<Utc>,
+    // Change control that undid this one
+    pub undone_by: Option<String>,
+}
+
+impl ChangeRecord {
+    pub fn new(
+        site: &str,
+        cc_id: &str,
+        user_id: &str,
+        action: impl Into<Option<PortAction>>,
+        walljacks: Vec<String>,
+        ports: BTreeMap<String, Vec<String>>,
+    ) -> Self {
+        ChangeRecord {
+            site: site.to_string(),
+            cc_id: cc_id.to_string(),
+            user_id: user_id.to_string(),
+            action: action.into(),
+            settings: Vec::new(),
+            walljacks,
+            ports,
+            created: Utc::now(),
+            undone_by: None,
+        }
+    }
+
+    /// A record for a change to a single interface
+    pub fn single(
+        site: &str,
+        cc_id: &str,
+        user_id: &str,
+        action: impl Into<Option<PortAction>>,
+        walljack: &str,
+        device_id: &str,
+        interface_id: &str,
+    ) -> Self {
+        let mut ports = BTreeMap::new();
+        ports.insert(device_id.to_string(), vec![interface_id.to_string()]);
+        ChangeRecord::new(
+            site,
+            cc_id,
+            user_id,
+            action,
+            vec![walljack.to_string()],
+            ports,
+        )
+    }
+
+    pub fn with_settings(mut self, settings: Vec<Setting>) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// The change that reverts this one, None if it cannot be undone
+    pub fn inverse(&self) -> Option<Inverse> {
+        let action = match self.action {
+            Some(action) => Some(inverse(action)?),
+            None => None,
+        };
+        let settings = self
+            .settings
+            .iter()
+            .map(inverse_setting)
+            .collect::<Option<Vec<Setting>>>()?;
+        if action.is_none() && settings.is_empty() {
+            return None;
+        }
+        Some(Inverse { action, settings })
+    }
+}
+
+/// A port setting a change control replaced, with the value it had before
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Setting {
+    // The old VLAN can also be "trunk" or "routed"
+    Vlan { from: Option<String>, to: u16 },
+    Description { from: Option<String>, to: String },
+}
+
+/// What reverts a change: the inverse admin state action and the settings to put back
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inverse {
+    pub action: Option<PortAction>,
+    pub settings: Vec<Setting>,
+}
+
+/// Registry of inverse actions. Any action that changes port state must have an entry here
+/// for `/portundo` to revert it.
+pub fn inverse(action: PortAction) -> Option<PortAction> {
+    match action {
+        PortAction::Shut => Some(PortAction::NoShut),
+        PortAction::NoShut => Some(PortAction::Shut),
+    }
+}
+
+/// Registry of inverse settings, which set the value that was replaced. Only an access VLAN
+/// can be put back, the bot cannot set up a trunk or routed port.
+pub fn inverse_setting(setting: &Setting) -> Option<Setting> {
+    match setting {
+        Setting::Vlan { from, to } => {
+            let from = from.as_deref()?.parse::<u16>().ok()?;
+            Some(Setting::Vlan {
+                from: Some(to.to_string()),
+                to: from,
+            })
+        }
+        Setting::Description { from, to } => Some(Setting::Description {
+            from: Some(to.clone()),
+            to: from.clone().unwrap_or_default(),
+        }),
+    }
+}
+
+/// Recent change controls, newest last. Persisted to a JSON file so changes made before a
+/// restart can still be undone.
+#[derive(Debug, Default)]
+pub struct History {
+    // None keeps the history in memory only
+    path: Option<PathBuf>,
+    records: Vec<ChangeRecord>,
+}
+
+impl History {
+    /// Loads the history from `path`, a missing file is an empty history
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let records = match fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(History {
+            path: Some(path.to_path_buf()),
+            records,
+        })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        // Write then rename so a crash never leaves a half written file
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&self.records)?)?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Remembers a change. It is kept even when the file cannot be written, the change control
+    /// has run and can still be undone until a restart.
+    pub fn record(&mut self, record: ChangeRecord) -> io::Result<()> {
+        self.records.push(record);
+        if self.records.len() > MAX_RECORDS {
+            self.records.remove(0);
+        }
+        self.save()
+    }
+
+    pub fn get(&self, cc_id: &str) -> Option<&ChangeRecord> {
+        self.records.iter().find(|record| record.cc_id == cc_id)
+    }
+
+    /// The last change a user made that has not been undone yet
+    pub fn last_for(&self, user_id: &str) -> Option<&ChangeRecord> {
+        self.records
+            .iter()
+            .rev()
+            .find(|record| record.user_id == user_id && record.undone_by.is_none())
+    }
+
+    pub fn mark_undone(&mut self, cc_id: &str, undone_by: &str) -> io::Result<()> {
+        if let Some(record) = self.records.iter_mut().find(|r| r.cc_id == cc_id) {
+            record.undone_by = Some(undone_by.to_string());
+        }
+        self.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_inverse() {
+        let record =
+            ChangeRecord::single("", "cc1", "U123", PortAction::Shut, "2-114", "JPE1", "Et1");
+        assert_eq!(record.inverse().unwrap().action, Some(PortAction::NoShut));
+        assert_eq!(inverse(PortAction::NoShut), Some(PortAction::Shut));
+        assert_eq!(record.ports["JPE1"], vec!["Et1"]);
+    }
+    #[test]
+    fn test_inverse_settings() {
+        let vlan = Setting::Vlan {
+            from: Some("20".to_string()),
+            to: 30,
+        };
+        let description = Setting::Description {
+            from: None,
+            to: "Room 114".to_string(),
+        };
+        let record = ChangeRecord::single("", "cc1", "U123", None, "2-114", "JPE1", "Et1")
+            .with_settings(vec![vlan, description]);
+        let inverse = record.inverse().unwrap();
+        assert_eq!(inverse.action, None);
+        assert_eq!(
+            inverse.settings,
+            vec![
+                Setting::Vlan {
+                    from: Some("30".to_string()),
+                    to: 20
+                },
+                Setting::Description {
+                    from: Some("Room 114".to_string()),
+                    to: String::new()
+                }
+            ]
+        );
+
+        // A trunk port cannot be put back
+        let trunk = Setting::Vlan {
+            from: Some("trunk".to_string()),
+            to: 30,
+        };
+        let record =
+            ChangeRecord::single("", "cc2", "U123", PortAction::Shut, "2-114", "JPE1", "Et1")
+                .with_settings(vec![trunk]);
+        assert_eq!(record.inverse(), None);
+    }
+    #[test]
+    fn test_last_for() {
+        let mut history = History::default();
+        history
+            .record(ChangeRecord::single(
+                "emea",
+                "cc1",
+                "U123",
+                PortAction::Shut,
+                "2-114",
+                "JPE1",
+                "Et1",
+            ))
+            .unwrap();
+        history
+            .record(ChangeRecord::single(
+                "emea",
+                "cc2",
+                "U456",
+                PortAction::Shut,
+                "2-115",
+                "JPE1",
+                "Et2",
+            ))
+            .unwrap();
+        assert_eq!(history.last_for("U123").unwrap().cc_id, "cc1");
+        history.mark_undone("cc1", "cc3").unwrap();
+        assert_eq!(history.last_for("U123"), None);
+        assert_eq!(
+            history.get("cc1").unwrap().undone_by.as_deref(),
+            Some("cc3")
+        );
+        assert_eq!(history.last_for("U789"), None);
+    }
+    #[test]
+    fn test_history_persists() {
+        let path = std::env::temp_dir().join(format!("history-{}.json", uuid::Uuid::new_v4()));
+        let mut history = History::load(&path).unwrap();
+        assert_eq!(history.last_for("U123"), None);
+        let description = Setting::Description {
+            from: None,
+            to: "Room 114".to_string(),
+        };
+        let record = ChangeRecord::single("emea", "cc1", "U123", None, "2-114", "JPE1", "Et1")
+            .with_settings(vec![description]);
+        history.record(record.clone()).unwrap();
+
+        // A restart still finds the change to undo
+        let mut history = History::load(&path).unwrap();
+        assert_eq!(history.last_for("U123"), Some(&record));
+        history.mark_undone("cc1", "cc2").unwrap();
+        let history = History::load(&path).unwrap();
+        assert_eq!(history.last_for("U123"), None);
+        assert_eq!(
+            history.get("cc1").unwrap().undone_by.as_deref(),
+            Some("cc2")
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
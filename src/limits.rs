@@ -0,0 +1,363 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+/// A token bucket: `burst` requests at once, refilled at `per_minute`
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct BucketConfig {
+    // 0 turns the limit off
+    pub burst: u32,
+    pub per_minute: f64,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct LimitsConfig {
+    // Port changes each Slack user can make
+    #[serde(default = "default_user")]
+    pub user: BucketConfig,
+    // Port changes made from each channel
+    #[serde(default = "default_channel")]
+    pub channel: BucketConfig,
+    // Changes to each interface
+    #[serde(default = "default_interface")]
+    pub interface: BucketConfig,
+    // Seconds after a change before the same interface can be changed again
+    #[serde(default = "default_cooldown")]
+    pub cooldown_secs: u64,
+}
+
+fn default_user() -> BucketConfig {
+    BucketConfig {
+        burst: 10,
+        per_minute: 5.0,
+    }
+}
+
+fn default_channel() -> BucketConfig {
+    BucketConfig {
+        burst: 30,
+        per_minute: 20.0,
+    }
+}
+
+fn default_interface() -> BucketConfig {
+    BucketConfig {
+        burst: 4,
+        per_minute: 1.0,
+    }
+}
+
+fn default_cooldown() -> u64 {
+    30
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            user: default_user(),
+            channel: default_channel(),
+            interface: default_interface(),
+            cooldown_secs: default_cooldown(),
+        }
+    }
+}
+
+/// Why a request was refused and when it can be made again
+#[derive(Debug, PartialEq)]
+pub struct Limited {
+    reason: String,
+    retry_after: Duration,
+}
+
+impl fmt::Display for Limited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}, try again in {}",
+            self.reason,
+            format_wait(self.retry_after)
+        )
+    }
+}
+
+// Whole seconds rounded up, or minutes for longer waits
+fn format_wait(wait: Duration) -> String {
+    let secs = wait
+        .as_secs()
+        .saturating_add(u64::from(wait.subsec_nanos() > 0));
+    if secs < 120 {
+        format!("{} seconds", secs.max(1))
+    } else {
+        format!("{} minutes", secs.div_ceil(60))
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    // Tokens after refilling up to `now`
+    fn refill(&mut self, config: BucketConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.per_minute / 60.0).min(config.burst as f64);
+        self.updated = now;
+    }
+
+    // How long until there is a token, None when there is one now
+    fn wait(&self, config: BucketConfig) -> Option<Duration> {
+        if self.tokens >= 1.0 {
+            return None;
+        }
+        if config.per_minute <= 0.0 {
+            return Some(Duration::MAX);
+        }
+        Some(Duration::from_secs_f64(
+            (1.0 - self.tokens) * 60.0 / config.per_minute,
+        ))
+    }
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
+enum Scope {
+    User,
+    Channel,
+    Interface,
+}
+
+/// Token buckets per user, channel and interface, and when each interface was last changed
+#[derive(Debug, Default)]
+pub struct RateLimits {
+    config: LimitsConfig,
+    buckets: Mutex<HashMap<(Scope, String), Bucket>>,
+    changed: Mutex<HashMap<String, Instant>>,
+}
+
+impl RateLimits {
+    pub fn new(config: LimitsConfig) -> Self {
+        RateLimits {
+            config,
+            ..RateLimits::default()
+        }
+    }
+
+    fn bucket_config(&self, scope: Scope) -> BucketConfig {
+        match scope {
+            Scope::User => self.config.user,
+            Scope::Channel => self.config.channel,
+            Scope::Interface => self.config.interface,
+        }
+    }
+
+    // Takes a token from every bucket, or from none when one of them is empty
+    fn take(&self, keys: Vec<(Scope, String, String)>, now: Instant) -> Result<(), Limited> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let keys: Vec<_> = keys
+            .into_iter()
+            .filter(|(scope, _, _)| self.bucket_config(*scope).burst > 0)
+            .collect();
+        for (scope, key, reason) in &keys {
+            let config = self.bucket_config(*scope);
+            let bucket = buckets
+                .entry((*scope, key.clone()))
+                .or_insert_with(|| Bucket {
+                    tokens: config.burst as f64,
+                    updated: now,
+                });
+            bucket.refill(config, now);
+            if let Some(retry_after) = bucket.wait(config) {
+                return Err(Limited {
+                    reason: reason.clone(),
+                    retry_after,
+                });
+            }
+        }
+        for (scope, key, _) in keys {
+            if let Some(bucket) = buckets.get_mut(&(scope, key)) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a change request against the user and the channel it came from
+    pub fn check_request(
+        &self,
+        user_id: &str,
+        channel_id: &str,
+        now: Instant,
+    ) -> Result<(), Limited> {
+        self.take(
+            vec![
+                (
+                    Scope::User,
+                    user_id.to_string(),
+                    "You have made too many port changes".to_string(),
+                ),
+                (
+                    Scope::Channel,
+                    channel_id.to_string(),
+                    "Too many port changes have been made from this channel".to_string(),
+                ),
+            ],
+            now,
+        )
+    }
+
+    /// Counts a change against each interface it touches, refusing it while one of them is
+    /// cooling down from its last change
+    pub fn check_ports(
+        &self,
+        site: &str,
+        ports: &BTreeMap<String, Vec<String>>,
+        now: Instant,
+    ) -> Result<(), Limited> {
+        let cooldown = Duration::from_secs(self.config.cooldown_secs);
+        let changed = self.changed.lock().unwrap();
+        for (device_id, interface_id) in interfaces(ports) {
+            let key = port_key(site, device_id, interface_id);
+            if let Some(at) = changed.get(&key) {
+                let since = now.saturating_duration_since(*at);
+                if since < cooldown {
+                    return Err(Limited {
+                        reason: format!(
+                            "{} on {} was changed {} ago",
+                            interface_id,
+                            device_id,
+                            format_wait(since)
+                        ),
+                        retry_after: cooldown - since,
+                    });
+                }
+            }
+        }
+        drop(changed);
+        let keys = interfaces(ports)
+            .map(|(device_id, interface_id)| {
+                (
+                    Scope::Interface,
+                    port_key(site, device_id, interface_id),
+                    format!(
+                        "{} on {} has been changed too often",
+                        interface_id, device_id
+                    ),
+                )
+            })
+            .collect();
+        self.take(keys, now)
+    }
+
+    /// Starts the cooldown of the interfaces a change ran on
+    pub fn changed(&self, site: &str, ports: &BTreeMap<String, Vec<String>>, now: Instant) {
+        let mut changed = self.changed.lock().unwrap();
+        changed.retain(|_, at| {
+            now.saturating_duration_since(*at).as_secs() < self.config.cooldown_secs
+        });
+        for (device_id, interface_id) in interfaces(ports) {
+            changed.insert(port_key(site, device_id, interface_id), now);
+        }
+    }
+}
+
+fn interfaces(ports: &BTreeMap<String, Vec<String>>) -> impl Iterator<Item = (&str, &str)> {
+    ports.iter().flat_map(|(device_id, interface_ids)| {
+        interface_ids
+            .iter()
+            .map(move |interface_id| (device_id.as_str(), interface_id.as_str()))
+    })
+}
+
+fn port_key(site: &str, device_id: &str, interface_id: &str) -> String {
+    format!("{}/{}/{}", site, device_id, interface_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn port(device_id: &str, interface_id: &str) -> BTreeMap<String, Vec<String>> {
+        BTreeMap::from([(device_id.to_string(), vec![interface_id.to_string()])])
+    }
+    #[test]
+    fn test_request_limits() {
+        let limits = RateLimits::new(LimitsConfig {
+            user: BucketConfig {
+                burst: 2,
+                per_minute: 1.0,
+            },
+            ..LimitsConfig::default()
+        });
+        let now = Instant::now();
+        assert!(limits.check_request("U1", "C1", now).is_ok());
+        assert!(limits.check_request("U1", "C1", now).is_ok());
+        let limited = limits.check_request("U1", "C1", now).unwrap_err();
+        assert_eq!(
+            limited.to_string(),
+            "You have made too many port changes, try again in 60 seconds"
+        );
+        // Other users have their own bucket, and the bucket refills
+        assert!(limits.check_request("U2", "C1", now).is_ok());
+        assert!(limits
+            .check_request("U1", "C1", now + Duration::from_secs(30))
+            .is_err());
+        assert!(limits
+            .check_request("U1", "C1", now + Duration::from_secs(60))
+            .is_ok());
+    }
+    #[test]
+    fn test_port_limits() {
+        let limits = RateLimits::new(LimitsConfig {
+            interface: BucketConfig {
+                burst: 2,
+                per_minute: 0.1,
+            },
+            cooldown_secs: 30,
+            ..LimitsConfig::default()
+        });
+        let now = Instant::now();
+        let ethernet1 = port("JPE1", "Ethernet1");
+        assert!(limits.check_ports("emea", &ethernet1, now).is_ok());
+        limits.changed("emea", &ethernet1, now);
+        let limited = limits
+            .check_ports("emea", &ethernet1, now + Duration::from_secs(10))
+            .unwrap_err();
+        assert_eq!(
+            limited.to_string(),
+            "Ethernet1 on JPE1 was changed 10 seconds ago, try again in 20 seconds"
+        );
+        // The cooldown is per interface and per site
+        assert!(limits
+            .check_ports("emea", &port("JPE1", "Ethernet2"), now)
+            .is_ok());
+        assert!(limits.check_ports("amer", &ethernet1, now).is_ok());
+
+        let later = now + Duration::from_secs(40);
+        assert!(limits.check_ports("emea", &ethernet1, later).is_ok());
+        let limited = limits.check_ports("emea", &ethernet1, later).unwrap_err();
+        assert!(limited.to_string().contains("changed too often"));
+    }
+    #[test]
+    fn test_limits_off() {
+        let limits = RateLimits::new(LimitsConfig {
+            user: BucketConfig {
+                burst: 0,
+                per_minute: 0.0,
+            },
+            ..LimitsConfig::default()
+        });
+        let now = Instant::now();
+        assert!((0..20).all(|i| limits.check_request("U1", &format!("C{}", i), now).is_ok()));
+    }
+    #[test]
+    fn test_limits_config() {
+        let config: LimitsConfig =
+            toml::from_str("cooldown_secs = 60\n[user]\nburst = 3\nper_minute = 2.0").unwrap();
+        assert_eq!(config.user.burst, 3);
+        assert_eq!(config.channel, default_channel());
+        assert_eq!(config.cooldown_secs, 60);
+    }
+}
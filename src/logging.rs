@@ -0,0 +1,62 @@
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct LoggingConfig {
+    // A level or filter such as "info,slack_port_config=debug", RUST_LOG overrides it
+    #[serde(default = "default_level")]
+    pub level: String,
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+fn default_level() -> String {
+    "info".to_string()
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: default_level(),
+            format: LogFormat::default(),
+        }
+    }
+}
+
+/// Sends logs to stderr as text or as one JSON object per line, with the fields of the spans
+/// they happened in
+pub fn init(config: &LoggingConfig) {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&config.level))
+        .unwrap_or_else(|err| {
+            eprintln!("Invalid log level {}: {}", config.level, err);
+            EnvFilter::new(default_level())
+        });
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match config.format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_span_list(true).init(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_logging_config() {
+        let config: LoggingConfig = toml::from_str("format = \"json\"").unwrap();
+        assert_eq!(config.format, LogFormat::Json);
+        assert_eq!(config.level, "info");
+        assert!(toml::from_str::<LoggingConfig>("format = \"xml\"").is_err());
+    }
+}
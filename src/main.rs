@@ -1,48 +1,64 @@
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::{collections::HashMap, fs};
 
+use args::CommandArgs;
+use audit::AuditConfig;
+use auth::{TokenConfig, TokenWatch};
+use bulk::{BulkRequest, JackTarget, Lookup};
+use cache::CacheConfig;
+use calendar::{ChangeCalendar, OverrideRecord, Verdict};
 use chrono::prelude::*;
 use cvp::{Action, Approval, Change, ChangeConfig, CloudVisionError, RootStage, Stage, StageRow};
-use serde::Deserialize;
+use dedupe::{Dedupe, Seen};
+use history::{ChangeRecord, History};
+use limits::{LimitsConfig, RateLimits};
+use logging::LoggingConfig;
+use metrics::{metrics, ChangeEvent, MetricsConfig, Outcome};
+use regex::Regex;
+use schedule::{ScheduleStore, ScheduledTask, TaskKind};
+use secret::Secret;
+use serde::{Deserialize, Serialize};
+use shutdown::{Drain, RecoveryStore, ShutdownConfig, StrayChange};
+use site::{ActionIds, CloudVisionConfig, Site, Sites};
 use slack::*;
+use tags::{Assignment, LookupConfig, TagChanges};
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
 use tungstenite::Message;
+use uuid::Uuid;
 
 use crate::cvp::StartChange;
-//use serde_derive::Deserialize;
 
+mod args;
+mod audit;
+mod auth;
+mod bulk;
+mod cache;
+mod calendar;
 pub mod cvp;
-mod slack;
+mod dedupe;
+mod history;
+mod limits;
+mod logging;
+mod mapping;
+mod metrics;
+mod portconfig;
+mod schedule;
+mod secret;
+mod shutdown;
+mod site;
+pub mod slack;
+mod switch;
+mod tags;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
-async fn get_tag_assignment(
-    cv: &cvp::Host,
-    label: String,
-    value: String,
-) -> Result<Vec<cvp::InterfaceResponse>, CloudVisionError> {
-    let workspace_key = cvp::TagKey {
-        workspace_id: None,
-        element_type: Some("ELEMENT_TYPE_INTERFACE".to_string()),
-        label: Some(label.to_string()),
-        value: Some(value.to_string()),
-    };
-    let filter = cvp::Tag { key: workspace_key };
-    let data = cvp::PartialEqFilter {
-        partial_eq_filter: vec![filter],
-    };
-    let device_json = cv.get_tag_assignment_config(data).await?;
-    println!("device: {}", &device_json);
-    // TODO: use stream deserializer here for the json stream response
-    // https://docs.serde.rs/serde_json/struct.StreamDeserializer.html
-    // TODO: Better error handling here, should we return an error if there is no assignment?
-    let result: cvp::TagAssignmentConfigResponse = serde_json::from_str(&device_json).unwrap();
-    Ok(vec![result.result])
-}
-
-async fn _get_inventory(cv: &cvp::Host) -> Result<(), CloudVisionError> {
-    let inventory = cv.get_all_devices().await?;
-    println!("Getting inventory: \n{}", inventory);
-    Ok(())
+fn cache_note(age: Option<chrono::Duration>) -> String {
+    match age {
+        Some(age) => format!(" _(from cache, {} old)_", cache::format_age(age)),
+        None => String::new(),
+    }
 }
 
 /// Command line arguments
@@ -54,275 +70,3175 @@ struct Cli {
     #[clap(long)]
     cvp_port: Option<u32>,
     #[clap(long)]
-    cvp_token: Option<String>,
+    cvp_token: Option<Secret>,
+    #[clap(long)]
+    slack_token: Option<Secret>,
     #[clap(long)]
-    slack_token: Option<String>,
+    slack_bot_token: Option<Secret>,
     #[clap(short, long, parse(from_os_str), value_name = "FILE")]
     config_file: Option<PathBuf>,
+    /// CloudVision site the import, export and audit commands use, the first by default
+    #[clap(long)]
+    site: Option<String>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+/// One-off tasks run instead of the bot
+#[derive(Subcommand, Debug, PartialEq)]
+enum Command {
+    /// Apply wall jack mappings from a CSV of wall_jack,device,interface rows
+    Import {
+        #[clap(parse(from_os_str), value_name = "CSV")]
+        file: PathBuf,
+        /// Print the planned changes without applying them
+        #[clap(long)]
+        dry_run: bool,
+        /// Also remove wall jacks that are not in the CSV
+        #[clap(long)]
+        prune: bool,
+    },
+    /// Write the current wall jack mappings, with switch hostnames, to a CSV
+    Export {
+        #[clap(parse(from_os_str), value_name = "CSV")]
+        file: PathBuf,
+    },
+    /// Report orphaned, duplicate and badly formatted wall jack tags
+    Audit {
+        /// Write the report to a file instead of printing it
+        #[clap(long, parse(from_os_str), value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
 }
 
 #[derive(PartialEq, Debug, Deserialize)]
 struct Config {
-    cloudvision: CloudVisionConfig,
+    #[serde(deserialize_with = "site::one_or_many")]
+    cloudvision: Vec<CloudVisionConfig>,
     slack: SlackConfig,
+    #[serde(default)]
+    schedule: ScheduleConfig,
+    #[serde(default)]
+    history: HistoryConfig,
+    #[serde(default)]
+    change_calendar: ChangeCalendar,
+    #[serde(default)]
+    audit: AuditConfig,
+    #[serde(default)]
+    cache: CacheConfig,
+    #[serde(default)]
+    lookup: LookupConfig,
+    #[serde(default)]
+    tokens: TokenConfig,
+    #[serde(default)]
+    logging: LoggingConfig,
+    #[serde(default)]
+    metrics: MetricsConfig,
+    #[serde(default)]
+    shutdown: ShutdownConfig,
+    #[serde(default)]
+    limits: LimitsConfig,
 }
 
 #[derive(PartialEq, Debug, Deserialize)]
 struct SlackConfig {
-    token: String,
+    #[serde(deserialize_with = "secret::load")]
+    token: Secret,
+    // Bot token (xoxb-) used to post scheduled results to a channel
+    #[serde(default, deserialize_with = "secret::load_opt")]
+    bot_token: Option<Secret>,
+}
+
+#[derive(PartialEq, Debug, Deserialize)]
+struct ScheduleConfig {
+    // Where pending scheduled tasks are kept between restarts
+    file: PathBuf,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        ScheduleConfig {
+            file: PathBuf::from("schedule.json"),
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Deserialize)]
+struct HistoryConfig {
+    // Where change controls that can be undone are kept between restarts
+    file: PathBuf,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            file: PathBuf::from("history.json"),
+        }
+    }
+}
+
+impl Config {
+    fn new_from_toml(toml_str: &str) -> Self {
+        toml::from_str(toml_str).unwrap()
+    }
+    fn new_from_cli(cli: Cli) -> Self {
+        let cloudvision = CloudVisionConfig {
+            hostname: cli.cvp_host.unwrap_or_default(),
+            port: cli.cvp_port.unwrap_or_default(),
+            token: cli.cvp_token.unwrap_or_default(),
+            ..CloudVisionConfig::default()
+        };
+        let slack = SlackConfig {
+            token: cli.slack_token.unwrap_or_default(),
+            bot_token: cli.slack_bot_token,
+        };
+        Config {
+            cloudvision: vec![cloudvision],
+            slack,
+            schedule: ScheduleConfig::default(),
+            history: HistoryConfig::default(),
+            change_calendar: ChangeCalendar::default(),
+            audit: AuditConfig::default(),
+            cache: CacheConfig::default(),
+            lookup: LookupConfig::default(),
+            tokens: TokenConfig::default(),
+            logging: LoggingConfig::default(),
+            metrics: MetricsConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            limits: LimitsConfig::default(),
+        }
+    }
+}
+
+/// Interface actions the bot can run through a change control
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum PortAction {
+    Shut,
+    NoShut,
+}
+
+impl PortAction {
+    // Action ids used when a site does not configure its own
+    fn action_id(&self) -> &'static str {
+        match self {
+            PortAction::Shut => "ps5pMVndlXpK6IsQJGr7U",
+            PortAction::NoShut => "rfzsJdsdQEU9EOlPeNeAL",
+        }
+    }
+    fn stage_name(&self) -> &'static str {
+        match self {
+            PortAction::Shut => "shut_interface",
+            PortAction::NoShut => "no_shut_interface",
+        }
+    }
+    fn past_tense(&self) -> &'static str {
+        match self {
+            PortAction::Shut => "shut down",
+            PortAction::NoShut => "enabled",
+        }
+    }
+}
+
+/// Options given with a port command
+#[derive(Debug, Clone, Default)]
+struct PortOptions {
+    // Have CloudVision start the change at this time instead of now
+    start_at: Option<DateTime<Utc>>,
+    // Re-enable a shut down port at this time
+    reenable_at: Option<DateTime<Utc>>,
+    // Justification for overriding the change calendar
+    justification: Option<String>,
+    channel_id: String,
+    user_id: String,
+    // Name of the CloudVision site the change goes to
+    site: String,
+}
+
+/// What to do with a change after checking the change calendar
+enum Gate {
+    Run,
+    Refuse(String),
+    Queue(DateTime<Utc>),
+}
+
+/// Tag changes waiting for confirmation, with when they were planned and their site
+struct PendingTags {
+    created: DateTime<Utc>,
+    site: String,
+    changes: TagChanges,
+}
+
+/// State shared by the command handlers
+struct Bot {
+    sites: Sites,
+    // Bulk requests waiting on a confirm button, keyed by the id carried in the button value
+    pending: Mutex<HashMap<String, BulkRequest>>,
+    // Tag changes with conflicts waiting on a confirm button
+    pending_tags: Mutex<HashMap<String, PendingTags>>,
+    schedules: Mutex<ScheduleStore>,
+    // Change controls the bot has run, for /portundo
+    history: Mutex<History>,
+    // Web API client with the bot token, to post outside of command responses
+    web: Option<slack::WebClient>,
+    calendar: ChangeCalendar,
+    // Naming pattern wall jacks are audited against
+    jack_pattern: Option<Regex>,
+    // Running handlers, waited for on shutdown
+    drain: Drain,
+    // Change controls a shutdown left created but not started
+    recovery: Mutex<RecoveryStore>,
+    // Envelopes already handled, with the reply sent to each
+    seen: Dedupe<serde_json::Value>,
+    // How often users, channels and interfaces may have ports changed
+    limits: RateLimits,
+    // Handlers of the buttons and selects in the bot's messages
+    actions: slack::Router<Bot>,
+}
+
+impl Bot {
+    fn new(
+        sites: Sites,
+        schedules: ScheduleStore,
+        recovery: RecoveryStore,
+        web: Option<slack::WebClient>,
+        calendar: ChangeCalendar,
+        jack_pattern: Option<Regex>,
+        limits: RateLimits,
+    ) -> Self {
+        Bot {
+            sites,
+            pending: Mutex::new(HashMap::new()),
+            pending_tags: Mutex::new(HashMap::new()),
+            schedules: Mutex::new(schedules),
+            history: Mutex::new(History::default()),
+            web,
+            calendar,
+            jack_pattern,
+            drain: Drain::default(),
+            recovery: Mutex::new(recovery),
+            seen: Dedupe::default(),
+            limits,
+            actions: action_router(),
+        }
+    }
+
+    // Sites a command without a jack goes to, every site when it cannot be told
+    fn command_sites(&self, site: Option<&str>, channel_id: &str) -> Result<Vec<&Site>, String> {
+        Ok(match self.sites.route(site, channel_id, "")? {
+            Some(site) => vec![site],
+            None => self.sites.all().iter().collect(),
+        })
+    }
+
+    // Hostname of a device at a site from the cache, or its serial number
+    fn switch_name(&self, site: &str, device_id: &str) -> String {
+        match self.sites.get(site) {
+            Some(site) => site.switch_name(device_id),
+            None => device_id.to_string(),
+        }
+    }
+
+    // Names the site in replies once there is more than one
+    fn site_note(&self, site: &Site) -> String {
+        if self.sites.all().len() > 1 {
+            format!(" at site {}", site.name)
+        } else {
+            String::new()
+        }
+    }
+
+    // Posts to a channel outside of a command response
+    async fn notify(&self, channel_id: &str, text: String) {
+        let web = match &self.web {
+            Some(web) => web,
+            None => {
+                warn!("No Slack bot token configured, dropping message: {}", text);
+                return;
+            }
+        };
+        let message = slack::MessagePayload::new(text);
+        if let Err(err) = web.post_message(channel_id, &message).await {
+            error!("Error posting to channel {}: {}", channel_id, err);
+        }
+    }
+
+    fn with_history(mut self, history: History) -> Self {
+        self.history = Mutex::new(history);
+        self
+    }
+
+    // Remembers a change control so it can be undone
+    fn record_change(&self, record: ChangeRecord) {
+        if let Err(err) = self.history.lock().unwrap().record(record) {
+            error!("Error saving the undo history: {}", err);
+        }
+    }
+
+    // Posts news of a task that has not run yet. A task that keeps being postponed or retried
+    // edits its earlier post rather than adding another one to the channel.
+    async fn notify_task(&self, task: &mut ScheduledTask, text: String) {
+        let web = match &self.web {
+            Some(web) => web,
+            None => {
+                warn!("No Slack bot token configured, dropping message: {}", text);
+                return;
+            }
+        };
+        let message = slack::MessagePayload::new(text);
+        let posted = match &task.notice {
+            Some(notice) => web.update(&notice.channel, &notice.ts, &message).await,
+            None => web.post_message(&task.channel_id, &message).await,
+        };
+        match posted {
+            Ok(notice) => task.notice = Some(notice),
+            Err(err) => error!("Error posting to channel {}: {}", task.channel_id, err),
+        }
+    }
+
+    // Records a task to run later, returning its id
+    fn schedule(
+        &self,
+        kind: TaskKind,
+        due: DateTime<Utc>,
+        walljack: &str,
+        device_id: &str,
+        interface_id: &str,
+        options: &PortOptions,
+    ) -> std::io::Result<String> {
+        let mut task = ScheduledTask::new(
+            kind,
+            due,
+            walljack,
+            device_id,
+            interface_id,
+            &options.channel_id,
+            &options.user_id,
+        );
+        task.site = options.site.clone();
+        let id = task.id.clone();
+        self.schedules.lock().unwrap().add(task)?;
+        Ok(id)
+    }
+
+    // Checks the change calendar for every device a change touches, for a change starting at `at`
+    async fn check_calendar(
+        &self,
+        site: &Site,
+        devices: &[String],
+        at: DateTime<Utc>,
+    ) -> Result<Verdict, CloudVisionError> {
+        if self.calendar.is_empty() {
+            return Ok(Verdict::Allowed);
+        }
+        let mut tagged = Vec::new();
+        for device in devices {
+            let tags = if self.calendar.uses_device_tags() {
+                site.cv.get_device_tags(device).await?
+            } else {
+                Vec::new()
+            };
+            tagged.push((device.clone(), tags));
+        }
+        Ok(self
+            .calendar
+            .check_devices(&at.with_timezone(&Local), &tagged))
+    }
+
+    // Checks the rate limits of the interfaces a change is about to run on
+    fn limit_ports(
+        &self,
+        site: &Site,
+        ports: &BTreeMap<String, Vec<String>>,
+    ) -> Result<(), limits::Limited> {
+        let limited = self
+            .limits
+            .check_ports(&site.name, ports, std::time::Instant::now());
+        if limited.is_err() {
+            metrics::command_outcome(Outcome::Rejected);
+        }
+        limited
+    }
+
+    // Decides whether a change runs now, is queued for the next maintenance window or is
+    // refused, honouring an override from an authorized user
+    async fn gate_change(&self, site: &Site, devices: &[String], options: &PortOptions) -> Gate {
+        let gate = self.calendar_gate(site, devices, options).await;
+        if matches!(gate, Gate::Refuse(_)) {
+            metrics::command_outcome(Outcome::Rejected);
+        }
+        gate
+    }
+
+    async fn calendar_gate(&self, site: &Site, devices: &[String], options: &PortOptions) -> Gate {
+        let start = options.start_at.unwrap_or_else(Utc::now);
+        let verdict = match self.check_calendar(site, devices, start).await {
+            Ok(verdict) => verdict,
+            Err(err) => {
+                return Gate::Refuse(format!("Could not check the change calendar: {}", err))
+            }
+        };
+        let overridden = match &verdict {
+            Verdict::Allowed => return Gate::Run,
+            Verdict::Frozen { name, .. } => format!("freeze {}", name),
+            Verdict::OutsideWindow { .. } => "maintenance window".to_string(),
+        };
+        if let Some(justification) = &options.justification {
+            if !self.calendar.can_override(&options.user_id) {
+                return Gate::Refuse(
+                    "You are not allowed to override the change calendar".to_string(),
+                );
+            }
+            let record = OverrideRecord {
+                time: Utc::now(),
+                user_id: options.user_id.clone(),
+                justification: justification.clone(),
+                devices: devices.to_vec(),
+                overridden,
+            };
+            if let Err(err) = self.calendar.record_override(&record) {
+                return Gate::Refuse(format!("Could not record the override: {}", err));
+            }
+            return Gate::Run;
+        }
+        match verdict {
+            Verdict::Frozen { name, until } => Gate::Refuse(format!(
+                "Changes to {} are frozen for *{}* until {}. Authorized users can add `--override \"justification\"`",
+                devices.join(", "),
+                name,
+                slack::format_date(&until)
+            )),
+            // A change with a start time stays at that time rather than being queued
+            Verdict::OutsideWindow {
+                next_open: Some(at),
+            } if options.start_at.is_some() => Gate::Refuse(format!(
+                "{} is outside the maintenance window for {}, the next one opens {}",
+                slack::format_date(&start),
+                devices.join(", "),
+                slack::format_date(&at)
+            )),
+            Verdict::OutsideWindow {
+                next_open: Some(at),
+            } => Gate::Queue(at),
+            Verdict::OutsideWindow { next_open: None } if devices.len() > 1 => {
+                Gate::Refuse(format!(
+                    "The maintenance windows of {} are never open at the same time",
+                    devices.join(", ")
+                ))
+            }
+            Verdict::OutsideWindow { next_open: None } => Gate::Refuse(format!(
+                "No maintenance window will open for {}",
+                devices.join(", ")
+            )),
+            Verdict::Allowed => Gate::Run,
+        }
+    }
+}
+
+// Queues a change for the next maintenance window and describes the outcome
+fn queue_change(
+    bot: &Bot,
+    action: PortAction,
+    at: DateTime<Utc>,
+    walljack: &str,
+    device_id: &str,
+    interface_id: &str,
+    options: &PortOptions,
+) -> String {
+    let named = format!(
+        "{} ({} on {})",
+        walljack,
+        interface_id,
+        bot.switch_name(&options.site, device_id)
+    );
+    if options.reenable_at.is_some() {
+        return format!(
+            "Wall jack: {} is outside the maintenance window, which next opens {}. Temporary shut downs cannot be queued",
+            named,
+            slack::format_date(&at)
+        );
+    }
+    match bot.schedule(
+        TaskKind::Queued { action },
+        at,
+        walljack,
+        device_id,
+        interface_id,
+        options,
+    ) {
+        Ok(id) => format!(
+            "Wall jack: {} is outside the maintenance window, it will be {} {} (schedule `{}`)",
+            named,
+            action.past_tense(),
+            slack::format_date(&at),
+            id
+        ),
+        Err(err) => format!(
+            "Wall jack: {} is outside the maintenance window and the change could not be queued: {}",
+            named, err
+        ),
+    }
+}
+
+// Queues a confirmed bulk change as a single task, so it still runs as one change control once
+// the maintenance window opens
+fn queue_bulk_change(bot: &Bot, request: &BulkRequest, at: DateTime<Utc>) -> String {
+    let walljacks: Vec<&str> = request
+        .targets
+        .iter()
+        .filter(|t| matches!(t.lookup, Lookup::Found { .. }))
+        .map(|t| t.jack.as_str())
+        .collect();
+    let walljacks = walljacks.join(", ");
+    let options = &request.options;
+    if options.reenable_at.is_some() {
+        return format!(
+            "Wall jacks: {} are outside the maintenance window, which next opens {}. Temporary shut downs cannot be queued",
+            walljacks,
+            slack::format_date(&at)
+        );
+    }
+    let mut task = ScheduledTask::new(
+        TaskKind::Queued {
+            action: request.action,
+        },
+        at,
+        &walljacks,
+        "",
+        "",
+        &options.channel_id,
+        &options.user_id,
+    );
+    task.site = options.site.clone();
+    task.ports = request.ports_by_device();
+    let id = task.id.clone();
+    match bot.schedules.lock().unwrap().add(task) {
+        Ok(()) => format!(
+            "Wall jacks: {} are outside the maintenance window, they will be {} {} in one change control (schedule `{}`)",
+            walljacks,
+            request.action.past_tense(),
+            slack::format_date(&at),
+            id
+        ),
+        Err(err) => format!(
+            "Wall jacks: {} are outside the maintenance window and the change could not be queued: {}",
+            walljacks, err
+        ),
+    }
+}
+
+fn read_config_file(filename: &Path) -> Config {
+    let toml_str = fs::read_to_string(filename).expect("Error reading config file");
+    Config::new_from_toml(&toml_str)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), reqwest::Error> {
+    // Options should be command line, config file, or env var
+    let mut cli = Cli::parse();
+    let command = cli.command.take();
+    let cli_site = cli.site.take();
+    let config = if let Some(config_file) = cli.config_file.as_deref() {
+        read_config_file(config_file)
+    } else {
+        Config::new_from_cli(cli)
+    };
+    logging::init(&config.logging);
+    debug!(?config, ?command, "Starting");
+
+    if config.lookup.labels.is_empty() {
+        panic!("[lookup] labels needs at least one label");
+    }
+    let sites = Sites::new(
+        config
+            .cloudvision
+            .into_iter()
+            .map(|cloudvision| Site::new(cloudvision, &config.lookup.labels))
+            .collect(),
+    );
+    let jack_pattern = config
+        .audit
+        .jack_pattern
+        .as_deref()
+        .map(|pattern| Regex::new(pattern).expect("Invalid audit jack_pattern"));
+    // Read token files and log in before anything talks to CloudVision
+    let renew_before = chrono::Duration::hours(config.tokens.renew_hours);
+    let mut watches = Vec::new();
+    for site in sites.all() {
+        let mut watch = TokenWatch::new(&site.name, site.token.clone());
+        if let Some(warning) = watch.check(&site.cv, renew_before).await {
+            warn!("{}", warning);
+        }
+        watches.push(watch);
+    }
+    if let Some(command) = command {
+        let site = match cli_site.as_deref() {
+            Some(name) => sites.get(name),
+            None => sites.all().first(),
+        };
+        let site = match site {
+            Some(site) => site,
+            None => {
+                eprintln!("No such CloudVision site, use one of: {}", sites.names());
+                std::process::exit(1);
+            }
+        };
+        if let Err(err) = run_command(&site.cv, command, jack_pattern.as_ref()).await {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    let schedules =
+        ScheduleStore::load(&config.schedule.file).expect("Error reading schedule file");
+    let recovery =
+        RecoveryStore::load(&config.shutdown.recovery_file).expect("Error reading recovery file");
+    let history = History::load(&config.history.file).expect("Error reading history file");
+    let bot = Arc::new(
+        Bot::new(
+            sites,
+            schedules,
+            recovery,
+            config.slack.bot_token.map(slack::WebClient::new),
+            config.change_calendar,
+            jack_pattern,
+            RateLimits::new(config.limits),
+        )
+        .with_history(history),
+    );
+    let recovery_channel = config
+        .shutdown
+        .channel
+        .clone()
+        .or_else(|| config.tokens.admin_channel.clone());
+    let mut channels: Vec<&str> = recovery_channel
+        .iter()
+        .chain(config.tokens.admin_channel.iter())
+        .map(String::as_str)
+        .collect();
+    channels.dedup();
+    for channel in channels {
+        check_channel(&bot, channel).await;
+    }
+    offer_recovery(&bot, recovery_channel.as_deref()).await;
+    tokio::spawn(shutdown_on_signal(
+        bot.clone(),
+        std::time::Duration::from_secs(config.shutdown.deadline_secs),
+    ));
+    tokio::spawn(run_scheduler(bot.clone()));
+    for (index, watch) in watches.into_iter().enumerate() {
+        tokio::spawn(run_token_refresh(
+            bot.clone(),
+            index,
+            watch,
+            renew_before,
+            config.tokens.admin_channel.clone(),
+        ));
+    }
+    if config.cache.refresh_secs > 0 {
+        for index in 0..bot.sites.all().len() {
+            tokio::spawn(run_cache_refresh(
+                bot.clone(),
+                index,
+                config.cache.refresh_secs,
+            ));
+        }
+    }
+
+    if let Some(listen) = config.metrics.listen {
+        let addresses = bot
+            .sites
+            .all()
+            .iter()
+            .map(|site| (site.name.clone(), site.cv.address()))
+            .collect();
+        tokio::spawn(metrics::serve(listen, addresses));
+    }
+
+    let slack_token = config.slack.token;
+    let mut slack = slack::Client::new(slack_token);
+
+    connect_slack(&mut slack).await;
+    loop {
+        let msg = slack.receive_message().await.unwrap();
+        metrics().slack_message();
+        match msg {
+            // Envelopes that arrive while stopping are not acked, Slack delivers them again
+            Message::Text(t) => match bot.drain.begin() {
+                Some(_running) => handle_text(&bot, &t, &mut slack).await,
+                None => debug!("Shutting down, leaving the message for Slack to send again"),
+            },
+            Message::Binary(_) => debug!("Binary Slack message"),
+            Message::Ping(_p) => {}
+            Message::Pong(_p) => {}
+            Message::Close(_) => {
+                metrics().slack_connected(false);
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_command(
+    cv: &cvp::Host,
+    command: Command,
+    jack_pattern: Option<&Regex>,
+) -> Result<(), String> {
+    match command {
+        Command::Import {
+            file,
+            dry_run,
+            prune,
+        } => mapping::import(cv, &file, prune, dry_run)
+            .await
+            .map_err(|err| err.to_string()),
+        Command::Export { file } => {
+            let writer = fs::File::create(&file).map_err(|err| err.to_string())?;
+            let count = mapping::export(cv, writer)
+                .await
+                .map_err(|err| err.to_string())?;
+            println!("Wrote {} wall jacks to {}", count, file.display());
+            Ok(())
+        }
+        Command::Audit { output } => {
+            let report = audit::run(cv, jack_pattern)
+                .await
+                .map_err(|err| err.to_string())?;
+            match output {
+                Some(path) => {
+                    fs::write(&path, report.render()).map_err(|err| err.to_string())?;
+                    println!("{}", report.summary());
+                }
+                None => println!("{}", report.render()),
+            }
+            Ok(())
+        }
+    }
+}
+
+// Keeps the CloudVision token of a site current, warning the admin channel when a person has
+// to replace it
+async fn run_token_refresh(
+    bot: Arc<Bot>,
+    site_index: usize,
+    mut watch: TokenWatch,
+    renew_before: chrono::Duration,
+    admin_channel: Option<String>,
+) {
+    let site = &bot.sites.all()[site_index];
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        if let Some(warning) = watch.check(&site.cv, renew_before).await {
+            match &admin_channel {
+                Some(channel) => bot.notify(channel, warning).await,
+                None => warn!("{}", warning),
+            }
+        }
+    }
+}
+
+// Reloads the jack cache of a site on an interval, or straight away after the bot changed tags
+async fn run_cache_refresh(bot: Arc<Bot>, site_index: usize, refresh_secs: u64) {
+    let site = &bot.sites.all()[site_index];
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(refresh_secs));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = site.cache.invalidated() => {}
+        }
+        match cache::load(&site.cv, &site.labels).await {
+            Ok(index) => {
+                info!(site = %site.name, jacks = index.len(), "Loaded the jack cache");
+                site.cache.replace(index);
+            }
+            Err(err) => error!("Error loading the jack cache for {}: {}", site.name, err),
+        }
+    }
+}
+
+// Opens the Socket Mode connection, waiting twice as long after each failure up to a minute
+async fn connect_slack(slack: &mut slack::Client) {
+    let mut wait = std::time::Duration::from_secs(1);
+    while let Err(err) = slack.connect().await {
+        error!(
+            "Could not connect to Slack, trying again in {:?}: {}",
+            wait, err
+        );
+        tokio::time::sleep(wait).await;
+        wait = (wait * 2).min(std::time::Duration::from_secs(60));
+    }
+    metrics().slack_connected(true);
+}
+
+// On SIGTERM or Ctrl-C stops taking commands, gives running ones until the deadline to finish
+// and keeps any change control they left created but not started for the next start
+async fn shutdown_on_signal(bot: Arc<Bot>, deadline: std::time::Duration) {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Could not listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    info!(?deadline, "Shutting down");
+    let stray = bot.drain.stop(deadline).await;
+    if !stray.is_empty() {
+        for change in &stray {
+            warn!(site = %change.site, cc_id = %change.cc_id, "Change control was not started");
+        }
+        if let Err(err) = bot.recovery.lock().unwrap().add(stray) {
+            error!("Error saving the recovery file: {}", err);
+        }
+    }
+    metrics().slack_connected(false);
+    std::process::exit(0);
+}
+
+// Warns at start up about a channel the bot is configured to post to but cannot
+async fn check_channel(bot: &Bot, channel: &str) {
+    let web = match &bot.web {
+        Some(web) => web,
+        None => return,
+    };
+    match web.conversation_info(channel).await {
+        Ok(conversation) if conversation.is_archived => {
+            warn!(channel, "The channel is archived, posts to it will fail")
+        }
+        Ok(conversation) if !conversation.is_member => {
+            warn!(
+                channel,
+                "The bot is not in the channel, posts to it may fail"
+            )
+        }
+        Ok(_) => {}
+        Err(err) => warn!(channel, "Could not look up the channel: {}", err),
+    }
+}
+
+// Posts each change control an earlier shutdown cut off, with buttons to resume or cancel it
+async fn offer_recovery(bot: &Bot, channel: Option<&str>) {
+    let changes = bot.recovery.lock().unwrap().changes().to_vec();
+    for change in changes {
+        let text = format!(
+            "Change control `{}`{} was created {} but the bot stopped before starting it",
+            change.cc_id,
+            bot.sites
+                .get(&change.site)
+                .map(|site| bot.site_note(site))
+                .unwrap_or_default(),
+            slack::format_date(&change.created)
+        );
+        let (channel, web) = match (channel, &bot.web) {
+            (Some(channel), Some(web)) => (channel, web),
+            _ => {
+                warn!(
+                    "{}, set [shutdown] channel and a bot token to resume or cancel it",
+                    text
+                );
+                continue;
+            }
+        };
+        let value = format!("{} {}", change.site, change.cc_id);
+        let blocks = vec![
+            Block::new_section(TextBlock::new_mrkdwn(text.clone())),
+            Block::new_actions(vec![
+                Button::new(
+                    TextBlock::new_plain("Resume".to_string()),
+                    "recovery_resume".to_string(),
+                    value.clone(),
+                )
+                .with_style(ButtonStyle::Primary),
+                Button::new(
+                    TextBlock::new_plain("Cancel".to_string()),
+                    "recovery_cancel".to_string(),
+                    value,
+                )
+                .with_style(ButtonStyle::Danger),
+            ]),
+        ];
+        let message = slack::MessagePayload::with_blocks(text, blocks);
+        if let Err(err) = web.post_message(channel, &message).await {
+            error!("Error posting to channel {}: {}", channel, err);
+        }
+    }
+}
+
+// Approves and starts, or deletes, a change control an earlier shutdown cut off
+async fn recover_change(bot: &Bot, value: &str, resume: bool) -> slack::MessagePayload {
+    let (site, cc_id) = split_site(value);
+    let change = bot.recovery.lock().unwrap().get(cc_id).cloned();
+    let text = match (bot.sites.get(site), change) {
+        (_, None) => format!("Change control `{}` was already dealt with", cc_id),
+        (None, Some(_)) => format!("Site {} is no longer configured", site),
+        (Some(site), Some(change)) => {
+            let result = if resume {
+                resume_change(&site.cv, &change).await
+            } else {
+                let delete = cvp::DeleteChange {
+                    cc_id: change.cc_id.clone(),
+                };
+                site.cv.delete_change_control(delete).await.map(|_| ())
+            };
+            match result {
+                Ok(()) => {
+                    if let Err(err) = bot.recovery.lock().unwrap().remove(cc_id) {
+                        error!("Error saving the recovery file: {}", err);
+                    }
+                    if resume {
+                        format!("Started change control `{}`", cc_id)
+                    } else {
+                        format!("Cancelled change control `{}`", cc_id)
+                    }
+                }
+                Err(err) => format!("Could not recover change control `{}`: {}", cc_id, err),
+            }
+        }
+    };
+    let mut message = slack::MessagePayload::new(text);
+    message.replace_original = Some(true);
+    message
+}
+
+async fn resume_change(cv: &cvp::Host, change: &StrayChange) -> Result<(), CloudVisionError> {
+    if !change.approved {
+        let approval = Approval {
+            cc_id: change.cc_id.clone(),
+            cc_timestamp: format!("{:?}", Utc::now()),
+        };
+        cv.approve_change_control(approval).await?;
+        metrics().change_control(ChangeEvent::Approved);
+    }
+    let start = StartChange {
+        cc_id: change.cc_id.clone(),
+    };
+    cv.execute_change_control(start).await?;
+    metrics().change_control(ChangeEvent::Started);
+    Ok(())
+}
+
+// Runs scheduled tasks once they are due
+async fn run_scheduler(bot: Arc<Bot>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(30));
+    loop {
+        interval.tick().await;
+        let due = bot.schedules.lock().unwrap().due(Utc::now());
+        for task in due {
+            let _running = match bot.drain.begin() {
+                Some(running) => running,
+                None => return,
+            };
+            run_scheduled_task(&bot, task).await;
+        }
+    }
+}
+
+#[tracing::instrument(skip_all, fields(task_id = %task.id, walljack = %task.walljack, site = %task.site))]
+async fn run_scheduled_task(bot: &Bot, mut task: ScheduledTask) {
+    let action = task.kind.action();
+    let site = match bot.sites.get(&task.site) {
+        Some(site) => site,
+        None => {
+            if let Err(err) = bot.schedules.lock().unwrap().remove(&task.id) {
+                error!("Error removing scheduled task {}: {}", task.id, err);
+            }
+            let text = format!(
+                "Scheduled {} of wall jack {} was dropped, site {} is no longer configured",
+                task.kind.describe(),
+                task.walljack,
+                task.site
+            );
+            bot.notify(&task.channel_id, text).await;
+            return;
+        }
+    };
+    if let TaskKind::CloudVision { cc_id, .. } = &task.kind {
+        let cc_id = cc_id.clone();
+        watch_scheduled_change(bot, site, task, &cc_id).await;
+        return;
+    }
+    // A queued bulk change runs every port it was confirmed for
+    let device_ids = task.device_ids();
+    // Scheduled tasks go through the change calendar too, a closed calendar postpones them
+    let postpone = match bot.check_calendar(site, &device_ids, Utc::now()).await {
+        Ok(Verdict::Frozen { name, until }) => {
+            Some((until, format!("changes are frozen for *{}*", name)))
+        }
+        Ok(Verdict::OutsideWindow {
+            next_open: Some(at),
+        }) => Some((at, "outside the maintenance window".to_string())),
+        _ => None,
+    };
+    if let Some((at, reason)) = postpone {
+        task.due = at;
+        let text = format!(
+            "Scheduled {} of wall jack {} postponed to {}, {}",
+            task.kind.describe(),
+            task.walljack,
+            slack::format_date(&at),
+            reason
+        );
+        bot.notify_task(&mut task, text).await;
+        if let Err(err) = bot.schedules.lock().unwrap().update(task.clone()) {
+            error!("Error saving scheduled task {}: {}", task.id, err);
+        }
+        return;
+    }
+    let result = match (action, task.ports.is_empty()) {
+        (_, false) => {
+            let change = build_bulk_change(&site.actions, action, &task.ports);
+            execute_change(bot, site, change).await
+        }
+        (PortAction::Shut, true) => {
+            execute_shut_action(bot, site, &task.device_id, &task.interface_id).await
+        }
+        (PortAction::NoShut, true) => {
+            execute_no_shut_action(bot, site, &task.device_id, &task.interface_id).await
+        }
+    };
+    let text = match result {
+        Ok(cc_id) => {
+            if let Err(err) = bot.schedules.lock().unwrap().remove(&task.id) {
+                error!("Error removing scheduled task {}: {}", task.id, err);
+            }
+            let record = if task.ports.is_empty() {
+                ChangeRecord::single(
+                    &site.name,
+                    &cc_id,
+                    &task.user_id,
+                    action,
+                    &task.walljack,
+                    &task.device_id,
+                    &task.interface_id,
+                )
+            } else {
+                ChangeRecord::new(
+                    &site.name,
+                    &cc_id,
+                    &task.user_id,
+                    action,
+                    task.walljack.split(", ").map(String::from).collect(),
+                    task.ports.clone(),
+                )
+            };
+            bot.record_change(record);
+            // The result is posted on its own, the earlier post about the task is ticked off
+            if let (Some(notice), Some(web)) = (&task.notice, &bot.web) {
+                let reacted = web
+                    .add_reaction(&notice.channel, &notice.ts, "white_check_mark")
+                    .await;
+                if let Err(err) = reacted {
+                    warn!(
+                        "Could not react to the post about task {}: {}",
+                        task.id, err
+                    );
+                }
+            }
+            let done = match task.kind {
+                TaskKind::ReEnable => "re-enabled",
+                _ => action.past_tense(),
+            };
+            format!(
+                "Wall jack: {} has been {} as scheduled by <@{}> (change control `{}`)",
+                task.walljack, done, task.user_id, cc_id
+            )
+        }
+        Err(err) => {
+            task.attempts += 1;
+            let text = if task.attempts < schedule::MAX_ATTEMPTS {
+                task.due = Utc::now() + chrono::Duration::minutes(5);
+                format!(
+                    "Scheduled {} of wall jack {} failed: {}. Retrying {}",
+                    task.kind.describe(),
+                    task.walljack,
+                    err,
+                    slack::format_date(&task.due)
+                )
+            } else {
+                let command = match action {
+                    PortAction::Shut => "portdown",
+                    PortAction::NoShut => "portup",
+                };
+                format!(
+                    "Scheduled {} of wall jack {} failed {} times: {}. Run `/{} {}` to retry",
+                    task.kind.describe(),
+                    task.walljack,
+                    task.attempts,
+                    err,
+                    command,
+                    task.walljack
+                )
+            };
+            bot.notify_task(&mut task, text).await;
+            let mut schedules = bot.schedules.lock().unwrap();
+            let saved = if task.attempts < schedule::MAX_ATTEMPTS {
+                schedules.update(task.clone())
+            } else {
+                schedules.remove(&task.id).map(|_| ())
+            };
+            if let Err(err) = saved {
+                error!("Error saving scheduled task {}: {}", task.id, err);
+            }
+            return;
+        }
+    };
+    bot.notify(&task.channel_id, text).await;
+}
+
+// Checks on a change control CloudVision starts by itself and reports once it has run
+#[tracing::instrument(skip(bot, site, task))]
+async fn watch_scheduled_change(bot: &Bot, site: &Site, mut task: ScheduledTask, cc_id: &str) {
+    let action = task.kind.action();
+    let finished = match site.cv.get_change_control(cc_id).await {
+        Ok(state) => match state.error.clone().filter(|err| !err.is_empty()) {
+            Some(err) => {
+                metrics().change_control(ChangeEvent::Failed);
+                Some(format!(
+                    "CloudVision change control `{}` for wall jack {} failed: {}",
+                    cc_id, task.walljack, err
+                ))
+            }
+            None if state.is_completed() => {
+                // Bulk changes are tracked per device only, so just single ports can be undone
+                if !task.interface_id.is_empty() {
+                    bot.record_change(ChangeRecord::single(
+                        &site.name,
+                        cc_id,
+                        &task.user_id,
+                        action,
+                        &task.walljack,
+                        &task.device_id,
+                        &task.interface_id,
+                    ));
+                }
+                Some(format!(
+                    "CloudVision has run change control `{}`, wall jack {} has been {}",
+                    cc_id,
+                    task.walljack,
+                    action.past_tense()
+                ))
+            }
+            None => None,
+        },
+        Err(err) => {
+            error!("Error checking change control {}: {}", cc_id, err);
+            None
+        }
+    };
+    task.attempts += 1;
+    let text = match finished {
+        Some(text) => text,
+        None if task.attempts >= schedule::MAX_POLLS => format!(
+            "CloudVision change control `{}` for wall jack {} has still not completed, check it in CloudVision",
+            cc_id, task.walljack
+        ),
+        None => {
+            task.due = Utc::now() + chrono::Duration::minutes(1);
+            if let Err(err) = bot.schedules.lock().unwrap().update(task) {
+                error!("Error saving scheduled task: {}", err);
+            }
+            return;
+        }
+    };
+    let removed = bot.schedules.lock().unwrap().remove(&task.id);
+    if let Err(err) = removed {
+        error!("Error removing scheduled task {}: {}", task.id, err);
+    }
+    bot.notify(&task.channel_id, text).await;
+}
+
+async fn handle_text(bot: &Bot, t: &str, slack: &mut slack::Client) {
+    let socket_event = slack::parse_message(t);
+    match socket_event {
+        slack::SocketEvent::EventsApi {
+            payload,
+            envelope_id: _,
+            accepts_response_payload: _,
+        } => {
+            debug!(?payload, "Events API message");
+        }
+        slack::SocketEvent::SlashCommands {
+            payload,
+            envelope_id,
+            accepts_response_payload: _,
+        } => {
+            let span = info_span!(
+                "slash_command",
+                %envelope_id,
+                user = %payload.user_id,
+                command = %payload.get_command(),
+                walljack = field::Empty,
+            );
+            let keys = vec![
+                format!("envelope:{}", envelope_id),
+                format!("trigger:{}", payload.trigger_id),
+            ];
+            // Envelopes are handled one at a time, so a retry only comes after the first reply
+            if let Seen::Done(response) = bot.seen.check(&keys) {
+                info!(%envelope_id, "Slash command was already handled, sending the first reply");
+                match response {
+                    Some(response) => slack.resend(&envelope_id, response),
+                    None => slack.ack(&envelope_id),
+                }
+                return;
+            }
+            let command = payload.get_command();
+            let handler = handle_slash_command(bot, slack, payload, envelope_id.clone());
+            metrics::track(&command, handler.instrument(span)).await;
+            bot.seen.finish(&keys, slack.take_response(&envelope_id));
+        }
+        slack::SocketEvent::Interactive {
+            payload,
+            envelope_id,
+            accepts_response_payload: _,
+        } => {
+            debug!(?payload, "Received interactive");
+            let names = payload.names();
+            let action = names.first().copied().unwrap_or_default().to_string();
+            let span = info_span!(
+                "interactive",
+                %envelope_id,
+                user = %payload.user().id,
+                action = %names.join(","),
+                walljack = field::Empty,
+            );
+            // A submitted form is answered in the ack, errors keep it open to be fixed
+            if let slack::Interactive::ViewSubmission(submission) = &payload {
+                if submission.view.callback_id == portconfig::CALLBACK_ID {
+                    if let Err(errors) = portconfig::parse(&submission.view) {
+                        slack.send_view_errors(&envelope_id, errors);
+                        let rejected = async { metrics::command_outcome(Outcome::Rejected) };
+                        metrics::track(&action, rejected).await;
+                        return;
+                    }
+                }
+            }
+            // Interactive payloads must be acked within 3 seconds, the actual reply goes to response_url
+            slack.ack(&envelope_id);
+            let mut keys = vec![format!("envelope:{}", envelope_id)];
+            if !payload.trigger_id().is_empty() {
+                keys.push(format!("trigger:{}", payload.trigger_id()));
+            }
+            if let slack::Interactive::BlockActions(block_actions) = &payload {
+                keys.extend(
+                    block_actions
+                        .actions
+                        .iter()
+                        .map(|action| format!("action:{}:{}", action.action_id, action.action_ts)),
+                );
+            }
+            // The first delivery already answered through response_url
+            if bot.seen.check(&keys) != Seen::New {
+                info!(%envelope_id, "Action was already handled");
+                return;
+            }
+            metrics::track(&action, handle_interactive(bot, payload).instrument(span)).await;
+            bot.seen.finish(&keys, None);
+        }
+        slack::SocketEvent::Disconnect { reason } => {
+            info!(%reason, "Slack asked for a new connection");
+            metrics().slack_connected(false);
+            connect_slack(slack).await;
+            metrics().slack_reconnect();
+        }
+    }
+}
+
+async fn handle_interactive(bot: &Bot, payload: slack::Interactive) {
+    let payload = match payload {
+        slack::Interactive::BlockActions(payload) => payload,
+        slack::Interactive::ViewSubmission(submission)
+            if submission.view.callback_id == portconfig::CALLBACK_ID =>
+        {
+            portconfig_submit(bot, &submission.user.id, &submission.view).await;
+            return;
+        }
+        other => {
+            info!(names = ?other.names(), "Interaction has no handler");
+            metrics::command_outcome(Outcome::Rejected);
+            return;
+        }
+    };
+    for reply in bot.actions.dispatch(bot, &payload).await {
+        let message = match reply {
+            Ok(Some(message)) => message,
+            Ok(None) => continue,
+            Err(action_id) => {
+                warn!(%action_id, "Unknown action");
+                metrics::command_outcome(Outcome::Rejected);
+                continue;
+            }
+        };
+        // Actions in a modal have no message to reply to
+        let response_url = match &payload.response_url {
+            Some(response_url) => response_url,
+            None => continue,
+        };
+        // Resposne to an interactive action is via response_url which is specific to the action and will tie into the block that sent the action
+        if let Err(err) = slack::respond(response_url, &message).await {
+            error!("Error responding to interactive action: {}", err);
+            metrics::command_outcome(Outcome::Error);
+        }
+    }
+}
+
+// Block action handlers by action id, the value of each button carries what it acts on
+fn action_router() -> slack::Router<Bot> {
+    slack::Router::new()
+        .on("bulk_confirm", |bot, payload, action| {
+            Box::pin(async move { Some(bulk_confirm(bot, action.value(), &payload.user.id).await) })
+        })
+        .on("bulk_cancel", |bot, payload, action| {
+            Box::pin(async move { Some(bulk_cancel(bot, action.value(), &payload.user.id)) })
+        })
+        .on("tags_confirm", |bot, payload, action| {
+            Box::pin(async move { Some(tags_confirm(bot, action.value(), &payload.user.id).await) })
+        })
+        .on("tags_cancel", |bot, _, action| {
+            Box::pin(async move { Some(tags_cancel(bot, action.value())) })
+        })
+        .on("recovery_resume", |bot, _, action| {
+            Box::pin(async move { Some(recover_change(bot, action.value(), true).await) })
+        })
+        .on("recovery_cancel", |bot, _, action| {
+            Box::pin(async move { Some(recover_change(bot, action.value(), false).await) })
+        })
+        .on("switch_details", |bot, _, action| {
+            Box::pin(async move { Some(switch_details(bot, action.value()).await) })
+        })
+        .on("switchports_page", |bot, _, action| {
+            Box::pin(async move { Some(switchports_page(bot, action.value()).await) })
+        })
+        .on("undo", |bot, payload, action| {
+            Box::pin(async move {
+                let channel_id = payload
+                    .channel
+                    .as_ref()
+                    .map(|channel| channel.id.clone())
+                    .unwrap_or_default();
+                let cc_id = action.input.value();
+                let (text, undo) = undo_change(bot, &payload.user.id, &channel_id, cc_id).await;
+                let mut message =
+                    slack::MessagePayload::with_blocks(text.clone(), result_blocks(text, undo));
+                message.replace_original = Some(false);
+                Some(message)
+            })
+        })
+        .on("port_assign_segment", |_, _, action| {
+            Box::pin(async move {
+                let segment = &action.input.selected_option.as_ref()?.text.text;
+                let mut message =
+                    slack::MessagePayload::new(format!("Updated with segement ID {}", segment));
+                message.mrkdwn = false;
+                Some(message)
+            })
+        })
+}
+
+// Matches possible slash commands
+// TODO: use an enum for commands
+async fn handle_slash_command(
+    bot: &Bot,
+    slack: &mut slack::Client,
+    payload: slack::SlashCommand,
+    envelope_id: String,
+) {
+    let command = &payload.get_command();
+    match command.as_str() {
+        "portcheck" | "portdown" | "portup" => {
+            let action = match command.as_str() {
+                "portdown" => Some(PortAction::Shut),
+                "portup" => Some(PortAction::NoShut),
+                _ => None,
+            };
+            let (jacks, mut options) = match port_args(action, &payload) {
+                Ok(parsed) => parsed,
+                Err(err) => {
+                    reject(slack, &envelope_id, err);
+                    return;
+                }
+            };
+            Span::current().record("walljack", field::display(jacks.join(",")));
+            let flag = Some(options.site.as_str()).filter(|site| !site.is_empty());
+            let sites: Vec<&Site> = match bot.sites.route_all(flag, &payload.channel_id, &jacks) {
+                Ok(Some(site)) => vec![site],
+                // A check can ask every site, a change has to know where it goes
+                Ok(None) if action.is_none() => bot.sites.all().iter().collect(),
+                Ok(None) => {
+                    let text = format!(
+                        "Could not tell which site the wall jacks are at, add `--site` with one of: {}",
+                        bot.sites.names()
+                    );
+                    reject(slack, &envelope_id, text);
+                    return;
+                }
+                Err(err) => {
+                    reject(slack, &envelope_id, err);
+                    return;
+                }
+            };
+            let labels: Vec<String> = sites
+                .iter()
+                .flat_map(|site| site.labels.iter().cloned())
+                .collect();
+            if let Err(err) = jacks.iter().try_for_each(|jack| check_label(jack, &labels)) {
+                reject(slack, &envelope_id, err);
+                return;
+            }
+            if action.is_some() {
+                let now = std::time::Instant::now();
+                if let Err(limited) =
+                    bot.limits
+                        .check_request(&payload.user_id, &payload.channel_id, now)
+                {
+                    reject(slack, &envelope_id, limited.to_string());
+                    return;
+                }
+            }
+            options.site = sites[0].name.clone();
+            if jacks.len() > 1 {
+                let text = format!("Looking up {} wall jacks...", jacks.len());
+                send_text(slack, &envelope_id, text);
+                port_bulk(bot, &sites, action, jacks, options, &payload.response_url).await;
+                return;
+            }
+            let walljack = &jacks[0];
+            let site = sites[0];
+            match action {
+                None => portcheck(bot, &sites, walljack, &envelope_id, slack).await,
+                Some(PortAction::Shut) => {
+                    port_shut(bot, site, walljack, &options, &envelope_id, slack).await
+                }
+                Some(PortAction::NoShut) => {
+                    port_no_shut(bot, site, walljack, &options, &envelope_id, slack).await
+                }
+            }
+        }
+        "portschedule" => portschedule(bot, &payload.text, &envelope_id, slack).await,
+        "portundo" => {
+            let cc_id = payload.text.split_whitespace().next();
+            let (text, undo) = undo_change(bot, &payload.user_id, &payload.channel_id, cc_id).await;
+            slack.send_response(&envelope_id, BlockPayload::new(result_blocks(text, undo)));
+        }
+        "jackaudit" => jackaudit(bot, &payload, &envelope_id, slack).await,
+        "switchinfo" => switchinfo(bot, &payload, &envelope_id, slack).await,
+        "switchports" => switchports(bot, &payload, &envelope_id, slack).await,
+        "jackassign" | "jackunassign" => {
+            jack_tags(bot, command, &payload, &envelope_id, slack).await
+        }
+        "portconfig" => portconfig(bot, &payload, &envelope_id, slack).await,
+        "portassign" => info!(text = %payload.text, "Assign port"),
+        _ => {
+            warn!(command, "Unknown command");
+            metrics::command_outcome(Outcome::Rejected);
+        }
+    }
+}
+
+// Parses the jacks and the options given with a port command
+fn port_args(
+    action: Option<PortAction>,
+    payload: &slack::SlashCommand,
+) -> Result<(Vec<String>, PortOptions), String> {
+    let args = CommandArgs::parse(&payload.text).map_err(|err| err.to_string())?;
+    let allowed: &[&str] = match action {
+        Some(PortAction::Shut) => &["for", "until", "at", "override", "site"],
+        Some(PortAction::NoShut) => &["at", "override", "site"],
+        None => &["site"],
+    };
+    args.only_flags(allowed).map_err(|err| err.to_string())?;
+    let jacks = bulk::parse_jack_list(&args.positional.join(",")).map_err(|err| err.to_string())?;
+    let start_at = match args.flag("at") {
+        Some(time) => {
+            let at = args::parse_time(time, &Local::now()).map_err(|err| err.to_string())?;
+            if at <= Utc::now() {
+                return Err("The `--at` time must be in the future".to_string());
+            }
+            Some(at)
+        }
+        None => None,
+    };
+    let start = start_at.unwrap_or_else(Utc::now);
+    let reenable_at = match (args.flag("for"), args.flag("until")) {
+        (Some(_), Some(_)) => return Err("Use either `--for` or `--until`, not both".to_string()),
+        (Some(duration), None) => {
+            Some(start + args::parse_duration(duration).map_err(|err| err.to_string())?)
+        }
+        (None, Some(time)) => {
+            Some(args::parse_time(time, &Local::now()).map_err(|err| err.to_string())?)
+        }
+        (None, None) => None,
+    };
+    if let Some(at) = reenable_at {
+        if at <= start {
+            return Err("The re-enable time must be after the port is shut down".to_string());
+        }
+    }
+    let justification = args.flag("override").map(|j| j.to_string());
+    if justification.as_deref().map(str::trim) == Some("") {
+        return Err("`--override` needs a justification".to_string());
+    }
+    let options = PortOptions {
+        start_at,
+        reenable_at,
+        justification,
+        channel_id: payload.channel_id.clone(),
+        user_id: payload.user_id.clone(),
+        site: args.flag("site").unwrap_or_default().to_string(),
+    };
+    Ok((jacks, options))
+}
+
+// Rejects a `label=` that is not one of the lookup labels
+fn check_label(jack: &str, labels: &[String]) -> Result<(), String> {
+    match bulk::split_label(jack) {
+        (Some(label), _) if !labels.iter().any(|l| l == label) => Err(format!(
+            "`{}` is not a lookup label, use one of: {}",
+            label,
+            labels.join(", ")
+        )),
+        (_, "") => Err(format!("`{}` has no value", jack)),
+        _ => Ok(()),
+    }
+}
+
+// Lists pending scheduled tasks, or cancels one with `/portschedule cancel <id>`
+async fn portschedule(bot: &Bot, text: &str, envelope_id: &str, slack: &mut slack::Client) {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let resp_text = match words.as_slice() {
+        [] | ["list"] => {
+            let schedules = bot.schedules.lock().unwrap();
+            if schedules.tasks().is_empty() {
+                "There are no scheduled port changes".to_string()
+            } else {
+                let lines: Vec<String> = schedules
+                    .tasks()
+                    .iter()
+                    .map(|task| {
+                        let switches: Vec<String> = task
+                            .device_ids()
+                            .iter()
+                            .map(|device_id| bot.switch_name(&task.site, device_id))
+                            .collect();
+                        let switch = switches.join(", ");
+                        let port = if task.interface_id.is_empty() {
+                            format!("on {}", switch)
+                        } else {
+                            format!("{} on {}", task.interface_id, switch)
+                        };
+                        let by = match &task.kind {
+                            TaskKind::CloudVision { cc_id, .. } => {
+                                format!(" by CloudVision (change control `{}`)", cc_id)
+                            }
+                            _ => String::new(),
+                        };
+                        format!(
+                            "`{}` {} wall jack {} ({}) {}{}, requested by <@{}>",
+                            task.id,
+                            task.kind.describe(),
+                            task.walljack,
+                            port,
+                            slack::format_date(&task.due),
+                            by,
+                            task.user_id
+                        )
+                    })
+                    .collect();
+                lines.join("\n")
+            }
+        }
+        ["cancel", id] => cancel_scheduled(bot, id).await,
+        _ => "Usage: `/portschedule [list]` or `/portschedule cancel <id>`".to_string(),
+    };
+    send_text(slack, envelope_id, resp_text);
+}
+
+async fn cancel_scheduled(bot: &Bot, id: &str) -> String {
+    let task = bot
+        .schedules
+        .lock()
+        .unwrap()
+        .tasks()
+        .iter()
+        .find(|task| task.id == id)
+        .cloned();
+    let task = match task {
+        Some(task) => task,
+        None => return format!("No scheduled change with id `{}`", id),
+    };
+    // A change CloudVision will start has to be removed there as well
+    if let TaskKind::CloudVision { cc_id, .. } = &task.kind {
+        let delete = cvp::DeleteChange {
+            cc_id: cc_id.clone(),
+        };
+        let site = match bot.sites.get(&task.site) {
+            Some(site) => site,
+            None => return format!("Site {} is no longer configured", task.site),
+        };
+        if let Err(err) = site.cv.delete_change_control(delete).await {
+            return format!("Could not delete change control `{}`: {}", cc_id, err);
+        }
+    }
+    if let Err(err) = bot.schedules.lock().unwrap().remove(id) {
+        return format!("Could not cancel `{}`: {}", id, err);
+    }
+    // A post about the task being postponed is out of date now
+    if let (Some(notice), Some(web)) = (&task.notice, &bot.web) {
+        if let Err(err) = web.delete(&notice.channel, &notice.ts).await {
+            warn!("Could not delete the post about task {}: {}", id, err);
+        }
+    }
+    match task.kind {
+        TaskKind::ReEnable => format!(
+            "Cancelled the scheduled re-enable of wall jack {}, it will stay shut down",
+            task.walljack
+        ),
+        _ => format!(
+            "Cancelled the scheduled {} of wall jack {}",
+            task.kind.describe(),
+            task.walljack
+        ),
+    }
+}
+
+fn send_text(slack: &mut slack::Client, envelope_id: &str, text: String) {
+    let block = Block::new_section(TextBlock::new_mrkdwn(text));
+    slack.send_response(envelope_id, BlockPayload::new(vec![block]));
+}
+
+// Answers a command that cannot be run as given
+fn reject(slack: &mut slack::Client, envelope_id: &str, text: String) {
+    metrics::command_outcome(Outcome::Rejected);
+    send_text(slack, envelope_id, text);
+}
+
+// Resolves every jack in a list or range, then either reports them (portcheck) or asks for
+// confirmation before changing them all in one change control
+async fn port_bulk(
+    bot: &Bot,
+    sites: &[&Site],
+    action: Option<PortAction>,
+    jacks: Vec<String>,
+    options: PortOptions,
+    response_url: &str,
+) {
+    let mut targets = Vec::new();
+    let mut oldest = None;
+    let mut hostnames = HashMap::new();
+    for jack in jacks {
+        let (lookup, age, site) = find_jack_at(sites, &jack).await;
+        oldest = oldest.max(age);
+        if let Lookup::Found { device_id, .. } = &lookup {
+            if !hostnames.contains_key(device_id) {
+                hostnames.insert(device_id.clone(), site.hostname(device_id).await);
+            }
+        }
+        targets.push(JackTarget { jack, lookup });
+    }
+    let mut blocks: Vec<Block> = bulk::summary_tables(&targets, &hostnames)
+        .into_iter()
+        .map(|table| Block::new_section(TextBlock::new_mrkdwn(table)))
+        .collect();
+    let found = targets
+        .iter()
+        .filter(|t| matches!(t.lookup, Lookup::Found { .. }))
+        .count();
+    let text = match action {
+        None => format!(
+            "Found {} of {} wall jacks{}",
+            found,
+            targets.len(),
+            cache_note(oldest)
+        ),
+        Some(_) if found == 0 => "None of the wall jacks were found, nothing to change".to_string(),
+        Some(action) => {
+            let mut until = match &options.start_at {
+                Some(at) => format!(" {}", slack::format_date(at)),
+                None => String::new(),
+            };
+            if let Some(at) = &options.reenable_at {
+                until.push_str(&format!(" until {}", slack::format_date(at)));
+            }
+            let mut request = BulkRequest::new(action, targets, options);
+            request.hostnames = hostnames;
+            let request_id = Uuid::new_v4().to_string();
+            let text = format!(
+                "{} of {} wall jacks will be {}{}. Create the change control?{}",
+                found,
+                request.targets.len(),
+                action.past_tense(),
+                until,
+                cache_note(oldest)
+            );
+            let style = match action {
+                PortAction::Shut => ButtonStyle::Danger,
+                PortAction::NoShut => ButtonStyle::Primary,
+            };
+            blocks.push(Block::new_section(TextBlock::new_mrkdwn(text.clone())));
+            blocks.push(Block::new_actions(vec![
+                Button::new(
+                    TextBlock::new_plain("Confirm".to_string()),
+                    "bulk_confirm".to_string(),
+                    request_id.clone(),
+                )
+                .with_style(style),
+                Button::new(
+                    TextBlock::new_plain("Cancel".to_string()),
+                    "bulk_cancel".to_string(),
+                    request_id.clone(),
+                ),
+            ]));
+            let mut pending = bot.pending.lock().unwrap();
+            // Drop confirmations nobody answered
+            let cutoff = Utc::now() - chrono::Duration::minutes(30);
+            pending.retain(|_, request| request.created > cutoff);
+            pending.insert(request_id, request);
+            text
+        }
+    };
+    let message = slack::MessagePayload::with_blocks(text, blocks);
+    if let Err(err) = slack::respond(response_url, &message).await {
+        error!("Error sending bulk summary: {}", err);
+    }
+}
+
+// Takes a pending bulk request for the user who asked for it, None if it has expired. Anyone
+// else clicking its buttons gets a reply of their own and the request stays pending.
+fn take_bulk_request(
+    bot: &Bot,
+    request_id: &str,
+    user_id: &str,
+) -> Result<Option<BulkRequest>, slack::MessagePayload> {
+    let mut pending = bot.pending.lock().unwrap();
+    match pending.get(request_id) {
+        Some(request) if request.options.user_id != user_id => {
+            let mut message = slack::MessagePayload::new(format!(
+                "Only <@{}> can confirm or cancel this change",
+                request.options.user_id
+            ));
+            message.replace_original = Some(false);
+            Err(message)
+        }
+        _ => Ok(pending.remove(request_id)),
+    }
+}
+
+async fn bulk_confirm(bot: &Bot, request_id: &str, user_id: &str) -> slack::MessagePayload {
+    let request = match take_bulk_request(bot, request_id, user_id) {
+        Ok(Some(request)) => request,
+        Ok(None) => {
+            return slack::MessagePayload::new(
+                "This request has expired or was already handled".to_string(),
+            )
+        }
+        Err(message) => return message,
+    };
+    let jacks: Vec<&str> = request.targets.iter().map(|t| t.jack.as_str()).collect();
+    Span::current().record("walljack", field::display(jacks.join(",")));
+    let site = match bot.sites.get(&request.options.site) {
+        Some(site) => site,
+        None => {
+            return slack::MessagePayload::new(format!(
+                "Site {} is no longer configured",
+                request.options.site
+            ))
+        }
+    };
+    let devices = request.ports_by_device();
+    let device_ids: Vec<String> = devices.keys().cloned().collect();
+    match bot.gate_change(site, &device_ids, &request.options).await {
+        Gate::Run => {}
+        Gate::Refuse(reason) => {
+            let mut message = slack::MessagePayload::new(reason);
+            message.replace_original = Some(true);
+            return message;
+        }
+        Gate::Queue(at) => {
+            let mut message = slack::MessagePayload::new(queue_bulk_change(bot, &request, at));
+            message.replace_original = Some(true);
+            return message;
+        }
+    }
+    if let Err(limited) = bot.limit_ports(site, &devices) {
+        let mut message = slack::MessagePayload::new(limited.to_string());
+        message.replace_original = Some(true);
+        return message;
+    }
+    let mut change = build_bulk_change(&site.actions, request.action, &devices);
+    if let Some(at) = request.options.start_at {
+        change.config = change.config.with_schedule(at);
+    }
+    let outcome = execute_change(bot, site, change)
+        .await
+        .map_err(|err| err.to_string());
+    if outcome.is_ok() {
+        bot.limits
+            .changed(&site.name, &devices, std::time::Instant::now());
+    }
+    let mut summary = match (&outcome, request.options.start_at) {
+        (Ok(cc_id), None) => {
+            let walljacks = request
+                .targets
+                .iter()
+                .filter(|t| matches!(t.lookup, Lookup::Found { .. }))
+                .map(|t| t.jack.clone())
+                .collect();
+            bot.record_change(ChangeRecord::new(
+                &site.name,
+                cc_id,
+                &request.options.user_id,
+                request.action,
+                walljacks,
+                devices.clone(),
+            ));
+            format!("Change control `{}` has run", cc_id)
+        }
+        (Ok(cc_id), Some(at)) => {
+            let found: Vec<&str> = request
+                .targets
+                .iter()
+                .filter(|t| matches!(t.lookup, Lookup::Found { .. }))
+                .map(|t| t.jack.as_str())
+                .collect();
+            let kind = TaskKind::CloudVision {
+                action: request.action,
+                cc_id: cc_id.clone(),
+            };
+            let options = &request.options;
+            let mut task = ScheduledTask::new(
+                kind,
+                at,
+                &found.join(", "),
+                "",
+                "",
+                &options.channel_id,
+                &options.user_id,
+            );
+            task.site = options.site.clone();
+            task.ports = devices;
+            if let Err(err) = bot.schedules.lock().unwrap().add(task) {
+                error!("Error tracking change control {}: {}", cc_id, err);
+            }
+            format!(
+                "Change control `{}` will be started by CloudVision {}",
+                cc_id,
+                slack::format_date(&at)
+            )
+        }
+        (Err(err), _) => format!("Change control failed: {}", err),
+    };
+    if let (Ok(_), Some(at)) = (&outcome, request.options.reenable_at) {
+        let mut scheduled = 0;
+        for target in &request.targets {
+            if let Lookup::Found {
+                device_id,
+                interface_id,
+            } = &target.lookup
+            {
+                match bot.schedule(
+                    TaskKind::ReEnable,
+                    at,
+                    &target.jack,
+                    device_id,
+                    interface_id,
+                    &request.options,
+                ) {
+                    Ok(_) => scheduled += 1,
+                    Err(err) => error!("Error scheduling re-enable of {}: {}", target.jack, err),
+                }
+            }
+        }
+        summary.push_str(&format!(
+            ", {} ports will be re-enabled {}, see `/portschedule`",
+            scheduled,
+            slack::format_date(&at)
+        ));
+    }
+    let lines = bulk::result_lines(&request, &outcome);
+    let mut blocks = vec![Block::new_section(TextBlock::new_mrkdwn(summary.clone()))];
+    for chunk in lines.chunks(40) {
+        blocks.push(Block::new_section(TextBlock::new_mrkdwn(chunk.join("\n"))));
+    }
+    if let (Ok(cc_id), None) = (&outcome, request.options.start_at) {
+        blocks.push(Block::new_actions(vec![Button::new(
+            TextBlock::new_plain("Undo".to_string()),
+            "undo".to_string(),
+            cc_id.clone(),
+        )]));
+    }
+    let mut message = slack::MessagePayload::with_blocks(summary, blocks);
+    message.replace_original = Some(true);
+    message
+}
+
+fn bulk_cancel(bot: &Bot, request_id: &str, user_id: &str) -> slack::MessagePayload {
+    if let Err(message) = take_bulk_request(bot, request_id, user_id) {
+        return message;
+    }
+    let mut message = slack::MessagePayload::new("Cancelled, no changes were made".to_string());
+    message.replace_original = Some(true);
+    message
+}
+
+// Assigns a wall jack tag to an interface, or removes it, through a CloudVision workspace.
+// Conflicting assignments are shown for confirmation before anything is submitted.
+async fn jack_tags(
+    bot: &Bot,
+    command: &str,
+    payload: &slack::SlashCommand,
+    envelope_id: &str,
+    slack: &mut slack::Client,
+) {
+    let args = match CommandArgs::parse(&payload.text) {
+        Ok(args) => args,
+        Err(err) => {
+            send_text(slack, envelope_id, err.to_string());
+            return;
+        }
+    };
+    if let Err(err) = args.only_flags(&["site"]) {
+        send_text(slack, envelope_id, err.to_string());
+        return;
+    }
+    let words: Vec<&str> = args.positional.iter().map(String::as_str).collect();
+    let first = words.first().copied().unwrap_or_default();
+    Span::current().record("walljack", first);
+    let site = match bot
+        .sites
+        .route(args.flag("site"), &payload.channel_id, first)
+    {
+        Ok(Some(site)) => site,
+        Ok(None) => {
+            let text = format!(
+                "Could not tell which site the wall jack is at, add `--site` with one of: {}",
+                bot.sites.names()
+            );
+            send_text(slack, envelope_id, text);
+            return;
+        }
+        Err(err) => {
+            send_text(slack, envelope_id, err);
+            return;
+        }
+    };
+    if !first.is_empty() {
+        if let Err(err) = check_label(first, &site.labels) {
+            send_text(slack, envelope_id, err);
+            return;
+        }
+    }
+    let (label, walljack) = match bulk::split_label(first) {
+        (Some(label), walljack) => (label, walljack),
+        (None, walljack) => (site.labels[0].as_str(), walljack),
+    };
+    let planned = match (command, words.as_slice()) {
+        ("jackassign", [_, device_id, interface_id]) => {
+            let target = Assignment::new(walljack, device_id, interface_id);
+            let current = match jack_and_port_assignments(&site.cv, label, &target).await {
+                Ok(current) => current,
+                Err(err) => {
+                    send_text(
+                        slack,
+                        envelope_id,
+                        format!("Could not read the tags: {}", err),
+                    );
+                    return;
+                }
+            };
+            let (changes, conflicts) = tags::plan_assign(label, target, &current);
+            if changes.is_empty() {
+                let text = format!(
+                    "Wall jack {} is already assigned to {} on {}",
+                    walljack, interface_id, device_id
+                );
+                send_text(slack, envelope_id, text);
+                return;
+            }
+            (changes, conflicts)
+        }
+        ("jackunassign", [_]) => {
+            let current = match tags::assignments(&site.cv, label, Some(walljack), None).await {
+                Ok(current) => current,
+                Err(err) => {
+                    send_text(
+                        slack,
+                        envelope_id,
+                        format!("Could not read the tags: {}", err),
+                    );
+                    return;
+                }
+            };
+            let changes = tags::plan_unassign(label, walljack, &current);
+            if changes.is_empty() {
+                let text = format!("Wall jack {} is not assigned to any port", walljack);
+                send_text(slack, envelope_id, text);
+                return;
+            }
+            (changes, Vec::new())
+        }
+        ("jackassign", _) => {
+            let usage = "Usage: `/jackassign <walljack> <device> <interface>`".to_string();
+            send_text(slack, envelope_id, usage);
+            return;
+        }
+        _ => {
+            send_text(
+                slack,
+                envelope_id,
+                "Usage: `/jackunassign <walljack>`".to_string(),
+            );
+            return;
+        }
+    };
+    let (changes, conflicts) = planned;
+    if conflicts.is_empty() {
+        send_text(
+            slack,
+            envelope_id,
+            "Updating tags in CloudVision...".to_string(),
+        );
+        let text = apply_tag_changes(site, &changes, &payload.user_id).await;
+        if let Err(err) = slack::respond(&payload.response_url, &MessagePayload::new(text)).await {
+            error!("Error sending tag result: {}", err);
+        }
+        return;
+    }
+    let request_id = Uuid::new_v4().to_string();
+    let text = format!(
+        "{}\n```{}```\nSubmit these tag changes?",
+        conflicts.join("\n"),
+        changes.describe().join("\n")
+    );
+    let blocks = vec![
+        Block::new_section(TextBlock::new_mrkdwn(text)),
+        Block::new_actions(vec![
+            Button::new(
+                TextBlock::new_plain("Submit".to_string()),
+                "tags_confirm".to_string(),
+                request_id.clone(),
+            )
+            .with_style(ButtonStyle::Primary),
+            Button::new(
+                TextBlock::new_plain("Cancel".to_string()),
+                "tags_cancel".to_string(),
+                request_id.clone(),
+            ),
+        ]),
+    ];
+    {
+        let mut pending = bot.pending_tags.lock().unwrap();
+        let cutoff = Utc::now() - chrono::Duration::minutes(30);
+        pending.retain(|_, pending| pending.created > cutoff);
+        let tags = PendingTags {
+            created: Utc::now(),
+            site: site.name.clone(),
+            changes,
+        };
+        pending.insert(request_id, tags);
+    }
+    slack.send_response(envelope_id, BlockPayload::new(blocks));
+}
+
+// Existing assignments of the jack anywhere, and of any jack on the target interface
+async fn jack_and_port_assignments(
+    cv: &cvp::Host,
+    label: &str,
+    target: &Assignment,
+) -> Result<Vec<Assignment>, CloudVisionError> {
+    let mut current = tags::assignments(cv, label, Some(&target.value), None).await?;
+    let on_port = tags::assignments(cv, label, None, Some(&target.device_id)).await?;
+    for assignment in on_port {
+        if assignment.interface_id == target.interface_id && !current.contains(&assignment) {
+            current.push(assignment);
+        }
+    }
+    Ok(current)
+}
+
+async fn apply_tag_changes(site: &Site, changes: &TagChanges, user_id: &str) -> String {
+    let description = format!("Requested from Slack by {}", user_id);
+    match tags::apply(&site.cv, changes, &description).await {
+        Ok(workspace_id) => {
+            site.cache.invalidate();
+            format!(
+                "Tags updated in workspace `{}`\n```{}```",
+                workspace_id,
+                changes.describe().join("\n")
+            )
+        }
+        Err(err) => format!("Tags were not changed: {}", err),
+    }
+}
+
+async fn tags_confirm(bot: &Bot, request_id: &str, user_id: &str) -> slack::MessagePayload {
+    let pending = bot.pending_tags.lock().unwrap().remove(request_id);
+    let text = match pending {
+        Some(pending) => match bot.sites.get(&pending.site) {
+            Some(site) => apply_tag_changes(site, &pending.changes, user_id).await,
+            None => format!("Site {} is no longer configured", pending.site),
+        },
+        None => "This request has expired or was already handled".to_string(),
+    };
+    let mut message = slack::MessagePayload::new(text);
+    message.replace_original = Some(true);
+    message
 }
 
-#[derive(PartialEq, Debug, Deserialize)]
-struct CloudVisionConfig {
-    hostname: String,
-    port: u32,
-    token: String,
+fn tags_cancel(bot: &Bot, request_id: &str) -> slack::MessagePayload {
+    bot.pending_tags.lock().unwrap().remove(request_id);
+    let mut message = slack::MessagePayload::new("Cancelled, no tags were changed".to_string());
+    message.replace_original = Some(true);
+    message
 }
 
-impl Config {
-    fn new_from_toml(toml_str: &str) -> Self {
-        toml::from_str(toml_str).unwrap()
-    }
-    fn new_from_cli(cli: Cli) -> Self {
-        let cloudvision = CloudVisionConfig {
-            hostname: cli.cvp_host.unwrap_or_default(),
-            port: cli.cvp_port.unwrap_or_default(),
-            token: cli.cvp_token.unwrap_or_default(),
+// Runs the wall jack audit, a report too long for a message is shared as a file
+async fn jackaudit(
+    bot: &Bot,
+    payload: &slack::SlashCommand,
+    envelope_id: &str,
+    slack: &mut slack::Client,
+) {
+    let sites = match command_site_flag(&payload.text)
+        .and_then(|flag| bot.command_sites(flag.as_deref(), &payload.channel_id))
+    {
+        Ok(sites) => sites,
+        Err(err) => {
+            send_text(slack, envelope_id, err);
+            return;
+        }
+    };
+    send_text(slack, envelope_id, "Auditing wall jack tags...".to_string());
+    let mut summaries = Vec::new();
+    let mut texts = Vec::new();
+    let mut problems = 0;
+    for site in &sites {
+        let report = match audit::run(&site.cv, bot.jack_pattern.as_ref()).await {
+            Ok(report) => report,
+            Err(err) => {
+                let message = MessagePayload::new(format!(
+                    "The audit{} failed: {}",
+                    bot.site_note(site),
+                    err
+                ));
+                if let Err(err) = slack::respond(&payload.response_url, &message).await {
+                    error!("Error sending audit result: {}", err);
+                }
+                return;
+            }
         };
-        let slack = SlackConfig {
-            token: cli.slack_token.unwrap_or_default(),
+        problems += report.problems();
+        if sites.len() > 1 {
+            summaries.push(format!("{}: {}", site.name, report.summary()));
+            texts.push(format!("Site {}\n{}", site.name, report.render()));
+        } else {
+            summaries.push(report.summary());
+            texts.push(report.render());
+        }
+    }
+    let summary = summaries.join("\n");
+    let text = texts.join("\n\n");
+    let message = if problems == 0 {
+        MessagePayload::new(summary)
+    } else if text.len() < 2900 {
+        let block = Block::new_section(TextBlock::new_mrkdwn(format!("```{}```", text)));
+        MessagePayload::with_blocks(summary, vec![block])
+    } else {
+        let uploaded = match &bot.web {
+            Some(web) => web
+                .upload_file(
+                    &payload.channel_id,
+                    "jack-audit.txt",
+                    &text,
+                    "Wall jack audit",
+                )
+                .await
+                .map_err(|err| err.to_string()),
+            None => Err("no Slack bot token is configured".to_string()),
         };
-        Config { cloudvision, slack }
+        match uploaded {
+            Ok(()) => MessagePayload::new(format!("{}. The full report is attached", summary)),
+            Err(err) => MessagePayload::new(format!(
+                "{}. The full report could not be attached ({}), run the `audit` command instead",
+                summary, err
+            )),
+        }
+    };
+    if let Err(err) = slack::respond(&payload.response_url, &message).await {
+        error!("Error sending audit result: {}", err);
     }
 }
 
-fn read_config_file(filename: &Path) -> Config {
-    let toml_str = fs::read_to_string(filename).expect("Error reading config file");
-    Config::new_from_toml(&toml_str)
+// Looks the jack up at every given site and lists each match
+async fn portcheck(
+    bot: &Bot,
+    sites: &[&Site],
+    walljack: &str,
+    envelope_id: &str,
+    slack: &mut slack::Client,
+) {
+    let mut blocks = Vec::new();
+    let mut failed = None;
+    for site in sites {
+        let (device_id, interface_id, age) = match site.find_jack(walljack).await {
+            (
+                Lookup::Found {
+                    device_id,
+                    interface_id,
+                },
+                age,
+            ) => (device_id, interface_id, age),
+            (Lookup::NotFound, _) => continue,
+            (Lookup::Failed(err), _) => {
+                failed.get_or_insert(format!(
+                    "Could not look up wall jack {}{}: {}",
+                    walljack,
+                    bot.site_note(site),
+                    err
+                ));
+                continue;
+            }
+        };
+        let resp_text = format!(
+            "Wall jack: {} is connected to port {} on switch {}{}{}",
+            walljack,
+            interface_id,
+            site.hostname(&device_id).await,
+            bot.site_note(site),
+            cache_note(age)
+        );
+        blocks.push(Block::new_section(TextBlock::new_mrkdwn(resp_text)));
+        blocks.push(Block::new_actions(vec![Button::new(
+            TextBlock::new_plain("Switch details".to_string()),
+            "switch_details".to_string(),
+            format!("{} {}", site.name, device_id),
+        )]));
+    }
+    if blocks.is_empty() {
+        let text = failed.unwrap_or_else(|| "Wall jack number was not found".to_string());
+        send_text(slack, envelope_id, text);
+        return;
+    }
+    slack.send_response(envelope_id, BlockPayload::new(blocks));
 }
 
-#[tokio::main]
-async fn main() -> Result<(), reqwest::Error> {
-    // Options should be command line, config file, or env var
-    let cli = Cli::parse();
-    println!("{:?}", cli);
-    let config = if let Some(config_file) = cli.config_file.as_deref() {
-        read_config_file(config_file)
-    } else {
-        Config::new_from_cli(cli)
+// Opens the /portconfig form for a wall jack. Slack only takes views.open for 3 seconds after
+// the command, so a placeholder opens first and is replaced once the port has been looked up.
+async fn portconfig(
+    bot: &Bot,
+    payload: &slack::SlashCommand,
+    envelope_id: &str,
+    slack: &mut slack::Client,
+) {
+    let web = match &bot.web {
+        Some(web) => web,
+        None => {
+            let text = "`/portconfig` needs a Slack bot token to open its form".to_string();
+            reject(slack, envelope_id, text);
+            return;
+        }
     };
-    println!("{:?}", config);
-
-    let mut cv = cvp::Host::new("www.cv-staging.corp.arista.io", 443);
+    let (walljack, flag) = match portconfig_args(&payload.text) {
+        Ok(args) => args,
+        Err(err) => {
+            reject(slack, envelope_id, err);
+            return;
+        }
+    };
+    Span::current().record("walljack", field::display(&walljack));
+    let jacks = std::slice::from_ref(&walljack);
+    let sites: Vec<&Site> = match bot
+        .sites
+        .route_all(flag.as_deref(), &payload.channel_id, jacks)
+    {
+        Ok(Some(site)) => vec![site],
+        // The jack is looked up everywhere and changed where it is found
+        Ok(None) => bot.sites.all().iter().collect(),
+        Err(err) => {
+            reject(slack, envelope_id, err);
+            return;
+        }
+    };
+    let labels: Vec<String> = sites
+        .iter()
+        .flat_map(|site| site.labels.iter().cloned())
+        .collect();
+    if let Err(err) = check_label(&walljack, &labels) {
+        reject(slack, envelope_id, err);
+        return;
+    }
+    let loading = portconfig::loading_modal(&walljack);
+    let view = match web.open_view(&payload.trigger_id, &loading).await {
+        Ok(view) => view,
+        Err(err) => {
+            error!("Error opening the port form: {}", err);
+            metrics::command_outcome(Outcome::Error);
+            send_text(
+                slack,
+                envelope_id,
+                format!("Could not open the form: {}", err),
+            );
+            return;
+        }
+    };
+    slack.ack(envelope_id);
+    let modal = portconfig_modal(bot, &sites, &walljack, &payload.channel_id).await;
+    // Fails when the user closed the placeholder first
+    if let Err(err) = web
+        .update_view(&view.id, view.hash.as_deref(), &modal)
+        .await
+    {
+        error!("Error filling in the port form: {}", err);
+        metrics::command_outcome(Outcome::Error);
+    }
+}
 
-    cv.get_token_from_file("tokens/token.txt.1".to_string())
-        .unwrap();
+fn portconfig_args(text: &str) -> Result<(String, Option<String>), String> {
+    let args = CommandArgs::parse(text).map_err(|err| err.to_string())?;
+    args.only_flags(&["site"]).map_err(|err| err.to_string())?;
+    match args.positional.as_slice() {
+        [walljack] => Ok((walljack.clone(), args.flag("site").map(str::to_string))),
+        _ => Err("Usage: `/portconfig <walljack> [--site <name>]`".to_string()),
+    }
+}
 
-    cv.token = Some(config.cloudvision.token);
+// The form for a jack with its port's current settings, or a message saying why there is none
+async fn portconfig_modal(
+    bot: &Bot,
+    sites: &[&Site],
+    walljack: &str,
+    channel_id: &str,
+) -> slack::Modal {
+    let (device_id, interface_id, site) = match find_jack_at(sites, walljack).await {
+        (
+            Lookup::Found {
+                device_id,
+                interface_id,
+            },
+            _,
+            site,
+        ) => (device_id, interface_id, site),
+        (Lookup::NotFound, _, _) => {
+            return portconfig::message_modal("Wall jack number was not found".to_string())
+        }
+        (Lookup::Failed(err), _, site) => {
+            let text = format!(
+                "Could not look up wall jack {}{}: {}",
+                walljack,
+                bot.site_note(site),
+                err
+            );
+            return portconfig::message_modal(text);
+        }
+    };
+    let named = format!(
+        "{} on {}{}",
+        interface_id,
+        site.hostname(&device_id).await,
+        bot.site_note(site)
+    );
+    // Settings come from the switch's telemetry rather than the cache, so they are current
+    let current = match switch::ports(&site.cv, &device_id).await {
+        Ok(ports) => ports
+            .into_iter()
+            .find(|port| port.interface_id == interface_id)
+            .unwrap_or_default(),
+        Err(err) => {
+            let text = format!("Could not get the settings of {}: {}", named, err);
+            return portconfig::message_modal(text);
+        }
+    };
+    let port = portconfig::PortState {
+        site: site.name.clone(),
+        walljack: walljack.to_string(),
+        device_id,
+        interface_id,
+        channel_id: channel_id.to_string(),
+        enabled: current.enabled,
+        vlan: current.vlan,
+        description: current.description,
+    };
+    portconfig::modal(&port, &site.actions, &named)
+}
 
-    let slack_token = slack::Client::get_token_from_file("tokens/slack.token").unwrap();
-    let slack_token = config.slack.token;
-    let mut slack = slack::Client::new(slack_token);
+// Runs a submitted /portconfig form as one change control and tells the user how it went
+async fn portconfig_submit(bot: &Bot, user_id: &str, view: &slack::View) {
+    // Checked before the submission was acked
+    let config = match portconfig::parse(view) {
+        Ok(config) => config,
+        Err(_) => return,
+    };
+    let port = &config.port;
+    Span::current().record("walljack", field::display(&port.walljack));
+    let (text, undo) = match bot.sites.get(&port.site) {
+        Some(site) => apply_port_config(bot, site, user_id, &config).await,
+        None => (format!("Site {} is no longer configured", port.site), None),
+    };
+    let web = match &bot.web {
+        Some(web) => web,
+        None => return,
+    };
+    let message =
+        slack::MessagePayload::with_blocks(text.clone(), result_blocks(text.clone(), undo));
+    if let Err(err) = web
+        .post_ephemeral(&port.channel_id, user_id, &message)
+        .await
+    {
+        // Such as a channel the bot is not in, the user gets a direct message instead
+        warn!(channel = %port.channel_id, "Could not reply in the channel: {}", err);
+        bot.notify(user_id, text).await;
+    }
+}
 
-    slack.connect().await.unwrap();
-    loop {
-        let msg = slack.receive_message().await.unwrap();
-        match msg {
-            Message::Text(t) => handle_text(&cv, &t, &mut slack).await,
-            Message::Binary(_) => println!("binary"),
-            Message::Ping(_p) => {}
-            Message::Pong(_p) => {}
-            Message::Close(_) => break,
+// Runs a submitted form as one change control. Returns the reply and, if the change ran, its
+// change control id to offer an undo for.
+async fn apply_port_config(
+    bot: &Bot,
+    site: &Site,
+    user_id: &str,
+    config: &portconfig::PortConfig,
+) -> (String, Option<String>) {
+    let port = &config.port;
+    let named = format!(
+        "{} ({} on {})",
+        port.walljack,
+        port.interface_id,
+        site.hostname(&port.device_id).await
+    );
+    let now = std::time::Instant::now();
+    if let Err(limited) = bot.limits.check_request(user_id, &port.channel_id, now) {
+        metrics::command_outcome(Outcome::Rejected);
+        return (format!("Wall jack: {}: {}", named, limited), None);
+    }
+    let ports = BTreeMap::from([(port.device_id.clone(), vec![port.interface_id.clone()])]);
+    if let Err(limited) = bot.limit_ports(site, &ports) {
+        return (format!("Wall jack: {}: {}", named, limited), None);
+    }
+    let options = PortOptions {
+        start_at: None,
+        reenable_at: None,
+        justification: None,
+        channel_id: port.channel_id.clone(),
+        user_id: user_id.to_string(),
+        site: site.name.clone(),
+    };
+    match bot
+        .gate_change(site, std::slice::from_ref(&port.device_id), &options)
+        .await
+    {
+        Gate::Run => {}
+        Gate::Refuse(reason) => return (reason, None),
+        Gate::Queue(at) => {
+            metrics::command_outcome(Outcome::Rejected);
+            let text = format!(
+                "Wall jack: {} is outside the maintenance window, submit the form again after {}",
+                named,
+                slack::format_date(&at)
+            );
+            return (text, None);
+        }
+    }
+    match execute_change(bot, site, config.change(&site.actions)).await {
+        Ok(cc_id) => {
+            bot.limits
+                .changed(&site.name, &ports, std::time::Instant::now());
+            let record = ChangeRecord::single(
+                &site.name,
+                &cc_id,
+                user_id,
+                config.action(),
+                &port.walljack,
+                &port.device_id,
+                &port.interface_id,
+            )
+            .with_settings(config.settings(&site.actions));
+            bot.record_change(record);
+            let text = format!(
+                "Wall jack: {} has been changed: {} (change control `{}`)",
+                named,
+                config.summary(),
+                cc_id
+            );
+            (text, Some(cc_id))
+        }
+        Err(err) => {
+            let text = format!("Wall jack: {} could not be changed: {}", named, err);
+            (text, None)
         }
     }
-    Ok(())
 }
 
-async fn handle_text(cv: &cvp::Host, t: &str, slack: &mut slack::Client) {
-    let socket_event = slack::parse_message(t);
-    match socket_event {
-        slack::SocketEvent::EventsApi {
-            payload,
-            envelope_id: _,
-            accepts_response_payload: _,
-        } => {
-            println!("{:?}", payload);
-        }
-        slack::SocketEvent::SlashCommands {
-            payload,
-            envelope_id,
-            accepts_response_payload: _,
-        } => {
-            handle_slash_command(cv, slack, payload, envelope_id).await;
+// Looks a jack up at each site in turn and returns the first site that has it
+async fn find_jack_at<'a>(
+    sites: &[&'a Site],
+    walljack: &str,
+) -> (Lookup, Option<chrono::Duration>, &'a Site) {
+    let mut failed = None;
+    for site in sites {
+        match site.find_jack(walljack).await {
+            (Lookup::NotFound, _) => {}
+            (Lookup::Failed(err), _) => {
+                failed.get_or_insert(err);
+            }
+            (lookup, age) => return (lookup, age, site),
         }
-        slack::SocketEvent::Interactive {
-            payload,
-            envelope_id: _,
-            accepts_response_payload: _,
-        } => {
-            println!("Received interactive: {:?}", payload);
-            handle_interactive(payload).await;
-            println!("response sent");
+    }
+    let lookup = match failed {
+        Some(err) => Lookup::Failed(err),
+        None => Lookup::NotFound,
+    };
+    (lookup, None, sites[0])
+}
+
+// The `--site` given with a command that takes no other flags
+fn command_site_flag(text: &str) -> Result<Option<String>, String> {
+    let args = CommandArgs::parse(text).map_err(|err| err.to_string())?;
+    args.only_flags(&["site"]).map_err(|err| err.to_string())?;
+    Ok(args.flag("site").map(str::to_string))
+}
+
+// Button values carry the site ahead of the rest, values without one are for the first site
+fn split_site(value: &str) -> (&str, &str) {
+    value.split_once(' ').unwrap_or(("", value))
+}
+
+// Serial number, model and software of a switch, for the Switch details button
+async fn switch_details(bot: &Bot, value: &str) -> slack::MessagePayload {
+    let (site, device_id) = split_site(value);
+    let text = match bot.sites.get(site) {
+        Some(site) => match site.cv.get_device(device_id).await {
+            Ok(device) => switch::describe(&device),
+            Err(err) => format!("Could not get switch {}: {}", device_id, err),
+        },
+        None => format!("Site {} is no longer configured", site),
+    };
+    let mut message = slack::MessagePayload::new(text);
+    message.replace_original = Some(false);
+    message
+}
+
+// Finds a switch by hostname or serial number for the switch commands, at the site the
+// command goes to or else the first site that has it
+async fn find_switch<'a>(
+    bot: &'a Bot,
+    payload: &slack::SlashCommand,
+) -> Result<(&'a Site, Vec<String>, cvp::Device), String> {
+    let args = CommandArgs::parse(&payload.text).map_err(|err| err.to_string())?;
+    args.only_flags(&["site"]).map_err(|err| err.to_string())?;
+    let name = match args.positional.first() {
+        Some(name) => name.as_str(),
+        None => return Err("Give a switch hostname or serial number".to_string()),
+    };
+    let mut failed = None;
+    for site in bot.command_sites(args.flag("site"), &payload.channel_id)? {
+        match site.cv.get_all_devices().await {
+            Ok(devices) => {
+                if let Some(device) = switch::find_device(&devices, name) {
+                    return Ok((site, args.positional[1..].to_vec(), device.clone()));
+                }
+            }
+            Err(err) => {
+                failed.get_or_insert(format!(
+                    "Could not get the device inventory{}: {}",
+                    bot.site_note(site),
+                    err
+                ));
+            }
         }
     }
+    Err(failed.unwrap_or_else(|| format!("No switch named {} was found", name)))
 }
 
-async fn handle_interactive(payload: slack::Interactive) {
-    println!("Received interactive with actions {:?}", payload.actions);
-    let text = format!(
-        "Updated with segement ID {}",
-        payload.actions.first().unwrap().selected_option.text.text
-    );
-    let message = slack::MessagePayload {
-        text,
-        blocks: None,
-        thread_ts: None,
-        mrkdwn: false,
-    };
-    // Resposne to an interactive action is via response_url which is specific to the action and will tie into the block that sent the action
-    let response_json = serde_json::to_string(&message).unwrap();
-    println!("responding to : {}", &payload.response_url);
-    let client = reqwest::Client::new();
-    client
-        .post(&payload.response_url)
-        .body(response_json)
-        .send()
-        .await
-        .unwrap();
-    //TODO remove semicolon and make this return value
+// Shows the inventory fields of a switch
+async fn switchinfo(
+    bot: &Bot,
+    payload: &slack::SlashCommand,
+    envelope_id: &str,
+    slack: &mut slack::Client,
+) {
+    let resp_text = match find_switch(bot, payload).await {
+        Ok((site, _, device)) => format!("{}{}", switch::describe(&device), bot.site_note(site)),
+        Err(err) => err,
+    };
+    send_text(slack, envelope_id, resp_text);
 }
 
-// Matches possible slash commands
-// TODO: use an enum for commands
-async fn handle_slash_command(
-    cv: &cvp::Host,
+// Lists every interface of a switch, `/switchports <hostname> [page]`
+async fn switchports(
+    bot: &Bot,
+    payload: &slack::SlashCommand,
+    envelope_id: &str,
     slack: &mut slack::Client,
-    payload: slack::SlashCommand,
-    envelope_id: String,
 ) {
-    let command = &payload.get_command();
-    match command.as_str() {
-        "portcheck" => portcheck(cv, &payload.text, &envelope_id, slack).await,
-        "portdown" => port_shut(cv, &payload.text, &envelope_id, slack).await,
-        "portup" => port_no_shut(cv, &payload.text, &envelope_id, slack).await,
-        "portassign" => println!("Assign port {} ", &payload.text),
-        _ => println!("Unknown command {}", command),
-    }
+    let (site, rest, device) = match find_switch(bot, payload).await {
+        Ok(found) => found,
+        Err(err) => {
+            send_text(slack, envelope_id, err);
+            return;
+        }
+    };
+    let page = match rest.as_slice() {
+        [] => 1,
+        [page] => match page.parse::<usize>() {
+            Ok(page) if page > 0 => page,
+            _ => {
+                send_text(
+                    slack,
+                    envelope_id,
+                    format!("`{}` is not a page number", page),
+                );
+                return;
+            }
+        },
+        _ => {
+            let usage = "Usage: `/switchports <hostname> [page]`".to_string();
+            send_text(slack, envelope_id, usage);
+            return;
+        }
+    };
+    let blocks = switchports_blocks(site, &device, page - 1).await;
+    slack.send_response(envelope_id, BlockPayload::new(blocks));
 }
 
-async fn portcheck(cv: &cvp::Host, walljack: &str, envelope_id: &str, slack: &mut slack::Client) {
-    let device = get_tag_assignment(cv, "wall_jack".to_string(), walljack.to_string())
-        .await
-        .unwrap();
-    let resp_text = if let Some(first_device) = device.first() {
-        format!(
-            "Wall jack: {} is connected to port {} on switch {}",
-            walljack, &first_device.value.key.interface_id, &first_device.value.key.device_id
-        )
-    } else {
-        "Wall jack number was not found".to_string()
+// Previous and Next page buttons, the value is the site, device id and page
+async fn switchports_page(bot: &Bot, value: &str) -> slack::MessagePayload {
+    let (site, value) = match value.matches(' ').count() {
+        2 => split_site(value),
+        _ => ("", value),
     };
-    let block2 = Block::new_section(TextBlock::new_mrkdwn(resp_text));
-    let blocks = vec![block2];
-    let payload = BlockPayload::new(blocks);
-    slack.send_response(envelope_id, payload);
+    let (device_id, page) = match value.split_once(' ') {
+        Some((device_id, page)) => (device_id, page.parse().unwrap_or_default()),
+        None => (value, 0),
+    };
+    let blocks = match bot.sites.get(site) {
+        Some(site) => match site.cv.get_device(device_id).await {
+            Ok(device) => switchports_blocks(site, &device, page).await,
+            Err(err) => vec![Block::new_section(TextBlock::new_mrkdwn(format!(
+                "Could not get switch {}: {}",
+                device_id, err
+            )))],
+        },
+        None => vec![Block::new_section(TextBlock::new_mrkdwn(format!(
+            "Site {} is no longer configured",
+            site
+        )))],
+    };
+    let mut message = slack::MessagePayload::with_blocks("Switch ports".to_string(), blocks);
+    message.replace_original = Some(true);
+    message
 }
 
-async fn port_shut(cv: &cvp::Host, walljack: &str, envelope_id: &str, slack: &mut slack::Client) {
-    let resp_text;
-    let device = get_tag_assignment(cv, "wall_jack".to_string(), walljack.to_string())
-        .await
-        .unwrap();
-    if let Some(first_device) = device.first() {
-        resp_text = format!("Wall jack: {} has been shut down", walljack);
-        execute_shut_action(
-            cv,
-            &first_device.value.key.device_id,
-            &first_device.value.key.interface_id,
-        )
-        .await;
-    } else {
-        resp_text = "Wall jack number was not found".to_string();
+async fn switchports_blocks(site: &Site, device: &cvp::Device, page: usize) -> Vec<Block> {
+    let ports = match switch::ports(&site.cv, &device.key.device_id).await {
+        Ok(ports) => ports,
+        Err(err) => {
+            let text = format!(
+                "Could not get the ports of {}: {}",
+                switch::name(device),
+                err
+            );
+            return vec![Block::new_section(TextBlock::new_mrkdwn(text))];
+        }
+    };
+    let pages = switch::page_count(&ports);
+    let page = page.min(pages - 1);
+    let header = format!(
+        "*{}* has {} interfaces, page {} of {}",
+        switch::name(device),
+        ports.len(),
+        page + 1,
+        pages
+    );
+    let mut blocks = vec![
+        Block::new_section(TextBlock::new_mrkdwn(header)),
+        Block::new_section(TextBlock::new_mrkdwn(switch::port_table(&ports, page))),
+    ];
+    let mut buttons = Vec::new();
+    if page > 0 {
+        buttons.push(Button::new(
+            TextBlock::new_plain("Previous".to_string()),
+            "switchports_page".to_string(),
+            format!("{} {} {}", site.name, device.key.device_id, page - 1),
+        ));
     }
-    let block2 = Block::new_section(TextBlock::new_mrkdwn(resp_text));
-    let blocks = vec![block2];
-    let payload = BlockPayload::new(blocks);
-    slack.send_response(envelope_id, payload);
+    if page + 1 < pages {
+        buttons.push(Button::new(
+            TextBlock::new_plain("Next".to_string()),
+            "switchports_page".to_string(),
+            format!("{} {} {}", site.name, device.key.device_id, page + 1),
+        ));
+    }
+    if !buttons.is_empty() {
+        blocks.push(Block::new_actions(buttons));
+    }
+    blocks
+}
+
+async fn port_shut(
+    bot: &Bot,
+    site: &Site,
+    walljack: &str,
+    options: &PortOptions,
+    envelope_id: &str,
+    slack: &mut slack::Client,
+) {
+    let (device_id, interface_id, age) = match site.find_jack(walljack).await {
+        (
+            Lookup::Found {
+                device_id,
+                interface_id,
+            },
+            age,
+        ) => (device_id, interface_id, age),
+        (Lookup::NotFound, _) => {
+            send_text(
+                slack,
+                envelope_id,
+                "Wall jack number was not found".to_string(),
+            );
+            return;
+        }
+        (Lookup::Failed(err), _) => {
+            let text = format!("Could not look up wall jack {}: {}", walljack, err);
+            send_text(slack, envelope_id, text);
+            return;
+        }
+    };
+    let (text, undo) = match bot
+        .gate_change(site, std::slice::from_ref(&device_id), options)
+        .await
+    {
+        Gate::Refuse(reason) => (reason, None),
+        Gate::Queue(at) => (
+            queue_change(
+                bot,
+                PortAction::Shut,
+                at,
+                walljack,
+                &device_id,
+                &interface_id,
+                options,
+            ),
+            None,
+        ),
+        Gate::Run => {
+            run_port_action(
+                bot,
+                site,
+                PortAction::Shut,
+                walljack,
+                &device_id,
+                &interface_id,
+                options,
+            )
+            .await
+        }
+    };
+    let text = format!("{}{}", text, cache_note(age));
+    slack.send_response(envelope_id, BlockPayload::new(result_blocks(text, undo)));
 }
 async fn port_no_shut(
-    cv: &cvp::Host,
+    bot: &Bot,
+    site: &Site,
     walljack: &str,
+    options: &PortOptions,
     envelope_id: &str,
     slack: &mut slack::Client,
 ) {
     // TODO: pass function such as execute_no_shut_action as a functino parameter to a
     // function that will generate response and execute action
-    let resp_text;
-    let device = get_tag_assignment(cv, "wall_jack".to_string(), walljack.to_string())
+    let (device_id, interface_id, age) = match site.find_jack(walljack).await {
+        (
+            Lookup::Found {
+                device_id,
+                interface_id,
+            },
+            age,
+        ) => (device_id, interface_id, age),
+        (Lookup::NotFound, _) => {
+            send_text(
+                slack,
+                envelope_id,
+                "Wall jack number was not found".to_string(),
+            );
+            return;
+        }
+        (Lookup::Failed(err), _) => {
+            let text = format!("Could not look up wall jack {}: {}", walljack, err);
+            send_text(slack, envelope_id, text);
+            return;
+        }
+    };
+    let (text, undo) = match bot
+        .gate_change(site, std::slice::from_ref(&device_id), options)
         .await
-        .unwrap();
-    if let Some(first_device) = device.first() {
-        println!("No shut {:?} ", &first_device);
-        resp_text = format!("Wall jack: {} has been enabled", walljack);
-        execute_no_shut_action(
-            cv,
-            &first_device.value.key.device_id,
-            &first_device.value.key.interface_id,
-        )
-        .await;
-    } else {
-        resp_text = "Wall jack number was not found".to_string();
-    }
-    let block2 = Block::new_section(TextBlock::new_mrkdwn(resp_text));
-    let blocks = vec![block2];
-    let payload = BlockPayload::new(blocks);
-    slack.send_response(envelope_id, payload);
+    {
+        Gate::Refuse(reason) => (reason, None),
+        Gate::Queue(at) => (
+            queue_change(
+                bot,
+                PortAction::NoShut,
+                at,
+                walljack,
+                &device_id,
+                &interface_id,
+                options,
+            ),
+            None,
+        ),
+        Gate::Run => {
+            run_port_action(
+                bot,
+                site,
+                PortAction::NoShut,
+                walljack,
+                &device_id,
+                &interface_id,
+                options,
+            )
+            .await
+        }
+    };
+    let text = format!("{}{}", text, cache_note(age));
+    slack.send_response(envelope_id, BlockPayload::new(result_blocks(text, undo)));
 }
 
-// TODO: Not yet implemented
-fn _port_assign(text: &str, envelope_id: &str, slack: &mut slack::Client) {
-    let placeholder = TextBlock::new_plain("segment".to_string());
-    let option1 = OptionObject::new(
-        TextBlock::new_plain("USERS:VLAN 100".to_string()),
-        "vlan100".to_string(),
+// Runs a port action now, or hands it to CloudVision when a start time was given, then
+// schedules the re-enable of a temporary shut down. Returns the reply and, if the change ran,
+// its change control id to offer an undo for.
+async fn run_port_action(
+    bot: &Bot,
+    site: &Site,
+    action: PortAction,
+    walljack: &str,
+    device_id: &str,
+    interface_id: &str,
+    options: &PortOptions,
+) -> (String, Option<String>) {
+    // Name the port so a wrong jack is easy to spot
+    let named = format!(
+        "{} ({} on {})",
+        walljack,
+        interface_id,
+        site.hostname(device_id).await
     );
-    let accessory = StaticSelect::new(placeholder, "action123".to_string(), vec![option1]);
-    let first = format!("Choose a segment for walljack: {}", text);
-    let mut block1 = Block::new_section(TextBlock::new_mrkdwn(first));
-    block1.add_accessory(accessory);
-    let blocks = vec![block1];
-    let payload = BlockPayload::new(blocks);
-    slack.send_response(envelope_id, payload);
+    let ports = BTreeMap::from([(device_id.to_string(), vec![interface_id.to_string()])]);
+    if let Err(limited) = bot.limit_ports(site, &ports) {
+        return (format!("Wall jack: {}: {}", named, limited), None);
+    }
+    let mut change = build_port_change(
+        &site.actions,
+        action,
+        device_id.to_string(),
+        interface_id.to_string(),
+    );
+    if let Some(at) = options.start_at {
+        change.config = change.config.with_schedule(at);
+    }
+    let cc_id = match execute_change(bot, site, change).await {
+        Ok(cc_id) => cc_id,
+        Err(err) => {
+            let text = format!(
+                "Wall jack: {} could not be {}: {}",
+                named,
+                action.past_tense(),
+                err
+            );
+            return (text, None);
+        }
+    };
+    bot.limits
+        .changed(&site.name, &ports, std::time::Instant::now());
+    let mut undo = None;
+    let mut text = match options.start_at {
+        None => {
+            bot.record_change(ChangeRecord::single(
+                &site.name,
+                &cc_id,
+                &options.user_id,
+                action,
+                walljack,
+                device_id,
+                interface_id,
+            ));
+            undo = Some(cc_id.clone());
+            format!("Wall jack: {} has been {}", named, action.past_tense())
+        }
+        Some(at) => {
+            let kind = TaskKind::CloudVision {
+                action,
+                cc_id: cc_id.clone(),
+            };
+            match bot.schedule(kind, at, walljack, device_id, interface_id, options) {
+                Ok(id) => format!(
+                    "Wall jack: {} will be {} by CloudVision {} (change control `{}`, schedule `{}`)",
+                    named,
+                    action.past_tense(),
+                    slack::format_date(&at),
+                    cc_id,
+                    id
+                ),
+                Err(err) => format!(
+                    "Wall jack: {} will be {} by CloudVision {} (change control `{}`) but it could not be tracked: {}",
+                    named,
+                    action.past_tense(),
+                    slack::format_date(&at),
+                    cc_id,
+                    err
+                ),
+            }
+        }
+    };
+    if let Some(at) = options.reenable_at {
+        let scheduled = bot.schedule(
+            TaskKind::ReEnable,
+            at,
+            walljack,
+            device_id,
+            interface_id,
+            options,
+        );
+        text.push_str(&match scheduled {
+            Ok(id) => format!(
+                ", it will be re-enabled {} (schedule `{}`)",
+                slack::format_date(&at),
+                id
+            ),
+            Err(err) => format!(", but the re-enable could not be scheduled: {}", err),
+        });
+    }
+    (text, undo)
 }
 
-async fn execute_shut_action(cv: &cvp::Host, device: &str, interface: &str) {
-    // Build the action
-    let change = build_shut_action(device.to_string(), interface.to_string());
-    let change_json = serde_json::to_string(&change).unwrap();
-    let cc_res = cv.post_change_control(change_json).await.unwrap();
-    println!("post_change_control result: {}", cc_res);
+// A reply section, with an Undo button when a change control ran
+fn result_blocks(text: String, undo: Option<String>) -> Vec<Block> {
+    let mut blocks = vec![Block::new_section(TextBlock::new_mrkdwn(text))];
+    if let Some(cc_id) = undo {
+        blocks.push(Block::new_actions(vec![Button::new(
+            TextBlock::new_plain("Undo".to_string()),
+            "undo".to_string(),
+            cc_id,
+        )]));
+    }
+    blocks
+}
 
-    // Approve the change
-    let cc_timestamp = format!("{:?}", Utc::now());
-    let cc_id = change.config.id;
-    let approval = Approval {
-        cc_id: cc_id.clone(),
-        cc_timestamp,
+// Reverts a change control the user ran through the bot, their last one unless `cc_id` is
+// given, by running the inverse action through the usual calendar and approval path
+#[tracing::instrument(skip(bot))]
+async fn undo_change(
+    bot: &Bot,
+    user_id: &str,
+    channel_id: &str,
+    cc_id: Option<&str>,
+) -> (String, Option<String>) {
+    let record = {
+        let history = bot.history.lock().unwrap();
+        match cc_id {
+            Some(cc_id) => history.get(cc_id).cloned(),
+            None => history.last_for(user_id).cloned(),
+        }
     };
-    let response = cv.approve_change_control(approval).await.unwrap();
-    println!("approval response: {}", response);
-    let start = StartChange {
-        cc_id: cc_id.clone(),
+    let record = match (record, cc_id) {
+        (Some(record), _) => record,
+        (None, Some(cc_id)) => {
+            let text = format!(
+                "Change control `{}` was not made by the bot recently",
+                cc_id
+            );
+            return (text, None);
+        }
+        (None, None) => return ("You have no recent change to undo".to_string(), None),
     };
-    // Execute the change
-    cv.execute_change_control(start).await.unwrap();
+    if record.user_id != user_id {
+        let text = format!(
+            "Change control `{}` was made by <@{}>, only they can undo it",
+            record.cc_id, record.user_id
+        );
+        return (text, None);
+    }
+    if let Some(undone_by) = &record.undone_by {
+        let text = format!(
+            "Change control `{}` was already undone by `{}`",
+            record.cc_id, undone_by
+        );
+        return (text, None);
+    }
+    let inverse = match record.inverse() {
+        Some(inverse) => inverse,
+        None => {
+            let text = format!("Change control `{}` cannot be undone", record.cc_id);
+            return (text, None);
+        }
+    };
+    let site = match bot.sites.get(&record.site) {
+        Some(site) => site,
+        None => {
+            let text = format!(
+                "Change control `{}` was made at site {}, which is no longer configured",
+                record.cc_id, record.site
+            );
+            return (text, None);
+        }
+    };
+    let options = PortOptions {
+        channel_id: channel_id.to_string(),
+        user_id: user_id.to_string(),
+        site: site.name.clone(),
+        ..PortOptions::default()
+    };
+    let device_ids: Vec<String> = record.ports.keys().cloned().collect();
+    let walljacks = record.walljacks.join(", ");
+    match bot.gate_change(site, &device_ids, &options).await {
+        Gate::Run => {}
+        Gate::Refuse(reason) => return (reason, None),
+        Gate::Queue(at) => {
+            let text = format!(
+                "Undoing `{}` is outside the maintenance window, which next opens {}",
+                record.cc_id,
+                slack::format_date(&at)
+            );
+            return (text, None);
+        }
+    }
+    let now = std::time::Instant::now();
+    if let Err(limited) = bot.limits.check_request(user_id, channel_id, now) {
+        metrics::command_outcome(Outcome::Rejected);
+        return (limited.to_string(), None);
+    }
+    if let Err(limited) = bot.limit_ports(site, &record.ports) {
+        return (limited.to_string(), None);
+    }
+    // Settings are only recorded for the single port a /portconfig form changed
+    let port = record
+        .ports
+        .iter()
+        .find_map(|(device_id, interfaces)| Some((device_id, interfaces.first()?)));
+    let (change, changed) = match (port, inverse.action) {
+        (Some((device_id, interface_id)), _) if !inverse.settings.is_empty() => {
+            let port = portconfig::PortState {
+                site: site.name.clone(),
+                walljack: walljacks.clone(),
+                device_id: device_id.clone(),
+                interface_id: interface_id.clone(),
+                channel_id: channel_id.to_string(),
+                ..portconfig::PortState::default()
+            };
+            let config = portconfig::PortConfig::undo(port, &inverse);
+            let changed = format!("changed: {}", config.summary());
+            (config.change(&site.actions), changed)
+        }
+        (_, Some(action)) => {
+            let change = build_bulk_change(&site.actions, action, &record.ports);
+            (change, action.past_tense().to_string())
+        }
+        (_, None) => {
+            let text = format!("Change control `{}` cannot be undone", record.cc_id);
+            return (text, None);
+        }
+    };
+    let cc_id = match execute_change(bot, site, change).await {
+        Ok(cc_id) => cc_id,
+        Err(err) => {
+            let text = format!("Could not undo change control `{}`: {}", record.cc_id, err);
+            return (text, None);
+        }
+    };
+    bot.limits
+        .changed(&site.name, &record.ports, std::time::Instant::now());
+    if let Err(err) = bot
+        .history
+        .lock()
+        .unwrap()
+        .mark_undone(&record.cc_id, &cc_id)
+    {
+        error!("Error saving the undo history: {}", err);
+    }
+    let undo = ChangeRecord::new(
+        &site.name,
+        &cc_id,
+        user_id,
+        inverse.action,
+        record.walljacks.clone(),
+        record.ports.clone(),
+    );
+    bot.record_change(undo.with_settings(inverse.settings.clone()));
+    let mut text = format!(
+        "Undid change control `{}`, wall jack {} has been {} (change control `{}`)",
+        record.cc_id, walljacks, changed, cc_id
+    );
+    // A pending re-enable of a shut down that was undone is no longer needed
+    if inverse.action == Some(PortAction::NoShut) {
+        let mut schedules = bot.schedules.lock().unwrap();
+        let reenables: Vec<String> = schedules
+            .tasks()
+            .iter()
+            .filter(|task| task.kind == TaskKind::ReEnable)
+            .filter(|task| {
+                record
+                    .ports
+                    .get(&task.device_id)
+                    .is_some_and(|interfaces| interfaces.contains(&task.interface_id))
+            })
+            .map(|task| task.id.clone())
+            .collect();
+        for id in &reenables {
+            if let Err(err) = schedules.remove(id) {
+                error!("Error removing scheduled task {}: {}", id, err);
+            }
+        }
+        if !reenables.is_empty() {
+            text.push_str(", the scheduled re-enable was cancelled");
+        }
+    }
+    (text, Some(cc_id))
+}
+
+async fn execute_shut_action(
+    bot: &Bot,
+    site: &Site,
+    device: &str,
+    interface: &str,
+) -> Result<String, CloudVisionError> {
+    // Build the action
+    let change = build_shut_action(&site.actions, device.to_string(), interface.to_string());
+    execute_change(bot, site, change).await
 }
-async fn execute_no_shut_action(cv: &cvp::Host, device: &str, interface: &str) {
+async fn execute_no_shut_action(
+    bot: &Bot,
+    site: &Site,
+    device: &str,
+    interface: &str,
+) -> Result<String, CloudVisionError> {
     // Build the action
-    let change = build_no_shut_action(device.to_string(), interface.to_string());
-    let change_json = serde_json::to_string(&change).unwrap();
-    let cc_res = cv.post_change_control(change_json).await.unwrap();
-    println!("post_change_control result: {}", cc_res);
+    let change = build_no_shut_action(&site.actions, device.to_string(), interface.to_string());
+    execute_change(bot, site, change).await
+}
+
+// Creates, approves and, unless CloudVision has been given a schedule, starts a change
+// control, returning its id
+#[tracing::instrument(skip_all, fields(cc_id = %change.config.id))]
+async fn execute_change(
+    bot: &Bot,
+    site: &Site,
+    change: Change,
+) -> Result<String, CloudVisionError> {
+    let cc_id = change.config.id.clone();
+    let result = create_change(bot, site, change).await;
+    if result.is_err() {
+        metrics().change_control(ChangeEvent::Failed);
+    }
+    bot.drain.finished(&cc_id);
+    result
+}
+
+// Creates and approves a change control, and starts it unless it is scheduled
+async fn create_change(bot: &Bot, site: &Site, change: Change) -> Result<String, CloudVisionError> {
+    let cv = &site.cv;
+    let change_json = serde_json::to_string(&change)?;
+    let cc_res = cv.post_change_control(change_json).await?;
+    metrics().change_control(ChangeEvent::Created);
+    bot.drain.created(&site.name, &change.config.id);
+    info!(response = %cc_res, "Created change control");
 
     // Approve the change
     let cc_timestamp = format!("{:?}", Utc::now());
@@ -331,35 +3247,53 @@ async fn execute_no_shut_action(cv: &cvp::Host, device: &str, interface: &str) {
         cc_id: cc_id.clone(),
         cc_timestamp,
     };
-    let response = cv.approve_change_control(approval).await.unwrap();
-    println!("approval response: {}", response);
+    let response = cv.approve_change_control(approval).await?;
+    metrics().change_control(ChangeEvent::Approved);
+    bot.drain.approved(&cc_id);
+    info!(%response, "Approved change control");
+    if change.config.schedule.is_some() {
+        return Ok(cc_id);
+    }
     let start = StartChange {
         cc_id: cc_id.clone(),
     };
     // Execute the change
-    cv.execute_change_control(start).await.unwrap();
+    cv.execute_change_control(start).await?;
+    metrics().change_control(ChangeEvent::Started);
+    Ok(cc_id)
+}
+
+fn build_port_change(
+    actions: &ActionIds,
+    action: PortAction,
+    device: String,
+    interface: String,
+) -> Change {
+    match action {
+        PortAction::Shut => build_shut_action(actions, device, interface),
+        PortAction::NoShut => build_no_shut_action(actions, device, interface),
+    }
 }
 
-fn build_no_shut_action(device: String, interface: String) -> Change {
+fn build_no_shut_action(actions: &ActionIds, device: String, interface: String) -> Change {
     let mut args = HashMap::new();
     args.insert("DeviceID".to_string(), device);
     args.insert("interface".to_string(), interface);
-    let action_name = "rfzsJdsdQEU9EOlPeNeAL".to_string();
-    let stage_name = "no_shut_interface".to_string();
+    let action_name = actions.no_shut.clone();
+    let stage_name = PortAction::NoShut.stage_name().to_string();
     build_action_change(action_name, stage_name, args)
 }
 
-fn build_shut_action(device: String, interface: String) -> Change {
+fn build_shut_action(actions: &ActionIds, device: String, interface: String) -> Change {
     let utc = Utc::now().format("%y-%m-%d-%H-%M-%S").to_string();
     let mut args = HashMap::new();
     args.insert("DeviceID".to_string(), device);
     args.insert("interface".to_string(), interface);
-    // TODO: put action name and id into config struct
     let action = Action {
-        name: "ps5pMVndlXpK6IsQJGr7U".to_string(),
+        name: actions.shut.clone(),
         args,
     };
-    let stage = Stage::new("shut_interface".to_string(), action);
+    let stage = Stage::new(PortAction::Shut.stage_name().to_string(), action);
     let stages = vec![stage];
     let stage_row = StageRow { stage: stages };
     let stage_rows = vec![stage_row];
@@ -387,6 +3321,40 @@ fn build_action_change(
     Change { config }
 }
 
+// Builds one change control for many interfaces. Each row holds at most one interface per
+// device, so the devices run in parallel while a device's interfaces run one after another.
+fn build_bulk_change(
+    actions: &ActionIds,
+    action: PortAction,
+    devices: &BTreeMap<String, Vec<String>>,
+) -> Change {
+    let utc = Utc::now().format("%y-%m-%d-%H-%M-%S").to_string();
+    let row_count = devices.values().map(|i| i.len()).max().unwrap_or_default();
+    let stage_rows = (0..row_count)
+        .map(|row| {
+            let stage = devices
+                .iter()
+                .filter_map(|(device, interfaces)| {
+                    let interface = interfaces.get(row)?;
+                    let mut args = HashMap::new();
+                    args.insert("DeviceID".to_string(), device.clone());
+                    args.insert("interface".to_string(), interface.clone());
+                    let stage_action = Action {
+                        name: actions.get(action).to_string(),
+                        args,
+                    };
+                    let name = format!("{} {} {}", action.stage_name(), device, interface);
+                    Some(Stage::new(name, stage_action))
+                })
+                .collect();
+            StageRow { stage }
+        })
+        .collect();
+    let root_stage = RootStage::new(format!("Change {} root", utc), stage_rows);
+    let config = ChangeConfig::new(format!("Bulk change {}", utc), root_stage);
+    Change { config }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -404,12 +3372,28 @@ mod tests {
         let cloudvision = CloudVisionConfig {
             hostname: "www.cv-staging.arista.io".to_string(),
             port: 443,
-            token: "cvptoken".to_string(),
+            token: Secret::new("cvptoken".to_string()),
+            ..CloudVisionConfig::default()
         };
         let slack = SlackConfig {
-            token: "slacktoken".to_string(),
+            token: Secret::new("slacktoken".to_string()),
+            bot_token: None,
+        };
+        let base_config = Config {
+            cloudvision: vec![cloudvision],
+            slack,
+            schedule: ScheduleConfig::default(),
+            history: HistoryConfig::default(),
+            change_calendar: ChangeCalendar::default(),
+            audit: AuditConfig::default(),
+            cache: CacheConfig::default(),
+            lookup: LookupConfig::default(),
+            tokens: TokenConfig::default(),
+            logging: LoggingConfig::default(),
+            metrics: MetricsConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            limits: LimitsConfig::default(),
         };
-        let base_config = Config { cloudvision, slack };
         assert_eq!(config, base_config);
     }
     #[test]
@@ -419,23 +3403,101 @@ mod tests {
         let cli = Cli {
             cvp_host: Some("www.cv-staging.arista.io".to_string()),
             cvp_port: Some(443),
-            cvp_token: Some("cvptoken".to_string()),
-            slack_token: Some("slacktoken".to_string()),
+            cvp_token: Some(Secret::new("cvptoken".to_string())),
+            slack_token: Some(Secret::new("slacktoken".to_string())),
+            slack_bot_token: None,
             config_file,
+            site: None,
+            command: None,
         };
         let config = Config::new_from_cli(cli);
         let cloudvision = CloudVisionConfig {
             hostname: "www.cv-staging.arista.io".to_string(),
             port: 443,
-            token: "cvptoken".to_string(),
+            token: Secret::new("cvptoken".to_string()),
+            ..CloudVisionConfig::default()
         };
         let slack = SlackConfig {
-            token: "slacktoken".to_string(),
+            token: Secret::new("slacktoken".to_string()),
+            bot_token: None,
+        };
+        let base_config = Config {
+            cloudvision: vec![cloudvision],
+            slack,
+            schedule: ScheduleConfig::default(),
+            history: HistoryConfig::default(),
+            change_calendar: ChangeCalendar::default(),
+            audit: AuditConfig::default(),
+            cache: CacheConfig::default(),
+            lookup: LookupConfig::default(),
+            tokens: TokenConfig::default(),
+            logging: LoggingConfig::default(),
+            metrics: MetricsConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            limits: LimitsConfig::default(),
         };
-        let base_config = Config { cloudvision, slack };
         assert_eq!(config, base_config);
     }
     #[test]
+    fn test_several_clusters() {
+        let toml_str = r#"
+        [[cloudvision]]
+        name = "emea"
+        hostname = "cv-emea.example.com"
+        port = 443
+        token = "token1"
+        jack_prefixes = ["LON-"]
+        [[cloudvision]]
+        name = "us"
+        hostname = "cv-us.example.com"
+        port = 443
+        token = "token2"
+        channels = ["C_US"]
+        [cloudvision.actions]
+        shut = "usShut"
+        [slack]
+        token = "slacktoken"
+        "#;
+        let config = Config::new_from_toml(toml_str);
+        assert_eq!(config.cloudvision.len(), 2);
+        assert_eq!(config.cloudvision[0].jack_prefixes, vec!["LON-"]);
+        assert_eq!(config.cloudvision[1].actions.shut, "usShut");
+        let change = build_port_change(
+            &config.cloudvision[1].actions,
+            PortAction::Shut,
+            "JPE1".to_string(),
+            "Ethernet1".to_string(),
+        );
+        let stage = &change.config.root_stage.stage_row[0].stage[0];
+        assert_eq!(stage.action.name, "usShut");
+    }
+    #[test]
+    fn test_check_label() {
+        let labels = vec!["wall_jack".to_string(), "desk".to_string()];
+        assert!(check_label("desk=4F-22", &labels).is_ok());
+        assert!(check_label("2-114", &labels).is_ok());
+        assert!(check_label("vlan=20", &labels).is_err());
+        assert!(check_label("desk=", &labels).is_err());
+    }
+    #[test]
+    fn test_bulk_change() {
+        let mut devices = BTreeMap::new();
+        devices.insert(
+            "JPE1".to_string(),
+            vec!["Ethernet1".to_string(), "Ethernet2".to_string()],
+        );
+        devices.insert("JPE2".to_string(), vec!["Ethernet7".to_string()]);
+        let change = build_bulk_change(&ActionIds::default(), PortAction::Shut, &devices);
+        let rows = &change.config.root_stage.stage_row;
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].stage.len(), 2);
+        assert_eq!(rows[1].stage.len(), 1);
+        let last = &rows[1].stage[0];
+        assert_eq!(last.action.name, PortAction::Shut.action_id());
+        assert_eq!(last.action.args["DeviceID"], "JPE1");
+        assert_eq!(last.action.args["interface"], "Ethernet2");
+    }
+    #[test]
     fn test_action_change() {
         let device = "JPE1999";
         let interface = "Ethernet1";
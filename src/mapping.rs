@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cvp::{self, CloudVisionError};
+use crate::tags::{self, Assignment, TagChanges};
+
+#[derive(Debug)]
+pub enum MappingError {
+    Io(std::io::Error),
+    Csv(csv::Error),
+    CloudVision(CloudVisionError),
+    Invalid(String),
+}
+
+impl fmt::Display for MappingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MappingError::Io(err) => write!(f, "{}", err),
+            MappingError::Csv(err) => write!(f, "CSV error: {}", err),
+            MappingError::CloudVision(err) => write!(f, "{}", err),
+            MappingError::Invalid(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<std::io::Error> for MappingError {
+    fn from(err: std::io::Error) -> Self {
+        MappingError::Io(err)
+    }
+}
+impl From<csv::Error> for MappingError {
+    fn from(err: csv::Error) -> Self {
+        MappingError::Csv(err)
+    }
+}
+impl From<CloudVisionError> for MappingError {
+    fn from(err: CloudVisionError) -> Self {
+        MappingError::CloudVision(err)
+    }
+}
+
+/// A row of a patch schedule, extra columns such as `hostname` are ignored on import
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct MappingRow {
+    pub wall_jack: String,
+    pub device: String,
+    pub interface: String,
+    #[serde(default, skip_deserializing)]
+    pub hostname: String,
+}
+
+/// Reads `wall_jack,device,interface` rows. A jack or an interface listed twice is an error.
+pub fn read_csv<R: std::io::Read>(reader: R) -> Result<Vec<Assignment>, MappingError> {
+    let mut rows = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+    let mut jacks: HashMap<String, usize> = HashMap::new();
+    let mut ports: HashMap<(String, String), usize> = HashMap::new();
+    let mut assignments = Vec::new();
+    for (index, row) in rows.deserialize().enumerate() {
+        let row: MappingRow = row?;
+        // Line 1 is the header
+        let line = index + 2;
+        if row.wall_jack.is_empty() || row.device.is_empty() || row.interface.is_empty() {
+            return Err(MappingError::Invalid(format!(
+                "line {} has an empty field",
+                line
+            )));
+        }
+        if let Some(first) = jacks.insert(row.wall_jack.clone(), line) {
+            return Err(MappingError::Invalid(format!(
+                "wall jack {} is on line {} and line {}",
+                row.wall_jack, first, line
+            )));
+        }
+        let port = (row.device.clone(), row.interface.clone());
+        if let Some(first) = ports.insert(port, line) {
+            return Err(MappingError::Invalid(format!(
+                "{} on {} is on line {} and line {}",
+                row.interface, row.device, first, line
+            )));
+        }
+        assignments.push(Assignment::new(&row.wall_jack, &row.device, &row.interface));
+    }
+    Ok(assignments)
+}
+
+/// Differences between the wanted and current mappings
+#[derive(Debug, Default, PartialEq)]
+pub struct MappingDiff {
+    pub adds: Vec<Assignment>,
+    pub removes: Vec<Assignment>,
+    // (from, to)
+    pub moves: Vec<(Assignment, Assignment)>,
+}
+
+impl MappingDiff {
+    pub fn is_empty(&self) -> bool {
+        self.adds.is_empty() && self.removes.is_empty() && self.moves.is_empty()
+    }
+
+    pub fn describe(&self) -> Vec<String> {
+        let adds = self
+            .adds
+            .iter()
+            .map(|a| format!("add    {} -> {} {}", a.value, a.device_id, a.interface_id));
+        let moves = self.moves.iter().map(|(from, to)| {
+            format!(
+                "move   {} {} {} -> {} {}",
+                to.value, from.device_id, from.interface_id, to.device_id, to.interface_id
+            )
+        });
+        let removes = self
+            .removes
+            .iter()
+            .map(|a| format!("remove {} -> {} {}", a.value, a.device_id, a.interface_id));
+        adds.chain(moves).chain(removes).collect()
+    }
+
+    pub fn to_changes(&self, label: &str) -> TagChanges {
+        let mut changes = TagChanges::new(label);
+        for (from, to) in &self.moves {
+            changes.remove.push(from.clone());
+            changes.add.push(to.clone());
+        }
+        changes.add.extend(self.adds.iter().cloned());
+        changes.remove.extend(self.removes.iter().cloned());
+        changes
+    }
+}
+
+/// Compares wanted mappings with the current ones. A current assignment is removed when its
+/// jack moves, when its interface gets another jack, or with `prune` when the jack is not
+/// wanted at all.
+pub fn diff(wanted: &[Assignment], current: &[Assignment], prune: bool) -> MappingDiff {
+    let mut result = MappingDiff::default();
+    let mut moved: Vec<&str> = Vec::new();
+    for old in current.iter().filter(|c| !wanted.contains(c)) {
+        let same_jack = wanted.iter().find(|w| w.value == old.value);
+        let port_taken = wanted
+            .iter()
+            .any(|w| w.device_id == old.device_id && w.interface_id == old.interface_id);
+        match same_jack {
+            // A jack tagged twice only moves once, its other assignments are removed
+            Some(new) if !moved.contains(&new.value.as_str()) && !current.contains(new) => {
+                moved.push(&new.value);
+                result.moves.push((old.clone(), new.clone()));
+            }
+            Some(_) => result.removes.push(old.clone()),
+            None if port_taken || prune => result.removes.push(old.clone()),
+            None => {}
+        }
+    }
+    result.adds = wanted
+        .iter()
+        .filter(|w| !current.contains(w) && !moved.contains(&w.value.as_str()))
+        .cloned()
+        .collect();
+    result
+}
+
+/// Reads a CSV and applies the differences in one tag workspace, printing the plan first
+pub async fn import(
+    cv: &cvp::Host,
+    path: &Path,
+    prune: bool,
+    dry_run: bool,
+) -> Result<(), MappingError> {
+    let file = std::fs::File::open(path)?;
+    let wanted = read_csv(file)?;
+    let current = tags::assignments(cv, tags::WALL_JACK, None, None).await?;
+    let plan = diff(&wanted, &current, prune);
+    if plan.is_empty() {
+        println!("Nothing to change, {} mappings match", wanted.len());
+        return Ok(());
+    }
+    for line in plan.describe() {
+        println!("{}", line);
+    }
+    println!(
+        "{} adds, {} moves, {} removes",
+        plan.adds.len(),
+        plan.moves.len(),
+        plan.removes.len()
+    );
+    if dry_run {
+        return Ok(());
+    }
+    let description = format!("Imported from {}", path.display());
+    let workspace_id = tags::apply(cv, &plan.to_changes(tags::WALL_JACK), &description).await?;
+    println!("Submitted workspace {}", workspace_id);
+    Ok(())
+}
+
+/// Writes the current mappings, with device hostnames, as CSV
+pub async fn export<W: std::io::Write>(cv: &cvp::Host, writer: W) -> Result<usize, MappingError> {
+    let mut current = tags::assignments(cv, tags::WALL_JACK, None, None).await?;
+    current.sort();
+    let devices = cv.get_all_devices().await?;
+    let hostnames: HashMap<String, String> = devices
+        .into_iter()
+        .map(|d| (d.key.device_id, d.hostname))
+        .collect();
+    let mut csv = csv::Writer::from_writer(writer);
+    for assignment in &current {
+        csv.serialize(MappingRow {
+            wall_jack: assignment.value.clone(),
+            device: assignment.device_id.clone(),
+            interface: assignment.interface_id.clone(),
+            hostname: hostnames
+                .get(&assignment.device_id)
+                .cloned()
+                .unwrap_or_default(),
+        })?;
+    }
+    csv.flush()?;
+    Ok(current.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_read_csv() {
+        let data = "wall_jack,device,interface,hostname\n2-114, JPE1 ,Ethernet1,sw1\n2-115,JPE1,Ethernet2,sw1\n";
+        let rows = read_csv(data.as_bytes()).unwrap();
+        assert_eq!(rows[0], Assignment::new("2-114", "JPE1", "Ethernet1"));
+        assert_eq!(rows.len(), 2);
+
+        let duplicate = "wall_jack,device,interface\n2-114,JPE1,Ethernet1\n2-114,JPE1,Ethernet2\n";
+        assert!(matches!(
+            read_csv(duplicate.as_bytes()),
+            Err(MappingError::Invalid(_))
+        ));
+        let same_port = "wall_jack,device,interface\n2-114,JPE1,Ethernet1\n2-115,JPE1,Ethernet1\n";
+        assert!(read_csv(same_port.as_bytes()).is_err());
+    }
+    #[test]
+    fn test_diff() {
+        let current = vec![
+            Assignment::new("A1", "JPE1", "Ethernet1"),
+            Assignment::new("A2", "JPE1", "Ethernet2"),
+            Assignment::new("A3", "JPE1", "Ethernet3"),
+            Assignment::new("A4", "JPE1", "Ethernet4"),
+        ];
+        let wanted = vec![
+            // unchanged
+            Assignment::new("A1", "JPE1", "Ethernet1"),
+            // moved
+            Assignment::new("A2", "JPE2", "Ethernet2"),
+            // new jack on A3's port, A3 is removed
+            Assignment::new("B1", "JPE1", "Ethernet3"),
+        ];
+        let plan = diff(&wanted, &current, false);
+        assert_eq!(plan.moves, vec![(current[1].clone(), wanted[1].clone())]);
+        assert_eq!(plan.adds, vec![wanted[2].clone()]);
+        assert_eq!(plan.removes, vec![current[2].clone()]);
+
+        // A4 is not in the CSV, pruning removes it
+        let plan = diff(&wanted, &current, true);
+        assert_eq!(plan.removes, vec![current[2].clone(), current[3].clone()]);
+        let changes = plan.to_changes(tags::WALL_JACK);
+        assert_eq!(changes.add.len(), 2);
+        assert_eq!(changes.remove.len(), 3);
+    }
+}
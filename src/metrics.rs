@@ -0,0 +1,402 @@
+use std::cell::Cell;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use prometheus::{
+    Encoder, Gauge, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tracing::{error, info};
+
+#[derive(Deserialize, Debug, PartialEq, Default)]
+pub struct MetricsConfig {
+    // Address to serve /metrics and /healthz on, such as "0.0.0.0:9100"; none turns them off
+    #[serde(default)]
+    pub listen: Option<SocketAddr>,
+}
+
+/// How a command ended
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Ok,
+    // Bad arguments, an unknown command or a change that was not allowed
+    Rejected,
+    // A CloudVision or Slack request failed
+    Error,
+}
+
+impl Outcome {
+    fn label(self) -> &'static str {
+        match self {
+            Outcome::Ok => "ok",
+            Outcome::Rejected => "rejected",
+            Outcome::Error => "error",
+        }
+    }
+}
+
+/// Steps of a change control the bot runs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeEvent {
+    Created,
+    Approved,
+    Started,
+    Failed,
+}
+
+impl ChangeEvent {
+    fn label(self) -> &'static str {
+        match self {
+            ChangeEvent::Created => "created",
+            ChangeEvent::Approved => "approved",
+            ChangeEvent::Started => "started",
+            ChangeEvent::Failed => "failed",
+        }
+    }
+}
+
+pub struct Metrics {
+    registry: Registry,
+    commands: IntCounterVec,
+    command_seconds: HistogramVec,
+    cloudvision_seconds: HistogramVec,
+    cloudvision_errors: IntCounterVec,
+    change_controls: IntCounterVec,
+    reconnects: IntCounter,
+    connected: IntGauge,
+    since_last_message: Gauge,
+    last_message: Mutex<Option<Instant>>,
+    slack_connected: AtomicBool,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let commands = IntCounterVec::new(
+            Opts::new(
+                "slack_commands_total",
+                "Slash commands and actions received",
+            ),
+            &["command", "outcome"],
+        )
+        .unwrap();
+        let command_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "slack_command_duration_seconds",
+                "Time to handle a slash command or action",
+            )
+            .buckets(vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 180.0]),
+            &["command"],
+        )
+        .unwrap();
+        let cloudvision_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "cloudvision_request_duration_seconds",
+                "Time taken by CloudVision API requests",
+            ),
+            &["endpoint"],
+        )
+        .unwrap();
+        let cloudvision_errors = IntCounterVec::new(
+            Opts::new(
+                "cloudvision_request_errors_total",
+                "CloudVision API requests that failed",
+            ),
+            &["endpoint"],
+        )
+        .unwrap();
+        let change_controls = IntCounterVec::new(
+            Opts::new("change_controls_total", "Change controls by step reached"),
+            &["event"],
+        )
+        .unwrap();
+        let reconnects = IntCounter::new(
+            "slack_reconnects_total",
+            "Socket Mode connections opened again after Slack closed one",
+        )
+        .unwrap();
+        let connected = IntGauge::new(
+            "slack_connected",
+            "1 while the Socket Mode connection is open",
+        )
+        .unwrap();
+        let since_last_message = Gauge::new(
+            "slack_seconds_since_last_message",
+            "Seconds since the last message from Slack",
+        )
+        .unwrap();
+        let registry = Registry::new();
+        registry.register(Box::new(commands.clone())).unwrap();
+        registry
+            .register(Box::new(command_seconds.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(cloudvision_seconds.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(cloudvision_errors.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(change_controls.clone()))
+            .unwrap();
+        registry.register(Box::new(reconnects.clone())).unwrap();
+        registry.register(Box::new(connected.clone())).unwrap();
+        registry
+            .register(Box::new(since_last_message.clone()))
+            .unwrap();
+        Metrics {
+            registry,
+            commands,
+            command_seconds,
+            cloudvision_seconds,
+            cloudvision_errors,
+            change_controls,
+            reconnects,
+            connected,
+            since_last_message,
+            last_message: Mutex::new(None),
+            slack_connected: AtomicBool::new(false),
+        }
+    }
+
+    pub fn command(&self, command: &str, outcome: Outcome, elapsed: Duration) {
+        self.commands
+            .with_label_values(&[command, outcome.label()])
+            .inc();
+        self.command_seconds
+            .with_label_values(&[command])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn cloudvision_request(&self, path: &str, elapsed: Duration, ok: bool) {
+        let endpoint = endpoint(path);
+        self.cloudvision_seconds
+            .with_label_values(&[endpoint])
+            .observe(elapsed.as_secs_f64());
+        if !ok {
+            self.cloudvision_errors.with_label_values(&[endpoint]).inc();
+        }
+    }
+
+    pub fn change_control(&self, event: ChangeEvent) {
+        self.change_controls
+            .with_label_values(&[event.label()])
+            .inc();
+    }
+
+    pub fn slack_connected(&self, connected: bool) {
+        self.slack_connected.store(connected, Ordering::SeqCst);
+        self.connected.set(connected as i64);
+    }
+
+    pub fn slack_reconnect(&self) {
+        self.reconnects.inc();
+    }
+
+    pub fn slack_message(&self) {
+        *self.last_message.lock().unwrap() = Some(Instant::now());
+    }
+
+    fn is_slack_connected(&self) -> bool {
+        self.slack_connected.load(Ordering::SeqCst)
+    }
+
+    /// The metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        if let Some(at) = *self.last_message.lock().unwrap() {
+            self.since_last_message.set(at.elapsed().as_secs_f64());
+        }
+        let mut out = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut out)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+// Paths that name a device or change control are grouped, so each endpoint is one series
+fn endpoint(path: &str) -> &str {
+    let path = path.split('?').next().unwrap_or_default();
+    if path.starts_with("/api/v1/rest/") {
+        "/api/v1/rest"
+    } else {
+        path
+    }
+}
+
+tokio::task_local! {
+    static OUTCOME: Cell<Outcome>;
+}
+
+/// Handles a command, counting it and timing it. The handler marks how it ended with
+/// `command_outcome`, a failed CloudVision request marks it as an error.
+pub async fn track<F: Future<Output = ()>>(command: &str, handler: F) {
+    let started = Instant::now();
+    let outcome = OUTCOME
+        .scope(Cell::new(Outcome::Ok), async {
+            handler.await;
+            OUTCOME.with(Cell::get)
+        })
+        .await;
+    metrics().command(command, outcome, started.elapsed());
+}
+
+/// Records how the command being handled ended, an error is kept over a later outcome
+pub fn command_outcome(outcome: Outcome) {
+    let _ = OUTCOME.try_with(|current| {
+        if current.get() != Outcome::Error {
+            current.set(outcome);
+        }
+    });
+}
+
+#[derive(Serialize, Debug)]
+struct SiteHealth {
+    name: String,
+    reachable: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct Health {
+    slack_connected: bool,
+    cloudvision: Vec<SiteHealth>,
+}
+
+// CloudVision is reachable when a connection to its API port opens
+async fn reachable(address: &str) -> bool {
+    let connect = TcpStream::connect(address);
+    matches!(
+        tokio::time::timeout(Duration::from_secs(3), connect).await,
+        Ok(Ok(_))
+    )
+}
+
+async fn health(sites: &[(String, String)]) -> Health {
+    let mut cloudvision = Vec::new();
+    for (name, address) in sites {
+        cloudvision.push(SiteHealth {
+            name: name.clone(),
+            reachable: reachable(address).await,
+        });
+    }
+    Health {
+        slack_connected: metrics().is_slack_connected(),
+        cloudvision,
+    }
+}
+
+async fn route(request: Request<Body>, sites: &[(String, String)]) -> Response<Body> {
+    match request.uri().path() {
+        "/metrics" => Response::builder()
+            .header("Content-Type", TextEncoder::new().format_type())
+            .body(Body::from(metrics().render()))
+            .unwrap(),
+        "/healthz" => {
+            let health = health(sites).await;
+            let healthy =
+                health.slack_connected && health.cloudvision.iter().all(|site| site.reachable);
+            let status = if healthy {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            Response::builder()
+                .status(status)
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_string(&health).unwrap()))
+                .unwrap()
+        }
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap(),
+    }
+}
+
+/// Serves /metrics and /healthz. Sites are names and `host:port` addresses of the CloudVision
+/// clusters to check.
+pub async fn serve(listen: SocketAddr, sites: Vec<(String, String)>) {
+    let make_service = make_service_fn(move |_| {
+        let sites = sites.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let sites = sites.clone();
+                async move { Ok::<_, Infallible>(route(request, &sites).await) }
+            }))
+        }
+    });
+    let server = match Server::try_bind(&listen) {
+        Ok(builder) => builder.serve(make_service),
+        Err(err) => {
+            error!("Could not listen for metrics on {}: {}", listen, err);
+            return;
+        }
+    };
+    info!(%listen, "Serving metrics");
+    if let Err(err) = server.await {
+        error!("Metrics server failed: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_endpoint() {
+        assert_eq!(
+            endpoint("/api/resources/changecontrol/v1/ChangeControl?key.id=abc"),
+            "/api/resources/changecontrol/v1/ChangeControl"
+        );
+        assert_eq!(
+            endpoint("/api/v1/rest/JPE1/Sysdb/interface/status"),
+            "/api/v1/rest"
+        );
+    }
+    #[test]
+    fn test_track() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(track("portcheck", async {}));
+        runtime.block_on(track("portdown", async {
+            command_outcome(Outcome::Error);
+            command_outcome(Outcome::Rejected);
+        }));
+        metrics().change_control(ChangeEvent::Created);
+        let text = metrics().render();
+        assert!(text.contains(r#"slack_commands_total{command="portcheck",outcome="ok"} 1"#));
+        assert!(text.contains(r#"slack_commands_total{command="portdown",outcome="error"} 1"#));
+        assert!(text.contains(r#"change_controls_total{event="created"}"#));
+        assert!(text.contains("slack_command_duration_seconds_bucket"));
+    }
+    #[test]
+    fn test_healthz() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let request = Request::get("/healthz").body(Body::empty()).unwrap();
+        // Port 9 is discard, nothing listens there
+        let sites = vec![("emea".to_string(), "127.0.0.1:9".to_string())];
+        let response = runtime.block_on(route(request, &sites));
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = runtime
+            .block_on(hyper::body::to_bytes(response.into_body()))
+            .unwrap();
+        let health: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(health["cloudvision"][0]["name"], "emea");
+        assert_eq!(health["cloudvision"][0]["reachable"], false);
+
+        let request = Request::get("/nope").body(Body::empty()).unwrap();
+        let response = runtime.block_on(route(request, &sites));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
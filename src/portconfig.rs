@@ -0,0 +1,527 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::cvp::{Action, Change, ChangeConfig, RootStage, Stage, StageRow};
+use crate::history::{Inverse, Setting};
+use crate::site::ActionIds;
+use crate::slack::{
+    Block, Context, Input, Modal, OptionObject, PlainTextInput, StaticSelect, TextBlock, View,
+};
+use crate::PortAction;
+
+/// Callback id of the /portconfig modal
+pub const CALLBACK_ID: &str = "portconfig";
+const TITLE: &str = "Configure port";
+// Longest interface description EOS takes
+const DESCRIPTION_MAX: usize = 240;
+const TICKET_MAX: usize = 64;
+
+/// The port a modal is for and its settings when it was opened, kept in the modal's
+/// private_metadata
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct PortState {
+    pub site: String,
+    pub walljack: String,
+    pub device_id: String,
+    pub interface_id: String,
+    // Where the command came from, the result is posted there
+    pub channel_id: String,
+    pub enabled: Option<bool>,
+    // An access VLAN, or "trunk" or "routed"
+    pub vlan: Option<String>,
+    pub description: Option<String>,
+}
+
+/// What the user asked to change, None for settings left as they were
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortConfig {
+    pub port: PortState,
+    pub vlan: Option<u16>,
+    // Empty to remove the description
+    pub description: Option<String>,
+    pub enabled: Option<bool>,
+    pub ticket: Option<String>,
+}
+
+fn admin_option(enabled: bool) -> OptionObject {
+    let (text, value) = match enabled {
+        true => ("Enabled", "up"),
+        false => ("Shut down", "down"),
+    };
+    OptionObject::new(TextBlock::new_plain(text.to_string()), value.to_string())
+}
+
+/// Shown while the port is looked up, views.open has to be called before the trigger expires
+pub fn loading_modal(walljack: &str) -> Modal {
+    message_modal(format!("Looking up wall jack *{}*...", walljack))
+}
+
+/// A modal with only a message, such as why the form could not be filled in
+pub fn message_modal(text: String) -> Modal {
+    let blocks = vec![Block::new_section(TextBlock::new_mrkdwn(text))];
+    Modal::new(TITLE.to_string(), blocks).with_close("Close".to_string())
+}
+
+/// The form filled in with the port's settings. VLAN and description are only offered when the
+/// site has actions for them.
+pub fn modal(port: &PortState, actions: &ActionIds, named: &str) -> Modal {
+    let text = format!("Wall jack *{}* is {}", port.walljack, named);
+    let mut blocks = vec![Block::new_section(TextBlock::new_mrkdwn(text))];
+    if actions.vlan.is_some() {
+        let mut input = PlainTextInput::new("vlan".to_string())
+            .with_placeholder("1-4094".to_string())
+            .with_max_length(4);
+        let mut hint = None;
+        match port.vlan.as_deref() {
+            Some(vlan) if vlan.parse::<u16>().is_ok() => {
+                input = input.with_initial_value(vlan.to_string())
+            }
+            Some(mode @ ("trunk" | "routed")) => {
+                hint = Some(format!(
+                    "The port is {} now, a VLAN makes it an access port",
+                    mode
+                ))
+            }
+            _ => {}
+        }
+        let mut input = Input::new("Access VLAN".to_string(), input)
+            .with_block_id("vlan")
+            .optional();
+        if let Some(hint) = hint {
+            input = input.with_hint(hint);
+        }
+        blocks.push(input.into());
+    }
+    if actions.description.is_some() {
+        let mut input =
+            PlainTextInput::new("description".to_string()).with_max_length(DESCRIPTION_MAX as u32);
+        if let Some(description) = &port.description {
+            input = input.with_initial_value(description.clone());
+        }
+        let input = Input::new("Description".to_string(), input)
+            .with_block_id("description")
+            .optional();
+        blocks.push(input.into());
+    }
+    let mut select = StaticSelect::new(
+        TextBlock::new_plain("Admin state".to_string()),
+        "admin".to_string(),
+        vec![admin_option(true), admin_option(false)],
+    );
+    if let Some(enabled) = port.enabled {
+        select = select.with_initial_option(admin_option(enabled));
+    }
+    blocks.push(
+        Input::new("Admin state".to_string(), select)
+            .with_block_id("admin")
+            .into(),
+    );
+    let ticket = PlainTextInput::new("ticket".to_string())
+        .with_placeholder("CHG0012345".to_string())
+        .with_max_length(TICKET_MAX as u32);
+    blocks.push(
+        Input::new("Ticket".to_string(), ticket)
+            .with_block_id("ticket")
+            .with_hint("Added to the change control name".to_string())
+            .optional()
+            .into(),
+    );
+    if actions.vlan.is_none() || actions.description.is_none() {
+        let text = "Only settings with a CloudVision action in `[actions]` can be changed here";
+        blocks.push(Context::new(vec![TextBlock::new_mrkdwn(text.to_string())]).into());
+    }
+    Modal::new(TITLE.to_string(), blocks)
+        .with_submit("Apply".to_string())
+        .with_close("Cancel".to_string())
+        .with_callback_id(CALLBACK_ID)
+        .with_private_metadata(serde_json::to_string(port).unwrap())
+}
+
+/// Reads a submitted modal, or returns the errors to show under its inputs by block id
+pub fn parse(view: &View) -> Result<PortConfig, BTreeMap<String, String>> {
+    let mut errors = BTreeMap::new();
+    let port: PortState = match serde_json::from_str(&view.private_metadata) {
+        Ok(port) => port,
+        Err(_) => {
+            let error = "This form is out of date, run /portconfig again".to_string();
+            errors.insert("admin".to_string(), error);
+            return Err(errors);
+        }
+    };
+    // None when the input is not in the modal
+    let input = |block_id: &str| {
+        view.state
+            .get(block_id, block_id)
+            .map(|value| value.value().unwrap_or_default().trim())
+    };
+
+    let vlan = match input("vlan") {
+        None | Some("") => None,
+        Some(vlan) => match vlan.parse::<u16>() {
+            Ok(id) if (1..=4094).contains(&id) => {
+                Some(id).filter(|id| port.vlan.as_deref() != Some(id.to_string().as_str()))
+            }
+            _ => {
+                let error = "Enter a VLAN from 1 to 4094".to_string();
+                errors.insert("vlan".to_string(), error);
+                None
+            }
+        },
+    };
+    let description = match input("description") {
+        None => None,
+        Some(description) if description.chars().count() > DESCRIPTION_MAX => {
+            let error = format!("Use at most {} characters", DESCRIPTION_MAX);
+            errors.insert("description".to_string(), error);
+            None
+        }
+        Some(description) if description.chars().any(char::is_control) => {
+            let error = "Use a single line of text".to_string();
+            errors.insert("description".to_string(), error);
+            None
+        }
+        Some(description) => Some(description.to_string())
+            .filter(|new| port.description.as_deref().unwrap_or_default() != new),
+    };
+    let enabled = match input("admin") {
+        Some("up") => Some(true),
+        Some("down") => Some(false),
+        _ => {
+            errors.insert("admin".to_string(), "Choose an admin state".to_string());
+            None
+        }
+    }
+    .filter(|enabled| port.enabled != Some(*enabled));
+    let ticket = match input("ticket") {
+        None | Some("") => None,
+        Some(ticket)
+            if ticket.len() <= TICKET_MAX
+                && ticket
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_#.".contains(c)) =>
+        {
+            Some(ticket.to_string())
+        }
+        Some(_) => {
+            let error = "Use a ticket number such as CHG0012345".to_string();
+            errors.insert("ticket".to_string(), error);
+            None
+        }
+    };
+    if errors.is_empty() && vlan.is_none() && description.is_none() && enabled.is_none() {
+        let error = "Nothing was changed".to_string();
+        errors.insert("admin".to_string(), error);
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(PortConfig {
+        port,
+        vlan,
+        description,
+        enabled,
+        ticket,
+    })
+}
+
+impl PortConfig {
+    /// The config that reverts a change to `port`, which only needs its location filled in
+    pub fn undo(mut port: PortState, inverse: &Inverse) -> PortConfig {
+        let (mut vlan, mut description) = (None, None);
+        for setting in &inverse.settings {
+            match setting {
+                Setting::Vlan { from, to } => {
+                    port.vlan = from.clone();
+                    vlan = Some(*to);
+                }
+                Setting::Description { from, to } => {
+                    port.description = from.clone();
+                    description = Some(to.clone());
+                }
+            }
+        }
+        PortConfig {
+            port,
+            vlan,
+            description,
+            enabled: inverse.action.map(|action| action == PortAction::NoShut),
+            ticket: None,
+        }
+    }
+
+    /// The admin state action of the change, if it has one
+    pub fn action(&self) -> Option<PortAction> {
+        self.enabled.map(|enabled| match enabled {
+            true => PortAction::NoShut,
+            false => PortAction::Shut,
+        })
+    }
+
+    /// The settings the change replaces, with the values they had before. A setting the site
+    /// has no action for is left out since it is not changed.
+    pub fn settings(&self, actions: &ActionIds) -> Vec<Setting> {
+        let mut settings = Vec::new();
+        if let (Some(to), Some(_)) = (self.vlan, &actions.vlan) {
+            let from = self.port.vlan.clone();
+            settings.push(Setting::Vlan { from, to });
+        }
+        if let (Some(to), Some(_)) = (&self.description, &actions.description) {
+            let from = self.port.description.clone();
+            settings.push(Setting::Description {
+                from,
+                to: to.clone(),
+            });
+        }
+        settings
+    }
+
+    /// A change control with a stage for each setting, one after the other. A port is shut
+    /// down before it is reconfigured and enabled after.
+    pub fn change(&self, actions: &ActionIds) -> Change {
+        let utc = Utc::now().format("%y-%m-%d-%H-%M-%S").to_string();
+        let stage = |name: &str, action: &str, arg: Option<(&str, String)>| {
+            let mut args = HashMap::new();
+            args.insert("DeviceID".to_string(), self.port.device_id.clone());
+            args.insert("interface".to_string(), self.port.interface_id.clone());
+            if let Some((key, value)) = arg {
+                args.insert(key.to_string(), value);
+            }
+            let action = Action {
+                name: action.to_string(),
+                args,
+            };
+            StageRow {
+                stage: vec![Stage::new(name.to_string(), action)],
+            }
+        };
+        let mut rows = Vec::new();
+        if self.enabled == Some(false) {
+            let name = PortAction::Shut.stage_name();
+            rows.push(stage(name, &actions.shut, None));
+        }
+        if let (Some(description), Some(action)) = (&self.description, &actions.description) {
+            let arg = ("description", description.clone());
+            rows.push(stage("set_interface_description", action, Some(arg)));
+        }
+        if let (Some(vlan), Some(action)) = (self.vlan, &actions.vlan) {
+            let arg = ("vlan", vlan.to_string());
+            rows.push(stage("set_interface_vlan", action, Some(arg)));
+        }
+        if self.enabled == Some(true) {
+            let name = PortAction::NoShut.stage_name();
+            rows.push(stage(name, &actions.no_shut, None));
+        }
+        let name = match &self.ticket {
+            Some(ticket) => format!("Change {} {}", utc, ticket),
+            None => format!("Change {}", utc),
+        };
+        let root_stage = RootStage::new(format!("{} root", name), rows);
+        Change {
+            config: ChangeConfig::new(name, root_stage),
+        }
+    }
+
+    /// The changes as a list, such as "VLAN 20 to 30, shut down"
+    pub fn summary(&self) -> String {
+        let mut changes = Vec::new();
+        if let Some(vlan) = self.vlan {
+            changes.push(match &self.port.vlan {
+                Some(old) => format!("VLAN {} to {}", old, vlan),
+                None => format!("VLAN {}", vlan),
+            });
+        }
+        match self.description.as_deref() {
+            Some("") => changes.push("description removed".to_string()),
+            Some(description) => changes.push(format!("description \"{}\"", description)),
+            None => {}
+        }
+        match self.enabled {
+            Some(true) => changes.push("enabled".to_string()),
+            Some(false) => changes.push("shut down".to_string()),
+            None => {}
+        }
+        changes.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::ChangeRecord;
+    fn port() -> PortState {
+        PortState {
+            site: "emea".to_string(),
+            walljack: "2-114".to_string(),
+            device_id: "JPE1".to_string(),
+            interface_id: "Ethernet12".to_string(),
+            channel_id: "C1".to_string(),
+            enabled: Some(true),
+            vlan: Some("20".to_string()),
+            description: Some("Room 114".to_string()),
+        }
+    }
+    fn actions() -> ActionIds {
+        ActionIds {
+            vlan: Some("setVlan".to_string()),
+            description: Some("setDescription".to_string()),
+            ..ActionIds::default()
+        }
+    }
+    // A submission of the modal with the given input values
+    fn submitted(port: &PortState, values: serde_json::Value) -> View {
+        let mut state = serde_json::Map::new();
+        for (block_id, value) in values.as_object().unwrap() {
+            let element = match block_id.as_str() {
+                "admin" => serde_json::json!({"type": "static_select",
+                    "selected_option": {"text": {"type": "plain_text", "text": "-"}, "value": value}}),
+                _ => serde_json::json!({"type": "plain_text_input", "value": value}),
+            };
+            state.insert(
+                block_id.clone(),
+                serde_json::json!({ block_id.clone(): element }),
+            );
+        }
+        serde_json::from_value(serde_json::json!({
+            "id": "V1",
+            "callback_id": CALLBACK_ID,
+            "private_metadata": serde_json::to_string(port).unwrap(),
+            "state": {"values": state}
+        }))
+        .unwrap()
+    }
+    #[test]
+    fn test_modal() {
+        let form =
+            serde_json::to_value(modal(&port(), &actions(), "Ethernet12 on sw-2f-1")).unwrap();
+        assert_eq!(form["callback_id"], CALLBACK_ID);
+        let blocks = form["blocks"].as_array().unwrap();
+        assert_eq!(blocks[1]["block_id"], "vlan");
+        assert_eq!(blocks[1]["element"]["initial_value"], "20");
+        assert_eq!(blocks[2]["element"]["initial_value"], "Room 114");
+        assert_eq!(blocks[3]["element"]["initial_option"]["value"], "up");
+        assert_eq!(blocks.len(), 5);
+        let metadata: PortState =
+            serde_json::from_str(form["private_metadata"].as_str().unwrap()).unwrap();
+        assert_eq!(metadata, port());
+
+        // Without the actions only the admin state and ticket are offered
+        let mut trunk = port();
+        trunk.vlan = Some("trunk".to_string());
+        let form = serde_json::to_value(modal(&trunk, &ActionIds::default(), "")).unwrap();
+        let blocks: Vec<&str> = form["blocks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|block| block["type"].as_str().unwrap())
+            .collect();
+        assert_eq!(blocks, vec!["section", "input", "input", "context"]);
+    }
+    #[test]
+    fn test_parse() {
+        let view = submitted(
+            &port(),
+            serde_json::json!({"vlan": "30", "description": "Room 114", "admin": "down", "ticket": "CHG0012345"}),
+        );
+        let config = parse(&view).unwrap();
+        assert_eq!(config.vlan, Some(30));
+        // Left as it was
+        assert_eq!(config.description, None);
+        assert_eq!(config.enabled, Some(false));
+        assert_eq!(config.ticket.as_deref(), Some("CHG0012345"));
+        assert_eq!(config.summary(), "VLAN 20 to 30, shut down");
+
+        let view = submitted(
+            &port(),
+            serde_json::json!({"vlan": "5000", "description": "Room 114", "admin": "up", "ticket": "CHG 1"}),
+        );
+        let errors = parse(&view).unwrap_err();
+        assert_eq!(errors["vlan"], "Enter a VLAN from 1 to 4094");
+        assert!(errors.contains_key("ticket"));
+
+        let view = submitted(
+            &port(),
+            serde_json::json!({"vlan": "20", "description": "", "admin": "up"}),
+        );
+        let config = parse(&view).unwrap();
+        assert_eq!(config.description.as_deref(), Some(""));
+        assert_eq!(config.summary(), "description removed");
+
+        let view = submitted(&port(), serde_json::json!({"vlan": "", "admin": "up"}));
+        assert_eq!(parse(&view).unwrap_err()["admin"], "Nothing was changed");
+    }
+    #[test]
+    fn test_change() {
+        let mut port = port();
+        port.enabled = Some(false);
+        let config = PortConfig {
+            port,
+            vlan: Some(30),
+            description: Some("Room 115".to_string()),
+            enabled: Some(true),
+            ticket: Some("CHG0012345".to_string()),
+        };
+        let change = config.change(&actions());
+        assert!(change.config.name.ends_with(" CHG0012345"));
+        let stages: Vec<&Stage> = change
+            .config
+            .root_stage
+            .stage_row
+            .iter()
+            .flat_map(|row| &row.stage)
+            .collect();
+        let names: Vec<&str> = stages.iter().map(|stage| stage.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "set_interface_description",
+                "set_interface_vlan",
+                "no_shut_interface"
+            ]
+        );
+        assert_eq!(stages[1].action.name, "setVlan");
+        assert_eq!(stages[1].action.args["vlan"], "30");
+        assert_eq!(stages[1].action.args["interface"], "Ethernet12");
+        assert_eq!(stages[2].action.name, PortAction::NoShut.action_id());
+    }
+    #[test]
+    fn test_undo() {
+        let config = PortConfig {
+            port: port(),
+            vlan: Some(30),
+            description: Some(String::new()),
+            enabled: Some(false),
+            ticket: None,
+        };
+        assert_eq!(config.action(), Some(PortAction::Shut));
+        let record = ChangeRecord::single(
+            "emea",
+            "cc1",
+            "U123",
+            config.action(),
+            "2-114",
+            "JPE1",
+            "Ethernet12",
+        )
+        .with_settings(config.settings(&actions()));
+        let location = PortState {
+            vlan: None,
+            description: None,
+            enabled: None,
+            ..port()
+        };
+        let undo = PortConfig::undo(location, &record.inverse().unwrap());
+        assert_eq!(undo.vlan, Some(20));
+        assert_eq!(undo.description.as_deref(), Some("Room 114"));
+        assert_eq!(undo.enabled, Some(true));
+        assert_eq!(
+            undo.summary(),
+            "VLAN 30 to 20, description \"Room 114\", enabled"
+        );
+
+        // Without a VLAN action the VLAN was not changed and is not put back
+        let settings = config.settings(&ActionIds::default());
+        assert!(settings.is_empty());
+    }
+}
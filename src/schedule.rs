@@ -0,0 +1,269 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::slack::MessageRef;
+use crate::PortAction;
+
+/// Give up on a task after this many failed runs
+pub const MAX_ATTEMPTS: u32 = 5;
+/// Stop watching a CloudVision scheduled change after this many status checks, one a minute
+pub const MAX_POLLS: u32 = 360;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TaskKind {
+    /// Re-enable a port that was temporarily shut down
+    ReEnable,
+    /// A change queued until the next maintenance window
+    Queued { action: PortAction },
+    /// A change control CloudVision will start by itself, the bot only reports when it runs
+    CloudVision { action: PortAction, cc_id: String },
+}
+
+impl TaskKind {
+    pub fn action(&self) -> PortAction {
+        match self {
+            TaskKind::ReEnable => PortAction::NoShut,
+            TaskKind::Queued { action } | TaskKind::CloudVision { action, .. } => *action,
+        }
+    }
+    pub fn describe(&self) -> &'static str {
+        match (self, self.action()) {
+            (TaskKind::ReEnable, _) => "re-enable",
+            (_, PortAction::Shut) => "shut down",
+            (_, PortAction::NoShut) => "enable",
+        }
+    }
+}
+
+/// A port action the bot will run at a later time
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScheduledTask {
+    pub id: String,
+    pub kind: TaskKind,
+    pub due: DateTime<Utc>,
+    pub walljack: String,
+    pub device_id: String,
+    pub interface_id: String,
+    pub channel_id: String,
+    pub user_id: String,
+    pub created: DateTime<Utc>,
+    #[serde(default)]
+    pub attempts: u32,
+    // CloudVision site the task runs against
+    #[serde(default)]
+    pub site: String,
+    // Interfaces of a queued bulk change grouped by device, which run as one change control.
+    // Empty for a single port, a bulk change leaves `device_id` empty.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ports: BTreeMap<String, Vec<String>>,
+    // The channel post about the task being postponed or retried, edited on each new attempt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notice: Option<MessageRef>,
+}
+
+impl ScheduledTask {
+    pub fn new(
+        kind: TaskKind,
+        due: DateTime<Utc>,
+        walljack: &str,
+        device_id: &str,
+        interface_id: &str,
+        channel_id: &str,
+        user_id: &str,
+    ) -> Self {
+        // Short ids are easier to type into /portschedule cancel
+        let id = Uuid::new_v4().to_simple().to_string()[..8].to_string();
+        ScheduledTask {
+            id,
+            kind,
+            due,
+            walljack: walljack.to_string(),
+            device_id: device_id.to_string(),
+            interface_id: interface_id.to_string(),
+            channel_id: channel_id.to_string(),
+            user_id: user_id.to_string(),
+            created: Utc::now(),
+            attempts: 0,
+            site: String::new(),
+            ports: BTreeMap::new(),
+            notice: None,
+        }
+    }
+
+    /// The devices the task changes, every device of a bulk change or the one of a single port
+    pub fn device_ids(&self) -> Vec<String> {
+        if self.ports.is_empty() {
+            vec![self.device_id.clone()]
+        } else {
+            self.ports.keys().cloned().collect()
+        }
+    }
+}
+
+/// Pending tasks, persisted to a JSON file so they survive restarts
+#[derive(Debug)]
+pub struct ScheduleStore {
+    path: PathBuf,
+    tasks: Vec<ScheduledTask>,
+}
+
+impl ScheduleStore {
+    /// Loads the store from `path`, a missing file is an empty store
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let tasks = match fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(ScheduleStore {
+            path: path.to_path_buf(),
+            tasks,
+        })
+    }
+
+    // Saves `tasks` and only then makes them the stored tasks, so a failed save leaves the
+    // store as it was
+    fn commit(&mut self, mut tasks: Vec<ScheduledTask>) -> io::Result<()> {
+        tasks.sort_by_key(|task| task.due);
+        // Write then rename so a crash never leaves a half written file
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&tasks)?)?;
+        fs::rename(&tmp, &self.path)?;
+        self.tasks = tasks;
+        Ok(())
+    }
+
+    pub fn tasks(&self) -> &[ScheduledTask] {
+        &self.tasks
+    }
+
+    pub fn add(&mut self, task: ScheduledTask) -> io::Result<()> {
+        let mut tasks = self.tasks.clone();
+        tasks.push(task);
+        self.commit(tasks)
+    }
+
+    pub fn remove(&mut self, id: &str) -> io::Result<Option<ScheduledTask>> {
+        match self.tasks.iter().position(|task| task.id == id) {
+            Some(index) => {
+                let mut tasks = self.tasks.clone();
+                let task = tasks.remove(index);
+                self.commit(tasks)?;
+                Ok(Some(task))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Replaces the stored task with the same id, used to reschedule a failed run
+    pub fn update(&mut self, task: ScheduledTask) -> io::Result<()> {
+        let mut tasks: Vec<ScheduledTask> = self
+            .tasks
+            .iter()
+            .filter(|t| t.id != task.id)
+            .cloned()
+            .collect();
+        tasks.push(task);
+        self.commit(tasks)
+    }
+
+    /// Every task due at `now`. Tasks stay in the store until they are removed after running,
+    /// so a restart part way through runs them again rather than losing them.
+    pub fn due(&self, now: DateTime<Utc>) -> Vec<ScheduledTask> {
+        self.tasks
+            .iter()
+            .filter(|task| task.due <= now)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn task(due: DateTime<Utc>) -> ScheduledTask {
+        ScheduledTask::new(
+            TaskKind::ReEnable,
+            due,
+            "2-114",
+            "JPE1999",
+            "Ethernet1",
+            "C123",
+            "U123",
+        )
+    }
+    #[test]
+    fn test_store_persists() {
+        let path = std::env::temp_dir().join(format!("schedule-{}.json", Uuid::new_v4()));
+        let now = Utc::now();
+        let mut store = ScheduleStore::load(&path).unwrap();
+        assert!(store.tasks().is_empty());
+        let mut later = task(now + chrono::Duration::hours(48));
+        later.device_id = String::new();
+        later.ports = BTreeMap::from([
+            (
+                "JPE1999".to_string(),
+                vec!["Ethernet1".to_string(), "Ethernet2".to_string()],
+            ),
+            ("JPE2000".to_string(), vec!["Ethernet7".to_string()]),
+        ]);
+        assert_eq!(later.device_ids(), vec!["JPE1999", "JPE2000"]);
+        let sooner = task(now + chrono::Duration::hours(1));
+        assert_eq!(sooner.device_ids(), vec!["JPE1999"]);
+        store.add(later.clone()).unwrap();
+        store.add(sooner.clone()).unwrap();
+
+        let mut store = ScheduleStore::load(&path).unwrap();
+        assert_eq!(store.tasks(), &[sooner.clone(), later.clone()]);
+        assert_eq!(store.remove(&sooner.id).unwrap(), Some(sooner));
+        assert_eq!(store.remove("missing").unwrap(), None);
+
+        let store = ScheduleStore::load(&path).unwrap();
+        assert_eq!(store.tasks(), &[later]);
+        fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_failed_save() {
+        let dir = std::env::temp_dir().join(format!("schedule-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("schedule.json");
+        let mut store = ScheduleStore::load(&path).unwrap();
+        let kept = task(Utc::now());
+        store.add(kept.clone()).unwrap();
+        // Nothing can be written once the directory is gone
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(store.add(task(Utc::now())).is_err());
+        assert_eq!(store.tasks(), std::slice::from_ref(&kept));
+        assert!(store.remove(&kept.id).is_err());
+        assert_eq!(store.tasks(), std::slice::from_ref(&kept));
+        let mut retry = kept.clone();
+        retry.attempts = 1;
+        assert!(store.update(retry).is_err());
+        assert_eq!(store.tasks(), &[kept]);
+    }
+    #[test]
+    fn test_due() {
+        let path = std::env::temp_dir().join(format!("schedule-{}.json", Uuid::new_v4()));
+        let now = Utc::now();
+        let mut store = ScheduleStore::load(&path).unwrap();
+        let due = task(now - chrono::Duration::minutes(1));
+        let pending = task(now + chrono::Duration::minutes(1));
+        store.add(due.clone()).unwrap();
+        store.add(pending.clone()).unwrap();
+        assert_eq!(store.due(now), vec![due.clone()]);
+        let mut retry = due.clone();
+        retry.due = now + chrono::Duration::minutes(5);
+        retry.attempts = 1;
+        store.update(retry.clone()).unwrap();
+        assert!(store.due(now).is_empty());
+        assert_eq!(store.tasks(), &[pending, retry]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use zeroize::Zeroize;
+
+/// A token or password. Debug and Display print `[redacted]` and the memory is zeroed on drop.
+#[derive(Clone, Default, PartialEq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Secret(value)
+    }
+
+    /// The secret itself, only for the request that sends it
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[redacted]")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[redacted]")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl FromStr for Secret {
+    type Err = Infallible;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Secret(s.to_string()))
+    }
+}
+
+// Secrets in Slack payloads are plain strings, only the config can name a file or command
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Secret)
+    }
+}
+
+// Fields left out of logged request and response bodies
+const SECRET_KEYS: &[&str] = &[
+    "token",
+    "bot_token",
+    "password",
+    "access_token",
+    "sessionId",
+];
+
+/// A JSON body, or a stream of them, with secret fields replaced for logging. Bodies that are
+/// not JSON are returned as they are.
+pub fn redact(body: &str) -> String {
+    let values: Result<Vec<Value>, _> = serde_json::Deserializer::from_str(body)
+        .into_iter()
+        .collect();
+    match values {
+        Ok(mut values) => {
+            values.iter_mut().for_each(redact_value);
+            let lines: Vec<String> = values.iter().map(Value::to_string).collect();
+            lines.join("\n")
+        }
+        Err(_) => body.to_string(),
+    }
+}
+
+fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                if SECRET_KEYS.contains(&key.as_str()) {
+                    *field = Value::String("[redacted]".to_string());
+                } else {
+                    redact_value(field);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_value),
+        _ => {}
+    }
+}
+
+/// A secret in the config: the value itself, or a file, environment variable or command to
+/// read it from
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SecretConfig {
+    Value(String),
+    File { file: PathBuf },
+    Env { env: String },
+    Command { command: String },
+}
+
+impl SecretConfig {
+    fn resolve(self) -> Result<Secret, String> {
+        let mut value = match self {
+            SecretConfig::Value(value) => return Ok(Secret(value)),
+            SecretConfig::File { file } => fs::read_to_string(&file)
+                .map_err(|err| format!("could not read secret file {}: {}", file.display(), err))?,
+            SecretConfig::Env { env } => std::env::var(&env)
+                .map_err(|err| format!("could not read secret variable {}: {}", env, err))?,
+            SecretConfig::Command { command } => {
+                let output = Command::new("sh")
+                    .arg("-c")
+                    .arg(&command)
+                    .output()
+                    .map_err(|err| format!("could not run `{}`: {}", command, err))?;
+                if !output.status.success() {
+                    return Err(format!("`{}` failed with {}", command, output.status));
+                }
+                String::from_utf8(output.stdout)
+                    .map_err(|_| format!("`{}` did not print text", command))?
+            }
+        };
+        let secret = Secret(value.trim().to_string());
+        value.zeroize();
+        Ok(secret)
+    }
+}
+
+/// Reads a config secret given as a string or as `{ file = ... }`, `{ env = ... }` or
+/// `{ command = ... }`
+pub fn load<'de, D>(deserializer: D) -> Result<Secret, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    SecretConfig::deserialize(deserializer)?
+        .resolve()
+        .map_err(serde::de::Error::custom)
+}
+
+/// Like `load` for an optional secret
+pub fn load_opt<'de, D>(deserializer: D) -> Result<Option<Secret>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    load(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[derive(Deserialize, Debug)]
+    struct Config {
+        #[serde(deserialize_with = "load")]
+        token: Secret,
+        #[serde(default, deserialize_with = "load_opt")]
+        password: Option<Secret>,
+    }
+    #[test]
+    fn test_redacted() {
+        let config: Config = toml::from_str(r#"token = "xoxb-123""#).unwrap();
+        assert_eq!(config.token.expose(), "xoxb-123");
+        assert_eq!(config.password, None);
+        let printed = format!("{:?} {}", config, config.token);
+        assert!(!printed.contains("xoxb"));
+        assert!(printed.contains("[redacted]"));
+    }
+    #[test]
+    fn test_redact() {
+        let body = r#"{"envelope_id":"e1","payload":{"token":"verify123","user_id":"U1"}}
+{"items":[{"password":"hunter2"}]}"#;
+        let redacted = redact(body);
+        assert!(!redacted.contains("verify123"));
+        assert!(!redacted.contains("hunter2"));
+        assert!(redacted.contains("\"user_id\":\"U1\""));
+        assert_eq!(redact("not json"), "not json");
+    }
+    #[test]
+    fn test_load() {
+        let filename = std::env::temp_dir().join("slack-port-config-secret.txt");
+        fs::write(&filename, "from-file\n").unwrap();
+        std::env::set_var("SLACK_PORT_CONFIG_TEST_SECRET", "from-env");
+        let toml_str = format!(
+            "token = {{ file = \"{}\" }}\npassword = {{ env = \"SLACK_PORT_CONFIG_TEST_SECRET\" }}",
+            filename.display()
+        );
+        let config: Config = toml::from_str(&toml_str).unwrap();
+        assert_eq!(config.token.expose(), "from-file");
+        assert_eq!(config.password.unwrap().expose(), "from-env");
+
+        let config: Config =
+            toml::from_str(r#"token = { command = "echo from-command" }"#).unwrap();
+        assert_eq!(config.token.expose(), "from-command");
+        assert!(toml::from_str::<Config>(r#"token = { command = "false" }"#).is_err());
+        assert!(
+            toml::from_str::<Config>(r#"token = { env = "SLACK_PORT_CONFIG_UNSET" }"#).is_err()
+        );
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tracing::warn;
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct ShutdownConfig {
+    // Seconds running commands get to finish after SIGTERM before they are cut off
+    #[serde(default = "default_deadline")]
+    pub deadline_secs: u64,
+    // Where change controls cut off by a shutdown are kept until the next start
+    #[serde(default = "default_recovery_file")]
+    pub recovery_file: PathBuf,
+    // Slack channel offered to resume or cancel them, else the [tokens] admin channel
+    #[serde(default)]
+    pub channel: Option<String>,
+}
+
+fn default_deadline() -> u64 {
+    30
+}
+
+fn default_recovery_file() -> PathBuf {
+    PathBuf::from("recovery.json")
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            deadline_secs: default_deadline(),
+            recovery_file: default_recovery_file(),
+            channel: None,
+        }
+    }
+}
+
+/// A change control the bot created but had not started when it stopped
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StrayChange {
+    pub site: String,
+    pub cc_id: String,
+    pub approved: bool,
+    pub created: DateTime<Utc>,
+}
+
+/// Command handlers that are running and the change controls they are part way through
+#[derive(Debug, Default)]
+pub struct Drain {
+    stopping: AtomicBool,
+    running: Mutex<usize>,
+    idle: Notify,
+    changes: Mutex<HashMap<String, StrayChange>>,
+}
+
+impl Drain {
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Marks a handler as running until the guard is dropped, None once the bot is stopping
+    pub fn begin(&self) -> Option<Running<'_>> {
+        let mut running = self.running.lock().unwrap();
+        if self.is_stopping() {
+            return None;
+        }
+        *running += 1;
+        Some(Running(self))
+    }
+
+    pub fn created(&self, site: &str, cc_id: &str) {
+        let change = StrayChange {
+            site: site.to_string(),
+            cc_id: cc_id.to_string(),
+            approved: false,
+            created: Utc::now(),
+        };
+        self.changes
+            .lock()
+            .unwrap()
+            .insert(cc_id.to_string(), change);
+    }
+
+    pub fn approved(&self, cc_id: &str) {
+        if let Some(change) = self.changes.lock().unwrap().get_mut(cc_id) {
+            change.approved = true;
+        }
+    }
+
+    /// The change control was started or scheduled, or failed and was reported to the user
+    pub fn finished(&self, cc_id: &str) {
+        self.changes.lock().unwrap().remove(cc_id);
+    }
+
+    /// Stops new handlers and waits up to `deadline` for the running ones. Returns the change
+    /// controls that were left created but not started.
+    pub async fn stop(&self, deadline: Duration) -> Vec<StrayChange> {
+        {
+            let _running = self.running.lock().unwrap();
+            self.stopping.store(true, Ordering::SeqCst);
+        }
+        let drained = tokio::time::timeout(deadline, async {
+            loop {
+                let idle = self.idle.notified();
+                if *self.running.lock().unwrap() == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        })
+        .await;
+        if drained.is_err() {
+            warn!("Commands were still running after {:?}", deadline);
+        }
+        let mut changes: Vec<StrayChange> =
+            self.changes.lock().unwrap().values().cloned().collect();
+        changes.sort_by_key(|change| change.created);
+        changes
+    }
+}
+
+/// A running command handler
+#[derive(Debug)]
+pub struct Running<'a>(&'a Drain);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        let mut running = self.0.running.lock().unwrap();
+        *running -= 1;
+        if *running == 0 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// Change controls cut off by a shutdown, persisted to a JSON file until they are resumed or
+/// cancelled
+#[derive(Debug)]
+pub struct RecoveryStore {
+    path: PathBuf,
+    changes: Vec<StrayChange>,
+}
+
+impl RecoveryStore {
+    /// Loads the store from `path`, a missing file is an empty store
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let changes = match fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(RecoveryStore {
+            path: path.to_path_buf(),
+            changes,
+        })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        // Write then rename so a crash never leaves a half written file
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&self.changes)?)?;
+        fs::rename(&tmp, &self.path)
+    }
+
+    pub fn changes(&self) -> &[StrayChange] {
+        &self.changes
+    }
+
+    pub fn get(&self, cc_id: &str) -> Option<&StrayChange> {
+        self.changes.iter().find(|change| change.cc_id == cc_id)
+    }
+
+    pub fn add(&mut self, changes: Vec<StrayChange>) -> io::Result<()> {
+        self.changes.extend(changes);
+        self.save()
+    }
+
+    pub fn remove(&mut self, cc_id: &str) -> io::Result<Option<StrayChange>> {
+        match self.changes.iter().position(|change| change.cc_id == cc_id) {
+            Some(index) => {
+                let change = self.changes.remove(index);
+                self.save()?;
+                Ok(Some(change))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+    #[test]
+    fn test_drain() {
+        let drain = Drain::default();
+        let running = drain.begin().unwrap();
+        drain.created("emea", "cc-started");
+        drain.created("emea", "cc-cut-off");
+        drain.approved("cc-cut-off");
+        drain.finished("cc-started");
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let stray = runtime.block_on(async {
+            let stop = drain.stop(Duration::from_secs(5));
+            let finish = async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                drop(running);
+            };
+            tokio::join!(stop, finish).0
+        });
+        assert!(drain.begin().is_none());
+        assert_eq!(stray.len(), 1);
+        assert_eq!(stray[0].cc_id, "cc-cut-off");
+        assert!(stray[0].approved);
+    }
+    #[test]
+    fn test_drain_deadline() {
+        let drain = Drain::default();
+        let _running = drain.begin().unwrap();
+        drain.created("emea", "cc-1");
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let stray = runtime.block_on(drain.stop(Duration::from_millis(10)));
+        assert_eq!(stray[0].cc_id, "cc-1");
+        assert!(!stray[0].approved);
+    }
+    #[test]
+    fn test_recovery_store() {
+        let path = std::env::temp_dir().join(format!("recovery-{}.json", Uuid::new_v4()));
+        let mut store = RecoveryStore::load(&path).unwrap();
+        assert!(store.changes().is_empty());
+        let change = StrayChange {
+            site: "emea".to_string(),
+            cc_id: "cc-1".to_string(),
+            approved: false,
+            created: Utc::now(),
+        };
+        store.add(vec![change.clone()]).unwrap();
+
+        let mut store = RecoveryStore::load(&path).unwrap();
+        assert_eq!(store.get("cc-1"), Some(&change));
+        assert_eq!(store.remove("cc-1").unwrap(), Some(change));
+        assert!(RecoveryStore::load(&path).unwrap().changes().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,407 @@
+use std::path::PathBuf;
+
+use chrono::Duration;
+use serde::{Deserialize, Deserializer};
+use tracing::error;
+
+use crate::auth::TokenSource;
+use crate::bulk::{self, Lookup};
+use crate::cache::JackCache;
+use crate::cvp::{self, CloudVisionError, TagQuery};
+use crate::secret::{self, Secret};
+use crate::tags::Assignment;
+use crate::PortAction;
+
+/// A `[[cloudvision]]` cluster. A single `[cloudvision]` table is read as one unnamed cluster.
+#[derive(PartialEq, Debug, Deserialize, Default)]
+pub struct CloudVisionConfig {
+    // Name used with `--site`, the hostname when not given
+    #[serde(default)]
+    pub name: String,
+    pub hostname: String,
+    pub port: u32,
+    #[serde(default, deserialize_with = "secret::load")]
+    pub token: Secret,
+    // Service account token file, read again when it changes, instead of `token`
+    #[serde(default)]
+    pub token_file: Option<PathBuf>,
+    // Log in for a session token instead of using `token`
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default, deserialize_with = "secret::load_opt")]
+    pub password: Option<Secret>,
+    // Slack channel ids whose commands go to this cluster
+    #[serde(default)]
+    pub channels: Vec<String>,
+    // Jacks starting with one of these go to this cluster, such as "LON-"
+    #[serde(default)]
+    pub jack_prefixes: Vec<String>,
+    // Lookup labels for this cluster instead of `[lookup] labels`
+    #[serde(default)]
+    pub labels: Option<Vec<String>>,
+    #[serde(default)]
+    pub actions: ActionIds,
+}
+
+/// Reads `cloudvision` as either one table or an array of tables
+pub fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<CloudVisionConfig>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Box<CloudVisionConfig>),
+        Many(Vec<CloudVisionConfig>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(config) => vec![*config],
+        OneOrMany::Many(configs) => configs,
+    })
+}
+
+/// Ids of the CloudVision actions that change a port, these differ between clusters
+#[derive(PartialEq, Debug, Deserialize, Clone)]
+pub struct ActionIds {
+    #[serde(default = "default_shut")]
+    pub shut: String,
+    #[serde(default = "default_no_shut")]
+    pub no_shut: String,
+    // Actions taking a `vlan` or a `description` argument, /portconfig only offers the
+    // settings that have one
+    #[serde(default)]
+    pub vlan: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+fn default_shut() -> String {
+    PortAction::Shut.action_id().to_string()
+}
+
+fn default_no_shut() -> String {
+    PortAction::NoShut.action_id().to_string()
+}
+
+impl Default for ActionIds {
+    fn default() -> Self {
+        ActionIds {
+            shut: default_shut(),
+            no_shut: default_no_shut(),
+            vlan: None,
+            description: None,
+        }
+    }
+}
+
+impl ActionIds {
+    pub fn get(&self, action: PortAction) -> &str {
+        match action {
+            PortAction::Shut => &self.shut,
+            PortAction::NoShut => &self.no_shut,
+        }
+    }
+}
+
+/// A CloudVision cluster with its own jack cache
+pub struct Site {
+    pub name: String,
+    pub cv: cvp::Host,
+    // Interface tag labels ports are looked up by, the first is the default
+    pub labels: Vec<String>,
+    pub actions: ActionIds,
+    pub cache: JackCache,
+    pub token: TokenSource,
+    channels: Vec<String>,
+    jack_prefixes: Vec<String>,
+}
+
+impl Site {
+    pub fn new(config: CloudVisionConfig, default_labels: &[String]) -> Self {
+        let port = config
+            .port
+            .try_into()
+            .expect("CloudVision port out of range");
+        let cv = cvp::Host::new(&config.hostname, port);
+        let token = match (config.username, config.password, config.token_file) {
+            (Some(username), Some(password), _) => TokenSource::Login { username, password },
+            (_, _, Some(path)) => TokenSource::File(path),
+            _ => {
+                // Without a token requests fail with NoToken rather than a 401
+                if !config.token.is_empty() {
+                    cv.set_token(config.token.clone());
+                }
+                TokenSource::Fixed(config.token)
+            }
+        };
+        let name = if config.name.is_empty() {
+            config.hostname
+        } else {
+            config.name
+        };
+        Site {
+            name,
+            cv,
+            labels: config.labels.unwrap_or_else(|| default_labels.to_vec()),
+            actions: config.actions,
+            cache: JackCache::default(),
+            token,
+            channels: config.channels,
+            jack_prefixes: config.jack_prefixes,
+        }
+    }
+
+    /// Finds the interface behind a wall jack, from the cache when it has it. Also returns the
+    /// age of cached information so replies can show it. A jack without a `label=` tries each
+    /// lookup label in order.
+    pub async fn find_jack(&self, walljack: &str) -> (Lookup, Option<Duration>) {
+        let (label, value) = bulk::split_label(walljack);
+        let labels: Vec<&str> = match label {
+            Some(label) => vec![label],
+            None => self.labels.iter().map(String::as_str).collect(),
+        };
+        for label in &labels {
+            if let Some((assignment, age)) = self.cache.get(label, value) {
+                let lookup = Lookup::Found {
+                    device_id: assignment.device_id,
+                    interface_id: assignment.interface_id,
+                };
+                return (lookup, Some(age));
+            }
+        }
+        for label in &labels {
+            let lookup = lookup_jack(&self.cv, label, value).await;
+            match &lookup {
+                Lookup::Found {
+                    device_id,
+                    interface_id,
+                } => {
+                    self.cache
+                        .insert(label, Assignment::new(value, device_id, interface_id));
+                    return (lookup, None);
+                }
+                Lookup::NotFound => {}
+                Lookup::Failed(_) => return (lookup, None),
+            }
+        }
+        (Lookup::NotFound, None)
+    }
+
+    /// Hostname of a device from the cache, or its serial number if it is not cached
+    pub fn switch_name(&self, device_id: &str) -> String {
+        match self.cache.hostname(device_id) {
+            Some(hostname) if !hostname.is_empty() => hostname,
+            _ => device_id.to_string(),
+        }
+    }
+
+    /// Hostname of a device, asking CloudVision when it is not cached
+    pub async fn hostname(&self, device_id: &str) -> String {
+        if let Some(hostname) = self.cache.hostname(device_id).filter(|h| !h.is_empty()) {
+            return hostname;
+        }
+        match self.cv.get_device(device_id).await {
+            Ok(device) if !device.hostname.is_empty() => device.hostname,
+            Ok(_) => device_id.to_string(),
+            Err(err) => {
+                error!("Error getting device {}: {}", device_id, err);
+                device_id.to_string()
+            }
+        }
+    }
+}
+
+async fn get_tag_assignment(
+    cv: &cvp::Host,
+    label: &str,
+    value: &str,
+) -> Result<Vec<cvp::InterfaceResponse>, CloudVisionError> {
+    let query = TagQuery::interface().label(label).value(value);
+    let device_json = cv.get_tag_assignment_config(&query).await?;
+    let results: Vec<cvp::TagAssignmentConfigResponse> = cvp::parse_stream(&device_json)?;
+    Ok(results.into_iter().map(|r| r.result).collect())
+}
+
+async fn lookup_jack(cv: &cvp::Host, label: &str, walljack: &str) -> Lookup {
+    match get_tag_assignment(cv, label, walljack).await {
+        Ok(devices) => match devices.first() {
+            Some(first_device) => Lookup::Found {
+                device_id: first_device.value.key.device_id.clone(),
+                interface_id: first_device.value.key.interface_id.clone(),
+            },
+            None => Lookup::NotFound,
+        },
+        Err(err) => Lookup::Failed(err.to_string()),
+    }
+}
+
+/// The configured clusters and how commands are routed to them
+pub struct Sites {
+    sites: Vec<Site>,
+}
+
+impl Sites {
+    pub fn new(sites: Vec<Site>) -> Self {
+        Sites { sites }
+    }
+
+    pub fn all(&self) -> &[Site] {
+        &self.sites
+    }
+
+    /// A site by name. Tasks saved before there were several sites have no name and belong to
+    /// the first one.
+    pub fn get(&self, name: &str) -> Option<&Site> {
+        if name.is_empty() {
+            return self.sites.first();
+        }
+        self.sites.iter().find(|site| site.name == name)
+    }
+
+    pub fn names(&self) -> String {
+        let names: Vec<&str> = self.sites.iter().map(|site| site.name.as_str()).collect();
+        names.join(", ")
+    }
+
+    /// The site a command goes to: the one named with `--site`, else the one whose prefix the
+    /// jack starts with, else the one the channel belongs to. With a single site that is always
+    /// the one. None when the site cannot be told.
+    pub fn route(
+        &self,
+        site: Option<&str>,
+        channel_id: &str,
+        jack: &str,
+    ) -> Result<Option<&Site>, String> {
+        if let Some(name) = site {
+            return match self.sites.iter().find(|site| site.name == name) {
+                Some(site) => Ok(Some(site)),
+                None => Err(format!(
+                    "`{}` is not a site, use one of: {}",
+                    name,
+                    self.names()
+                )),
+            };
+        }
+        if self.sites.len() == 1 {
+            return Ok(self.sites.first());
+        }
+        let (_, value) = bulk::split_label(jack);
+        let by_prefix = self.sites.iter().find(|site| {
+            site.jack_prefixes
+                .iter()
+                .any(|prefix| !value.is_empty() && value.starts_with(prefix.as_str()))
+        });
+        if by_prefix.is_some() {
+            return Ok(by_prefix);
+        }
+        Ok(self
+            .sites
+            .iter()
+            .find(|site| site.channels.iter().any(|c| c == channel_id)))
+    }
+
+    /// The site of every jack in a list, which must all go to the same one
+    pub fn route_all(
+        &self,
+        site: Option<&str>,
+        channel_id: &str,
+        jacks: &[String],
+    ) -> Result<Option<&Site>, String> {
+        let mut routed: Option<&Site> = None;
+        for jack in jacks {
+            if let Some(site) = self.route(site, channel_id, jack)? {
+                match routed {
+                    Some(other) if other.name != site.name => {
+                        return Err(format!(
+                            "The wall jacks are at more than one site ({} and {}), run a command per site",
+                            other.name, site.name
+                        ))
+                    }
+                    _ => routed = Some(site),
+                }
+            }
+        }
+        Ok(routed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn sites() -> Sites {
+        let toml_str = r#"
+        [[cloudvision]]
+        name = "emea"
+        hostname = "cv-emea.example.com"
+        port = 443
+        token = "token1"
+        channels = ["C_EMEA"]
+        jack_prefixes = ["LON-"]
+        [[cloudvision]]
+        hostname = "cv-us.example.com"
+        port = 443
+        token = "token2"
+        channels = ["C_US"]
+        labels = ["desk"]
+        [cloudvision.actions]
+        shut = "usShut"
+        vlan = "usVlan"
+        "#;
+        #[derive(Deserialize)]
+        struct Config {
+            #[serde(deserialize_with = "one_or_many")]
+            cloudvision: Vec<CloudVisionConfig>,
+        }
+        let config: Config = toml::from_str(toml_str).unwrap();
+        let labels = vec!["wall_jack".to_string()];
+        Sites::new(
+            config
+                .cloudvision
+                .into_iter()
+                .map(|c| Site::new(c, &labels))
+                .collect(),
+        )
+    }
+    #[test]
+    fn test_site_config() {
+        let sites = sites();
+        let us = sites.get("cv-us.example.com").unwrap();
+        assert_eq!(us.labels, vec!["desk"]);
+        assert_eq!(us.actions.get(PortAction::Shut), "usShut");
+        assert_eq!(
+            us.actions.get(PortAction::NoShut),
+            PortAction::NoShut.action_id()
+        );
+        assert_eq!(us.actions.vlan.as_deref(), Some("usVlan"));
+        assert_eq!(us.actions.description, None);
+        assert_eq!(sites.get("emea").unwrap().labels, vec!["wall_jack"]);
+        // Unnamed tasks belong to the first site
+        assert_eq!(sites.get("").unwrap().name, "emea");
+    }
+    #[test]
+    fn test_route() {
+        let sites = sites();
+        let name = |routed: Result<Option<&Site>, String>| routed.unwrap().map(|s| s.name.clone());
+        assert_eq!(
+            name(sites.route(Some("emea"), "C_US", "2-114")).as_deref(),
+            Some("emea")
+        );
+        assert!(sites.route(Some("apac"), "C_US", "2-114").is_err());
+        assert_eq!(
+            name(sites.route(None, "C_US", "LON-2-114")).as_deref(),
+            Some("emea")
+        );
+        assert_eq!(
+            name(sites.route(None, "C_US", "desk=4F-22")).as_deref(),
+            Some("cv-us.example.com")
+        );
+        assert_eq!(name(sites.route(None, "C_OTHER", "2-114")), None);
+
+        let jacks = vec!["LON-1".to_string(), "2-114".to_string()];
+        assert!(sites.route_all(None, "C_US", &jacks).is_err());
+        assert_eq!(
+            name(sites.route_all(None, "C_OTHER", &jacks)).as_deref(),
+            Some("emea")
+        );
+    }
+}
@@ -1,19 +1,53 @@
+use chrono::prelude::*;
 use reqwest::header::*;
+use serde::de::{DeserializeOwned, IgnoredAny};
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::io::{Error, ErrorKind};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::future::Future;
+use std::io::Error;
+use std::pin::Pin;
 
-use log::info;
 use std::net::TcpStream;
+use tracing::{debug, info, warn};
 use tungstenite::stream::MaybeTlsStream;
 use tungstenite::{connect, Message};
 use url::Url;
 
+use crate::secret::{self, Secret};
+
 #[derive(Debug)]
 pub enum SlackErr {
     Http(reqwest::Error),
     Parse(url::ParseError),
     Connection(std::io::Error),
+    Socket(tungstenite::Error),
+    Api(String),
+    Json(serde_json::Error),
+    // Still rate limited after retrying, with how long Slack asked to wait
+    RateLimited(std::time::Duration),
+}
+
+impl fmt::Display for SlackErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SlackErr::Http(err) => write!(f, "Slack HTTP error: {}", err),
+            SlackErr::Parse(err) => write!(f, "Slack URL parse error: {}", err),
+            SlackErr::Connection(err) => write!(f, "Slack connection error: {}", err),
+            SlackErr::Socket(err) => write!(f, "Slack socket error: {}", err),
+            SlackErr::Api(err) => write!(f, "Slack API error: {}", err),
+            SlackErr::Json(err) => write!(f, "Could not parse the Slack response: {}", err),
+            SlackErr::RateLimited(wait) => {
+                write!(f, "Slack rate limited the request, retry after {:?}", wait)
+            }
+        }
+    }
+}
+
+impl From<serde_json::Error> for SlackErr {
+    fn from(err: serde_json::Error) -> Self {
+        SlackErr::Json(err)
+    }
 }
 
 impl From<url::ParseError> for SlackErr {
@@ -21,6 +55,11 @@ impl From<url::ParseError> for SlackErr {
         SlackErr::Parse(err)
     }
 }
+impl From<tungstenite::Error> for SlackErr {
+    fn from(err: tungstenite::Error) -> Self {
+        SlackErr::Socket(err)
+    }
+}
 impl From<reqwest::Error> for SlackErr {
     fn from(err: reqwest::Error) -> Self {
         SlackErr::Http(err)
@@ -39,6 +78,7 @@ struct AppsConnectionsOpenResponse {
     error: Option<String>,
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum SocketEvent {
@@ -60,6 +100,12 @@ pub enum SocketEvent {
         envelope_id: String,
         accepts_response_payload: bool,
     },
+    // Slack is about to close the connection and wants a new one opened
+    #[serde(rename = "disconnect")]
+    Disconnect {
+        #[serde(default)]
+        reason: String,
+    },
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -70,31 +116,275 @@ pub struct MessagePayload {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub thread_ts: Option<String>,
     pub mrkdwn: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replace_original: Option<bool>,
+}
+
+impl MessagePayload {
+    pub fn new(text: String) -> Self {
+        MessagePayload {
+            text,
+            blocks: None,
+            thread_ts: None,
+            mrkdwn: true,
+            replace_original: None,
+        }
+    }
+    pub fn with_blocks(text: String, blocks: Vec<Block>) -> Self {
+        MessagePayload {
+            blocks: Some(blocks),
+            ..MessagePayload::new(text)
+        }
+    }
 }
 
+/// A payload Slack sends when someone uses a button, select, modal or shortcut of the app
 #[derive(Deserialize, Debug)]
-pub struct Interactive {
-    #[serde(rename = "type")]
-    interactive_type: String,
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Interactive {
+    BlockActions(BlockActions),
+    ViewSubmission(ViewSubmission),
+    ViewClosed(ViewClosed),
+    Shortcut(Shortcut),
+    MessageAction(MessageAction),
+}
+
+impl Interactive {
+    pub fn user(&self) -> &IdObject {
+        match self {
+            Interactive::BlockActions(payload) => &payload.user,
+            Interactive::ViewSubmission(payload) => &payload.user,
+            Interactive::ViewClosed(payload) => &payload.user,
+            Interactive::Shortcut(payload) => &payload.user,
+            Interactive::MessageAction(payload) => &payload.user,
+        }
+    }
+
+    /// Empty for payloads that cannot open a modal
+    pub fn trigger_id(&self) -> &str {
+        match self {
+            Interactive::BlockActions(payload) => &payload.trigger_id,
+            Interactive::ViewSubmission(payload) => &payload.trigger_id,
+            Interactive::ViewClosed(_) => "",
+            Interactive::Shortcut(payload) => &payload.trigger_id,
+            Interactive::MessageAction(payload) => &payload.trigger_id,
+        }
+    }
+
+    /// The action ids of block actions, else the callback id of the view or shortcut
+    pub fn names(&self) -> Vec<&str> {
+        match self {
+            Interactive::BlockActions(payload) => payload
+                .actions
+                .iter()
+                .map(|action| action.action_id.as_str())
+                .collect(),
+            Interactive::ViewSubmission(payload) => vec![payload.view.callback_id.as_str()],
+            Interactive::ViewClosed(payload) => vec![payload.view.callback_id.as_str()],
+            Interactive::Shortcut(payload) => vec![payload.callback_id.as_str()],
+            Interactive::MessageAction(payload) => vec![payload.callback_id.as_str()],
+        }
+    }
+}
+
+/// Buttons, selects and other elements used in a message or a modal
+#[derive(Deserialize, Debug)]
+pub struct BlockActions {
+    pub user: IdObject,
+    // None for actions in a modal
+    pub channel: Option<IdObject>,
+    pub message: Option<InteractiveMessage>,
+    pub response_url: Option<String>,
+    #[serde(default)]
+    pub trigger_id: String,
+    #[serde(default)]
     pub actions: Vec<InteractiveAction>,
+    // Values of the input blocks around the actions
+    pub state: Option<ViewState>,
+    // The modal the actions are in
+    pub view: Option<View>,
+}
+
+/// A modal the user submitted
+#[derive(Deserialize, Debug)]
+pub struct ViewSubmission {
+    pub user: IdObject,
+    pub view: View,
+    #[serde(default)]
+    pub trigger_id: String,
+}
+
+/// A modal the user closed, only sent when the view asked for it with `notify_on_close`
+#[derive(Deserialize, Debug)]
+pub struct ViewClosed {
+    pub user: IdObject,
+    pub view: View,
+    #[serde(default)]
+    pub is_cleared: bool,
+}
+
+/// A global shortcut from the shortcuts menu
+#[derive(Deserialize, Debug)]
+pub struct Shortcut {
+    pub user: IdObject,
+    pub callback_id: String,
+    pub trigger_id: String,
+}
+
+/// A message shortcut used on a message
+#[derive(Deserialize, Debug)]
+pub struct MessageAction {
+    pub user: IdObject,
+    pub callback_id: String,
+    pub trigger_id: String,
+    pub channel: IdObject,
+    pub message: InteractiveMessage,
     pub response_url: String,
 }
 
+/// The message an action was used in
 #[derive(Deserialize, Debug)]
-pub struct InteractiveAction {
+pub struct InteractiveMessage {
+    pub ts: String,
+    #[serde(default)]
+    pub text: String,
+    pub user: Option<String>,
+    pub thread_ts: Option<String>,
+}
+
+/// A modal as Slack sends it back, with what the user entered
+#[derive(Deserialize, Debug)]
+pub struct View {
+    pub id: String,
+    #[serde(default)]
+    pub callback_id: String,
+    #[serde(default)]
+    pub private_metadata: String,
+    #[serde(default)]
+    pub state: ViewState,
+    pub hash: Option<String>,
+}
+
+/// Values of the inputs by block id and then action id
+#[derive(Deserialize, Debug, Default)]
+pub struct ViewState {
+    pub values: HashMap<String, HashMap<String, ElementValue>>,
+}
+
+impl ViewState {
+    pub fn get(&self, block_id: &str, action_id: &str) -> Option<&ElementValue> {
+        self.values.get(block_id)?.get(action_id)
+    }
+}
+
+/// The value of an element, which fields are set depends on the element type
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ElementValue {
     #[serde(rename = "type")]
-    action_type: String,
-    action_id: String,
-    block_id: String,
-    action_ts: String,
-    pub selected_option: SelectedOption,
+    pub element_type: String,
+    pub value: Option<String>,
+    pub selected_option: Option<SelectedOption>,
+    #[serde(default)]
+    pub selected_options: Vec<SelectedOption>,
+    pub selected_date: Option<String>,
+}
+
+impl ElementValue {
+    /// A button or text input value, or the value of the selected option
+    pub fn value(&self) -> Option<&str> {
+        self.value.as_deref().or(self
+            .selected_option
+            .as_ref()
+            .map(|option| option.value.as_str()))
+    }
+}
+
+/// The user or channel an interaction came from
+#[derive(Deserialize, Debug)]
+pub struct IdObject {
+    pub id: String,
 }
 
 #[derive(Deserialize, Debug)]
+pub struct InteractiveAction {
+    pub action_id: String,
+    pub block_id: String,
+    pub action_ts: String,
+    #[serde(flatten)]
+    pub input: ElementValue,
+}
+
+impl InteractiveAction {
+    pub fn value(&self) -> &str {
+        self.input.value().unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct SelectedOption {
     pub text: TextBlock,
     pub value: String,
 }
+
+/// A reply to an action, None when the handler has nothing to say
+pub type ActionFuture<'a> = Pin<Box<dyn Future<Output = Option<MessagePayload>> + 'a>>;
+
+type ActionHandler<C> = Box<
+    dyn for<'a> Fn(&'a C, &'a BlockActions, &'a InteractiveAction) -> ActionFuture<'a>
+        + Send
+        + Sync,
+>;
+
+/// Block action handlers by action id
+pub struct Router<C> {
+    handlers: HashMap<String, ActionHandler<C>>,
+}
+
+impl<C> Default for Router<C> {
+    fn default() -> Self {
+        Router {
+            handlers: HashMap::new(),
+        }
+    }
+}
+
+impl<C> Router<C> {
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    pub fn on<F>(mut self, action_id: &str, handler: F) -> Self
+    where
+        F: for<'a> Fn(&'a C, &'a BlockActions, &'a InteractiveAction) -> ActionFuture<'a>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.handlers
+            .insert(action_id.to_string(), Box::new(handler));
+        self
+    }
+
+    /// Runs the handler of each action in turn, returning the replies. An action nobody
+    /// registered is an error.
+    pub async fn dispatch(
+        &self,
+        context: &C,
+        payload: &BlockActions,
+    ) -> Vec<Result<Option<MessagePayload>, String>> {
+        let mut replies = Vec::new();
+        for action in &payload.actions {
+            let reply = match self.handlers.get(&action.action_id) {
+                Some(handler) => Ok(handler(context, payload, action).await),
+                None => Err(action.action_id.clone()),
+            };
+            replies.push(reply);
+        }
+        replies
+    }
+}
+
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 pub struct AppMention {
     #[serde(rename = "type")]
@@ -105,24 +395,28 @@ pub struct AppMention {
     channel: String,
     event_ts: String,
 }
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 pub struct EventCallback {
-    token: String,
+    token: Secret,
     team_id: String,
     event: AppMention,
     event_id: String,
 }
 // TODO: make the client specific to WSS either by name of struct or by module
 pub struct Client {
-    token: String,
+    token: Secret,
     socket: Option<tungstenite::WebSocket<MaybeTlsStream<TcpStream>>>,
+    // The first reply to the envelope being handled, kept for retries of it
+    response: Option<(String, serde_json::Value)>,
 }
 
 impl Client {
-    pub fn new(token: String) -> Self {
+    pub fn new(token: Secret) -> Self {
         Client {
             token,
             socket: None,
+            response: None,
         }
     }
     async fn get_wss_url(&mut self) -> Result<Url, SlackErr> {
@@ -130,7 +424,7 @@ impl Client {
         let client = reqwest::Client::new();
         let connection_response = client
             .post(base_url + "apps.connections.open")
-            .bearer_auth(&self.token)
+            .bearer_auth(self.token.expose())
             .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
             .send()
             .await?
@@ -139,28 +433,22 @@ impl Client {
 
         // check if connection was successful
         if !connection_response.ok {
-            return Err(SlackErr::Connection(Error::new(
-                ErrorKind::Other,
+            return Err(SlackErr::Connection(Error::other(
                 connection_response.error.unwrap(),
             )));
         }
-        let url = Url::parse(&connection_response.url).expect("Could not parse url");
+        let url = Url::parse(&connection_response.url)?;
         Ok(url)
     }
 
-    pub async fn connect(&mut self) -> Result<(), Error> {
-        let url = self.get_wss_url().await.expect("Could not get wss url");
-        let (mut socket, _response) = connect(url).expect("Can't connect");
-        let msg = socket.read_message().expect("Error reading message");
-        info!("recevied hello: {:?}", msg);
+    pub async fn connect(&mut self) -> Result<(), SlackErr> {
+        let url = self.get_wss_url().await?;
+        let (mut socket, _response) = connect(url)?;
+        let msg = socket.read_message()?;
+        info!(?msg, "Connected to Slack");
         self.socket = Some(socket);
         Ok(())
     }
-    pub fn get_token_from_file(filename: &str) -> Result<String, std::io::Error> {
-        let t = fs::read_to_string(filename)?;
-        let token = t.trim().to_string();
-        Ok(token)
-    }
     pub async fn receive_message(&mut self) -> Result<Message, Error> {
         Ok(self
             .socket
@@ -170,7 +458,7 @@ impl Client {
             .expect("Error reading message"))
     }
     pub fn send_message(&mut self, msg: &str) {
-        println!("send message {}", msg);
+        debug!(body = %secret::redact(msg), "Slack socket message");
         self.socket
             .as_mut()
             .unwrap()
@@ -178,6 +466,14 @@ impl Client {
             .unwrap();
     }
     pub fn send_response(&mut self, envelope_id: &str, payload: BlockPayload) {
+        let payload = serde_json::to_value(payload).unwrap();
+        if !matches!(&self.response, Some((id, _)) if id == envelope_id) {
+            self.response = Some((envelope_id.to_string(), payload.clone()));
+        }
+        self.resend(envelope_id, payload);
+    }
+    /// Sends a reply taken from an earlier envelope
+    pub fn resend(&mut self, envelope_id: &str, payload: serde_json::Value) {
         let response = Response {
             envelope_id: envelope_id.to_string(),
             payload,
@@ -185,23 +481,404 @@ impl Client {
         let response_json = serde_json::to_string(&response).unwrap();
         self.send_message(&response_json);
     }
+    /// The first reply sent to an envelope
+    pub fn take_response(&mut self, envelope_id: &str) -> Option<serde_json::Value> {
+        match self.response.take() {
+            Some((id, payload)) if id == envelope_id => Some(payload),
+            _ => None,
+        }
+    }
+    /// Answers a view_submission with errors to show under the inputs, by block id, which keeps
+    /// the modal open
+    pub fn send_view_errors(&mut self, envelope_id: &str, errors: BTreeMap<String, String>) {
+        let payload = serde_json::json!({"response_action": "errors", "errors": errors});
+        self.resend(envelope_id, payload);
+    }
+    // Acknowledge an envelope without a payload, the reply will come later via response_url
+    pub fn ack(&mut self, envelope_id: &str) {
+        let ack = Ack {
+            envelope_id: envelope_id.to_string(),
+        };
+        let ack_json = serde_json::to_string(&ack).unwrap();
+        self.send_message(&ack_json);
+    }
+}
+
+/// How many times a request Slack rate limits is sent before giving up
+const MAX_ATTEMPTS: u32 = 3;
+
+// Every Web API reply has `ok` and, when it is false, `error`
+#[derive(Deserialize, Debug)]
+struct ApiResponse {
+    ok: bool,
+    error: Option<String>,
+    #[serde(flatten)]
+    rest: serde_json::Value,
+}
+
+/// A message as chat.postMessage and chat.update identify it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MessageRef {
+    pub channel: String,
+    pub ts: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct EphemeralResponse {
+    message_ts: String,
+}
+
+/// What conversations.info says about a channel
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Conversation {
+    pub id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub is_private: bool,
+    #[serde(default)]
+    pub is_archived: bool,
+    #[serde(default)]
+    pub is_member: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct ConversationResponse {
+    channel: Conversation,
+}
+
+#[derive(Serialize, Debug)]
+struct PostMessage<'a> {
+    channel: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ts: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<&'a str>,
+    #[serde(flatten)]
+    message: &'a MessagePayload,
+}
+
+#[derive(Serialize, Debug)]
+struct MessageTarget<'a> {
+    channel: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ts: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+}
+
+#[derive(Serialize, Debug)]
+struct OpenView<'a> {
+    trigger_id: &'a str,
+    view: &'a Modal,
+}
+
+#[derive(Serialize, Debug)]
+struct UpdateView<'a> {
+    view_id: &'a str,
+    // Slack refuses the update when the view changed since this hash
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<&'a str>,
+    view: &'a Modal,
+}
+
+#[derive(Deserialize, Debug)]
+struct ViewResponse {
+    view: View,
+}
+
+#[derive(Deserialize, Debug)]
+struct UploadUrlResponse {
+    upload_url: String,
+    file_id: String,
+}
+
+#[derive(Serialize, Debug)]
+struct CompleteUpload<'a> {
+    files: Vec<UploadedFile<'a>>,
+    channel_id: &'a str,
+    initial_comment: &'a str,
+}
+
+#[derive(Serialize, Debug)]
+struct UploadedFile<'a> {
+    id: &'a str,
+    title: &'a str,
+}
+
+// Seconds Slack asks to wait in the Retry-After header of a 429
+fn retry_after(headers: &HeaderMap) -> std::time::Duration {
+    let secs = headers
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(1);
+    std::time::Duration::from_secs(secs)
+}
+
+/// Client for the Slack Web API. It needs a bot token (xoxb-) rather than the app token used
+/// for Socket Mode.
+#[derive(Debug, Clone)]
+pub struct WebClient {
+    token: Secret,
+    base_url: String,
+    http: reqwest::Client,
+}
+
+impl WebClient {
+    pub fn new(token: Secret) -> Self {
+        WebClient {
+            token,
+            base_url: "https://slack.com/api/".to_string(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Sends requests somewhere other than slack.com, such as a test server
+    #[cfg(test)]
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = format!("{}/", base_url.trim_end_matches('/'));
+        self
+    }
+
+    fn url(&self, method: &str) -> String {
+        format!("{}{}", self.base_url, method)
+    }
+
+    // Sends a request, waiting and sending it again when Slack rate limits it, and reads the
+    // fields of the reply when it is ok
+    async fn send<R: DeserializeOwned>(
+        &self,
+        method: &str,
+        request: reqwest::RequestBuilder,
+    ) -> Result<R, SlackErr> {
+        let request = request.bearer_auth(self.token.expose());
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let attempt = request
+                .try_clone()
+                .expect("Slack API requests are not streamed");
+            let response = attempt.send().await?;
+            if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                let wait = retry_after(response.headers());
+                if attempts >= MAX_ATTEMPTS {
+                    return Err(SlackErr::RateLimited(wait));
+                }
+                warn!(method, ?wait, "Slack rate limited the request");
+                tokio::time::sleep(wait).await;
+                continue;
+            }
+            let body = response.text().await?;
+            debug!(method, body = %secret::redact(&body), "Slack response");
+            let response: ApiResponse = serde_json::from_str(&body)?;
+            if !response.ok {
+                return Err(SlackErr::Api(response.error.unwrap_or_default()));
+            }
+            return Ok(serde_json::from_value(response.rest)?);
+        }
+    }
+
+    async fn post<B: Serialize, R: DeserializeOwned>(
+        &self,
+        method: &str,
+        body: &B,
+    ) -> Result<R, SlackErr> {
+        let body = serde_json::to_string(body)?;
+        debug!(method, body = %secret::redact(&body), "Slack request");
+        let request = self
+            .http
+            .post(self.url(method))
+            .header(CONTENT_TYPE, "application/json; charset=utf-8")
+            .body(body);
+        self.send(method, request).await
+    }
+
+    /// Posts a message to a channel with chat.postMessage
+    pub async fn post_message(
+        &self,
+        channel: &str,
+        message: &MessagePayload,
+    ) -> Result<MessageRef, SlackErr> {
+        let body = PostMessage {
+            channel,
+            ts: None,
+            user: None,
+            message,
+        };
+        self.post("chat.postMessage", &body).await
+    }
+
+    /// Replaces a message the bot posted with chat.update
+    pub async fn update(
+        &self,
+        channel: &str,
+        ts: &str,
+        message: &MessagePayload,
+    ) -> Result<MessageRef, SlackErr> {
+        let body = PostMessage {
+            channel,
+            ts: Some(ts),
+            user: None,
+            message,
+        };
+        self.post("chat.update", &body).await
+    }
+
+    /// Shows a message only `user` can see with chat.postEphemeral, returning its timestamp
+    pub async fn post_ephemeral(
+        &self,
+        channel: &str,
+        user: &str,
+        message: &MessagePayload,
+    ) -> Result<String, SlackErr> {
+        let body = PostMessage {
+            channel,
+            ts: None,
+            user: Some(user),
+            message,
+        };
+        let response: EphemeralResponse = self.post("chat.postEphemeral", &body).await?;
+        Ok(response.message_ts)
+    }
+
+    /// Deletes a message the bot posted with chat.delete
+    pub async fn delete(&self, channel: &str, ts: &str) -> Result<(), SlackErr> {
+        let body = MessageTarget {
+            channel,
+            ts: Some(ts),
+            timestamp: None,
+            name: None,
+        };
+        self.post::<_, IgnoredAny>("chat.delete", &body).await?;
+        Ok(())
+    }
+
+    /// Adds an emoji reaction, such as `white_check_mark`, to a message with reactions.add
+    pub async fn add_reaction(&self, channel: &str, ts: &str, name: &str) -> Result<(), SlackErr> {
+        let body = MessageTarget {
+            channel,
+            ts: None,
+            timestamp: Some(ts),
+            name: Some(name),
+        };
+        self.post::<_, IgnoredAny>("reactions.add", &body).await?;
+        Ok(())
+    }
+
+    /// Looks up a channel with conversations.info
+    pub async fn conversation_info(&self, channel: &str) -> Result<Conversation, SlackErr> {
+        let request = self
+            .http
+            .get(self.url("conversations.info"))
+            .query(&[("channel", channel)]);
+        let response: ConversationResponse = self.send("conversations.info", request).await?;
+        Ok(response.channel)
+    }
+
+    /// Opens a modal with views.open. The trigger id of the slash command or action that asked
+    /// for it is only valid for 3 seconds.
+    pub async fn open_view(&self, trigger_id: &str, modal: &Modal) -> Result<View, SlackErr> {
+        let body = OpenView {
+            trigger_id,
+            view: modal,
+        };
+        let response: ViewResponse = self.post("views.open", &body).await?;
+        Ok(response.view)
+    }
+
+    /// Replaces an open modal with views.update
+    pub async fn update_view(
+        &self,
+        view_id: &str,
+        hash: Option<&str>,
+        modal: &Modal,
+    ) -> Result<View, SlackErr> {
+        let body = UpdateView {
+            view_id,
+            hash,
+            view: modal,
+        };
+        let response: ViewResponse = self.post("views.update", &body).await?;
+        Ok(response.view)
+    }
+
+    /// Shares a text file in a channel, for output too long for a message. Needs the
+    /// `files:write` scope.
+    pub async fn upload_file(
+        &self,
+        channel: &str,
+        filename: &str,
+        content: &str,
+        comment: &str,
+    ) -> Result<(), SlackErr> {
+        let length = content.len().to_string();
+        let request = self
+            .http
+            .post(self.url("files.getUploadURLExternal"))
+            .form(&[("filename", filename), ("length", length.as_str())]);
+        let upload: UploadUrlResponse = self.send("files.getUploadURLExternal", request).await?;
+        self.http
+            .post(upload.upload_url)
+            .body(content.to_string())
+            .send()
+            .await?
+            .error_for_status()?;
+        let complete = CompleteUpload {
+            files: vec![UploadedFile {
+                id: &upload.file_id,
+                title: filename,
+            }],
+            channel_id: channel,
+            initial_comment: comment,
+        };
+        self.post::<_, IgnoredAny>("files.completeUploadExternal", &complete)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Formats a time so Slack shows it in the reader's own timezone
+pub fn format_date(time: &DateTime<Utc>) -> String {
+    format!(
+        "<!date^{}^{{date_short_pretty}} {{time}}|{}>",
+        time.timestamp(),
+        time.format("%Y-%m-%d %H:%M UTC")
+    )
 }
 
+/// Posts a message to a response_url from a slash command or interactive payload
+pub async fn respond(response_url: &str, message: &MessagePayload) -> Result<(), SlackErr> {
+    debug!(?message, "Responding to Slack");
+    let client = reqwest::Client::new();
+    client
+        .post(response_url)
+        .header(CONTENT_TYPE, "application/json")
+        .json(message)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 pub struct SlashCommand {
-    token: String,
+    token: Secret,
     team_id: String,
     team_domain: String,
-    channel_id: String,
+    pub channel_id: String,
     channel_name: String,
-    user_id: String,
+    pub user_id: String,
     user_name: String,
     command: String,
     pub text: String,
     api_app_id: String,
     is_enterprise_install: String,
-    response_url: String,
-    trigger_id: String,
+    pub response_url: String,
+    pub trigger_id: String,
 }
 
 impl SlashCommand {
@@ -216,7 +893,12 @@ impl SlashCommand {
 #[derive(Serialize, Deserialize, Debug)]
 struct Response {
     envelope_id: String,
-    payload: BlockPayload,
+    payload: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Ack {
+    envelope_id: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -229,68 +911,562 @@ impl BlockPayload {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Block {
+/// A modal to open with views.open or to replace an open one with views.update
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Modal {
     #[serde(rename = "type")]
-    block_type: String,
-    text: TextBlock,
+    view_type: String,
+    // Plain text of up to 24 characters
+    title: TextBlock,
+    blocks: Vec<Block>,
+    // Slack needs a submit button on a modal with input blocks
     #[serde(skip_serializing_if = "Option::is_none")]
-    fields: Option<Vec<TextBlock>>,
+    submit: Option<TextBlock>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    accessory: Option<StaticSelect>,
+    close: Option<TextBlock>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    callback_id: String,
+    // Up to 3000 characters given back with the submission
+    #[serde(skip_serializing_if = "String::is_empty")]
+    private_metadata: String,
+}
+impl Modal {
+    pub fn new(title: String, blocks: Vec<Block>) -> Self {
+        Modal {
+            view_type: "modal".to_string(),
+            title: TextBlock::new_plain(title),
+            blocks,
+            submit: None,
+            close: None,
+            callback_id: String::new(),
+            private_metadata: String::new(),
+        }
+    }
+    pub fn with_submit(mut self, submit: String) -> Self {
+        self.submit = Some(TextBlock::new_plain(submit));
+        self
+    }
+    pub fn with_close(mut self, close: String) -> Self {
+        self.close = Some(TextBlock::new_plain(close));
+        self
+    }
+    pub fn with_callback_id(mut self, callback_id: &str) -> Self {
+        self.callback_id = callback_id.to_string();
+        self
+    }
+    pub fn with_private_metadata(mut self, private_metadata: String) -> Self {
+        self.private_metadata = private_metadata;
+        self
+    }
+}
+
+/// A Block Kit layout block
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Block {
+    Section(Section),
+    Actions(Actions),
+    Context(Context),
+    Header(Header),
+    Divider,
+    Image(ImageBlock),
+    Input(Input),
 }
 impl Block {
     pub fn new_section(text: TextBlock) -> Self {
-        Block {
-            block_type: "section".to_owned(),
-            text,
-            fields: None,
+        Block::Section(Section::new(text))
+    }
+    pub fn new_actions<E: Into<Element>>(elements: Vec<E>) -> Self {
+        Block::Actions(Actions::new(elements))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Section {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<TextBlock>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<TextBlock>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    accessory: Option<Element>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block_id: Option<String>,
+}
+impl Section {
+    pub fn new(text: TextBlock) -> Self {
+        Section {
+            text: Some(text),
+            fields: Vec::new(),
+            accessory: None,
+            block_id: None,
+        }
+    }
+    /// A section of up to 10 fields shown in two columns, without the main text
+    pub fn new_fields(fields: Vec<TextBlock>) -> Self {
+        Section {
+            text: None,
+            fields,
             accessory: None,
+            block_id: None,
+        }
+    }
+    pub fn with_fields(mut self, fields: Vec<TextBlock>) -> Self {
+        self.fields = fields;
+        self
+    }
+    pub fn with_accessory(mut self, element: impl Into<Element>) -> Self {
+        self.accessory = Some(element.into());
+        self
+    }
+    pub fn with_block_id(mut self, block_id: &str) -> Self {
+        self.block_id = Some(block_id.to_string());
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Actions {
+    elements: Vec<Element>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block_id: Option<String>,
+}
+impl Actions {
+    pub fn new<E: Into<Element>>(elements: Vec<E>) -> Self {
+        Actions {
+            elements: elements.into_iter().map(Into::into).collect(),
+            block_id: None,
         }
     }
-    pub fn add_accessory(&mut self, element: StaticSelect) {
-        self.accessory = Some(element);
+    pub fn with_block_id(mut self, block_id: &str) -> Self {
+        self.block_id = Some(block_id.to_string());
+        self
     }
 }
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Element {}
-#[derive(Serialize, Deserialize, Debug)]
+
+/// Small text and images shown under a message
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Context {
+    elements: Vec<ContextElement>,
+}
+impl Context {
+    pub fn new<E: Into<ContextElement>>(elements: Vec<E>) -> Self {
+        Context {
+            elements: elements.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+// Text objects carry their own type and images are tagged as elements, so neither needs a tag here
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ContextElement {
+    Text(TextBlock),
+    Image(Box<Element>),
+}
+impl From<TextBlock> for ContextElement {
+    fn from(text: TextBlock) -> Self {
+        ContextElement::Text(text)
+    }
+}
+impl From<Image> for ContextElement {
+    fn from(image: Image) -> Self {
+        ContextElement::Image(Box::new(Element::Image(image)))
+    }
+}
+
+/// Large bold text, the text must be plain
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Header {
+    text: TextBlock,
+}
+impl Header {
+    pub fn new(text: String) -> Self {
+        Header {
+            text: TextBlock::new_plain(text),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ImageBlock {
+    image_url: String,
+    alt_text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<TextBlock>,
+}
+impl ImageBlock {
+    pub fn new(image_url: String, alt_text: String) -> Self {
+        ImageBlock {
+            image_url,
+            alt_text,
+            title: None,
+        }
+    }
+    pub fn with_title(mut self, title: String) -> Self {
+        self.title = Some(TextBlock::new_plain(title));
+        self
+    }
+}
+
+/// A labelled form field in a modal
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Input {
+    label: TextBlock,
+    element: Element,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hint: Option<TextBlock>,
+    #[serde(default)]
+    optional: bool,
+}
+impl Input {
+    pub fn new(label: String, element: impl Into<Element>) -> Self {
+        Input {
+            label: TextBlock::new_plain(label),
+            element: element.into(),
+            block_id: None,
+            hint: None,
+            optional: false,
+        }
+    }
+    pub fn with_block_id(mut self, block_id: &str) -> Self {
+        self.block_id = Some(block_id.to_string());
+        self
+    }
+    pub fn with_hint(mut self, hint: String) -> Self {
+        self.hint = Some(TextBlock::new_plain(hint));
+        self
+    }
+    pub fn optional(mut self) -> Self {
+        self.optional = true;
+        self
+    }
+}
+
+macro_rules! into_block {
+    ($($variant:ident($kind:ident)),*) => {
+        $(impl From<$kind> for Block {
+            fn from(block: $kind) -> Self {
+                Block::$variant(block)
+            }
+        })*
+    };
+}
+into_block!(
+    Section(Section),
+    Actions(Actions),
+    Context(Context),
+    Header(Header),
+    Image(ImageBlock),
+    Input(Input)
+);
+
+/// An interactive element in a section, actions or input block
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Element {
+    Button(Button),
+    Overflow(Overflow),
+    StaticSelect(StaticSelect),
+    MultiStaticSelect(MultiStaticSelect),
+    Datepicker(Datepicker),
+    PlainTextInput(PlainTextInput),
+    Image(Image),
+}
+
+macro_rules! into_element {
+    ($($kind:ident),*) => {
+        $(impl From<$kind> for Element {
+            fn from(element: $kind) -> Self {
+                Element::$kind(element)
+            }
+        })*
+    };
+}
+into_element!(
+    Button,
+    Overflow,
+    StaticSelect,
+    MultiStaticSelect,
+    Datepicker,
+    PlainTextInput,
+    Image
+);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ButtonStyle {
+    Primary,
+    Danger,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Button {
+    text: TextBlock,
+    action_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    style: Option<ButtonStyle>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confirm: Option<ConfirmObject>,
+}
+impl Button {
+    pub fn new(text: TextBlock, action_id: String, value: String) -> Self {
+        Button {
+            text,
+            action_id,
+            value: Some(value),
+            style: None,
+            url: None,
+            confirm: None,
+        }
+    }
+    pub fn with_style(mut self, style: ButtonStyle) -> Self {
+        self.style = Some(style);
+        self
+    }
+    /// Opens the URL in the browser as well as sending the action
+    pub fn with_url(mut self, url: String) -> Self {
+        self.url = Some(url);
+        self
+    }
+    pub fn with_confirm(mut self, confirm: ConfirmObject) -> Self {
+        self.confirm = Some(confirm);
+        self
+    }
+}
+
+/// A "..." menu of up to 5 options
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Overflow {
+    action_id: String,
+    options: Vec<OptionObject>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confirm: Option<ConfirmObject>,
+}
+impl Overflow {
+    pub fn new(action_id: String, options: Vec<OptionObject>) -> Self {
+        Overflow {
+            action_id,
+            options,
+            confirm: None,
+        }
+    }
+    pub fn with_confirm(mut self, confirm: ConfirmObject) -> Self {
+        self.confirm = Some(confirm);
+        self
+    }
+}
+
+// Slack takes either options or option groups, never both
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct StaticSelect {
-    #[serde(rename = "type")]
-    element_type: String,
     placeholder: TextBlock,
     action_id: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     options: Vec<OptionObject>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    option_groups: Option<Vec<OptionObject>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    option_groups: Vec<OptionGroup>,
     #[serde(skip_serializing_if = "Option::is_none")]
     initial_option: Option<OptionObject>,
     #[serde(skip_serializing_if = "Option::is_none")]
     confirm: Option<ConfirmObject>,
-    focus_on_load: bool,
 }
 impl StaticSelect {
     pub fn new(placeholder: TextBlock, action_id: String, options: Vec<OptionObject>) -> Self {
         StaticSelect {
-            element_type: "static_select".to_string(),
             placeholder,
             action_id,
             options,
-            option_groups: None,
+            option_groups: Vec::new(),
             initial_option: None,
             confirm: None,
-            focus_on_load: false,
         }
     }
+    pub fn new_grouped(
+        placeholder: TextBlock,
+        action_id: String,
+        groups: Vec<OptionGroup>,
+    ) -> Self {
+        StaticSelect {
+            option_groups: groups,
+            ..StaticSelect::new(placeholder, action_id, Vec::new())
+        }
+    }
+    pub fn with_initial_option(mut self, option: OptionObject) -> Self {
+        self.initial_option = Some(option);
+        self
+    }
+    pub fn with_confirm(mut self, confirm: ConfirmObject) -> Self {
+        self.confirm = Some(confirm);
+        self
+    }
 }
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ConfirmObject {}
-#[derive(Serialize, Deserialize, Debug)]
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MultiStaticSelect {
+    placeholder: TextBlock,
+    action_id: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    options: Vec<OptionObject>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    option_groups: Vec<OptionGroup>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    initial_options: Vec<OptionObject>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_selected_items: Option<u32>,
+}
+impl MultiStaticSelect {
+    pub fn new(placeholder: TextBlock, action_id: String, options: Vec<OptionObject>) -> Self {
+        MultiStaticSelect {
+            placeholder,
+            action_id,
+            options,
+            option_groups: Vec::new(),
+            initial_options: Vec::new(),
+            max_selected_items: None,
+        }
+    }
+    pub fn new_grouped(
+        placeholder: TextBlock,
+        action_id: String,
+        groups: Vec<OptionGroup>,
+    ) -> Self {
+        MultiStaticSelect {
+            option_groups: groups,
+            ..MultiStaticSelect::new(placeholder, action_id, Vec::new())
+        }
+    }
+    pub fn with_initial_options(mut self, options: Vec<OptionObject>) -> Self {
+        self.initial_options = options;
+        self
+    }
+    pub fn with_max_selected_items(mut self, max: u32) -> Self {
+        self.max_selected_items = Some(max);
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Datepicker {
+    action_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    placeholder: Option<TextBlock>,
+    // YYYY-MM-DD
+    #[serde(skip_serializing_if = "Option::is_none")]
+    initial_date: Option<String>,
+}
+impl Datepicker {
+    pub fn new(action_id: String) -> Self {
+        Datepicker {
+            action_id,
+            placeholder: None,
+            initial_date: None,
+        }
+    }
+    pub fn with_placeholder(mut self, placeholder: String) -> Self {
+        self.placeholder = Some(TextBlock::new_plain(placeholder));
+        self
+    }
+    pub fn with_initial_date(mut self, date: NaiveDate) -> Self {
+        self.initial_date = Some(date.format("%Y-%m-%d").to_string());
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlainTextInput {
+    action_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    placeholder: Option<TextBlock>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    initial_value: Option<String>,
+    #[serde(default)]
+    multiline: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_length: Option<u32>,
+}
+impl PlainTextInput {
+    pub fn new(action_id: String) -> Self {
+        PlainTextInput {
+            action_id,
+            placeholder: None,
+            initial_value: None,
+            multiline: false,
+            max_length: None,
+        }
+    }
+    pub fn with_placeholder(mut self, placeholder: String) -> Self {
+        self.placeholder = Some(TextBlock::new_plain(placeholder));
+        self
+    }
+    pub fn with_initial_value(mut self, value: String) -> Self {
+        self.initial_value = Some(value);
+        self
+    }
+    pub fn multiline(mut self) -> Self {
+        self.multiline = true;
+        self
+    }
+    pub fn with_max_length(mut self, max_length: u32) -> Self {
+        self.max_length = Some(max_length);
+        self
+    }
+}
+
+/// An image inside a section or context block
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Image {
+    image_url: String,
+    alt_text: String,
+}
+impl Image {
+    pub fn new(image_url: String, alt_text: String) -> Self {
+        Image {
+            image_url,
+            alt_text,
+        }
+    }
+}
+
+/// The "Are you sure?" dialog shown before an element's action is sent
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConfirmObject {
+    title: TextBlock,
+    text: TextBlock,
+    confirm: TextBlock,
+    deny: TextBlock,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    style: Option<ButtonStyle>,
+}
+impl ConfirmObject {
+    pub fn new(title: String, text: TextBlock, confirm: String, deny: String) -> Self {
+        ConfirmObject {
+            title: TextBlock::new_plain(title),
+            text,
+            confirm: TextBlock::new_plain(confirm),
+            deny: TextBlock::new_plain(deny),
+            style: None,
+        }
+    }
+    /// Style of the confirm button
+    pub fn with_style(mut self, style: ButtonStyle) -> Self {
+        self.style = Some(style);
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct OptionObject {
     text: TextBlock,
     value: String,
     #[serde(skip_serializing_if = "Option::is_none")]
-    description: Option<String>,
+    description: Option<TextBlock>,
+    // Only for overflow menus
     #[serde(skip_serializing_if = "Option::is_none")]
     url: Option<String>,
 }
@@ -304,9 +1480,27 @@ impl OptionObject {
             url: None,
         }
     }
+    pub fn with_description(mut self, description: String) -> Self {
+        self.description = Some(TextBlock::new_plain(description));
+        self
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OptionGroup {
+    label: TextBlock,
+    options: Vec<OptionObject>,
+}
+impl OptionGroup {
+    pub fn new(label: String, options: Vec<OptionObject>) -> Self {
+        OptionGroup {
+            label: TextBlock::new_plain(label),
+            options,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct TextBlock {
     #[serde(rename = "type")]
     text_type: String,
@@ -327,26 +1521,423 @@ impl TextBlock {
     }
 }
 
-pub fn handle_slash_command(
-    socket: &mut tungstenite::WebSocket<MaybeTlsStream<TcpStream>>,
-    envelope_id: String,
-) {
-    let block1 = Block::new_section(TextBlock::new_mrkdwn("This is a test".to_owned()));
-    let block2 = Block::new_section(TextBlock::new_mrkdwn("This is another test".to_owned()));
-    let blocks = vec![block1, block2];
-    let payload = BlockPayload { blocks };
-
-    // send block back as resposne
-    let response = Response {
-        envelope_id,
-        payload,
-    };
-    let response_json = serde_json::to_string(&response).unwrap();
-    println!("send message {}", &response_json);
-    socket.write_message(Message::Text(response_json)).unwrap();
-}
-
 pub fn parse_message(s: &str) -> SocketEvent {
     let socket_event: SocketEvent = serde_json::from_str(s).unwrap();
     socket_event
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use std::collections::VecDeque;
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+
+    type Requests = Arc<Mutex<Vec<(String, String, String)>>>;
+
+    // A local Slack answering each request with the next status, Retry-After and body, and
+    // keeping the path, Authorization header and body of every request
+    async fn mock_slack(
+        responses: Vec<(u16, Option<&'static str>, &'static str)>,
+    ) -> (String, Requests) {
+        let responses = Arc::new(Mutex::new(VecDeque::from(responses)));
+        let requests: Requests = Arc::default();
+        let seen = requests.clone();
+        let make_service = make_service_fn(move |_| {
+            let responses = responses.clone();
+            let seen = seen.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let responses = responses.clone();
+                    let seen = seen.clone();
+                    async move {
+                        let path = request.uri().to_string();
+                        let auth = request
+                            .headers()
+                            .get(AUTHORIZATION)
+                            .map(|value| value.to_str().unwrap().to_string())
+                            .unwrap_or_default();
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        let body = String::from_utf8(body.to_vec()).unwrap();
+                        seen.lock().unwrap().push((path, auth, body));
+                        let (status, retry, reply) = responses.lock().unwrap().pop_front().unwrap();
+                        let mut response = Response::builder().status(status);
+                        if let Some(retry) = retry {
+                            response = response.header(RETRY_AFTER, retry);
+                        }
+                        Ok::<_, Infallible>(response.body(Body::from(reply)).unwrap())
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let url = format!("http://{}/api", server.local_addr());
+        tokio::spawn(server);
+        (url, requests)
+    }
+    fn client(url: &str) -> WebClient {
+        WebClient::new(Secret::new("xoxb-test".to_string())).with_base_url(url)
+    }
+    #[test]
+    fn test_post_message() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let reply = r#"{"ok":true,"channel":"C1","ts":"1655712000.000100","message":{}}"#;
+            let (url, requests) = mock_slack(vec![(200, None, reply)]).await;
+            let message = MessagePayload::new("Wall jack 2-114 has been shut down".to_string());
+            let posted = client(&url).post_message("C1", &message).await.unwrap();
+            assert_eq!(
+                posted,
+                MessageRef {
+                    channel: "C1".to_string(),
+                    ts: "1655712000.000100".to_string()
+                }
+            );
+            let requests = requests.lock().unwrap();
+            let (path, auth, body) = &requests[0];
+            assert_eq!(path, "/api/chat.postMessage");
+            assert_eq!(auth, "Bearer xoxb-test");
+            let body: serde_json::Value = serde_json::from_str(body).unwrap();
+            assert_eq!(body["channel"], "C1");
+            assert_eq!(body["text"], "Wall jack 2-114 has been shut down");
+        });
+    }
+    #[test]
+    fn test_api_error() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let reply = r#"{"ok":false,"error":"channel_not_found"}"#;
+            let (url, _) = mock_slack(vec![(200, None, reply)]).await;
+            match client(&url).delete("C404", "1.2").await {
+                Err(SlackErr::Api(err)) => assert_eq!(err, "channel_not_found"),
+                result => panic!("Unexpected result {:?}", result),
+            }
+        });
+    }
+    #[test]
+    fn test_rate_limited() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let limited = (429, Some("0"), "");
+            let reply = r#"{"ok":true,"channel":{"id":"C1","name":"netops","is_member":true}}"#;
+            let (url, requests) = mock_slack(vec![limited, (200, None, reply)]).await;
+            let channel = client(&url).conversation_info("C1").await.unwrap();
+            assert_eq!(channel.name.as_deref(), Some("netops"));
+            assert!(channel.is_member);
+            {
+                let requests = requests.lock().unwrap();
+                assert_eq!(requests.len(), 2);
+                assert_eq!(requests[1].0, "/api/conversations.info?channel=C1");
+            }
+
+            // Slack keeps limiting, so the request gives up
+            let (url, requests) = mock_slack(vec![limited; 3]).await;
+            let result = client(&url)
+                .add_reaction("C1", "1.2", "white_check_mark")
+                .await;
+            assert!(matches!(result, Err(SlackErr::RateLimited(_))));
+            assert_eq!(requests.lock().unwrap().len(), 3);
+        });
+    }
+    #[test]
+    fn test_open_view() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let reply = r#"{"ok":true,"view":{"id":"V1","hash":"h1","callback_id":"portconfig","state":{"values":{}}}}"#;
+            let (url, requests) = mock_slack(vec![(200, None, reply)]).await;
+            let modal = Modal::new("Configure port".to_string(), vec![])
+                .with_close("Close".to_string())
+                .with_callback_id("portconfig");
+            let view = client(&url).open_view("t1", &modal).await.unwrap();
+            assert_eq!(view.id, "V1");
+            assert_eq!(view.hash.as_deref(), Some("h1"));
+            let requests = requests.lock().unwrap();
+            assert_eq!(requests[0].0, "/api/views.open");
+            let body: serde_json::Value = serde_json::from_str(&requests[0].2).unwrap();
+            assert_eq!(body["trigger_id"], "t1");
+            assert_eq!(body["view"]["type"], "modal");
+            assert_eq!(body["view"]["close"]["text"], "Close");
+            assert!(body["view"].get("submit").is_none());
+        });
+    }
+    #[test]
+    fn test_parse_disconnect() {
+        let message = r#"{"type":"disconnect","reason":"refresh_requested","debug_info":{"host":"applink-1"}}"#;
+        match parse_message(message) {
+            SocketEvent::Disconnect { reason } => assert_eq!(reason, "refresh_requested"),
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+    #[test]
+    fn test_parse_block_actions() {
+        let payload = r#"{"type":"block_actions","user":{"id":"U1","username":"sam","team_id":"T1"},
+            "channel":{"id":"C1","name":"netops"},"trigger_id":"t1","response_url":"https://hooks.slack.com/actions/1",
+            "message":{"type":"message","ts":"1655712000.000100","text":"Wall jack 2-114","user":"B1"},
+            "actions":[
+                {"type":"button","action_id":"undo","block_id":"b1","value":"cc-1","action_ts":"1655712001.1"},
+                {"type":"static_select","action_id":"port_assign_segment","block_id":"b2","action_ts":"1655712001.2",
+                 "selected_option":{"text":{"type":"plain_text","text":"USERS:VLAN 100"},"value":"vlan100"}}
+            ]}"#;
+        let payload: Interactive = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload.user().id, "U1");
+        assert_eq!(payload.names(), vec!["undo", "port_assign_segment"]);
+        let actions = match payload {
+            Interactive::BlockActions(actions) => actions,
+            other => panic!("Unexpected payload {:?}", other),
+        };
+        assert_eq!(actions.message.unwrap().ts, "1655712000.000100");
+        assert_eq!(actions.actions[0].value(), "cc-1");
+        assert_eq!(actions.actions[1].value(), "vlan100");
+        assert_eq!(actions.actions[1].input.element_type, "static_select");
+    }
+    #[test]
+    fn test_parse_views_and_shortcuts() {
+        let payload = r#"{"type":"view_submission","user":{"id":"U1"},"trigger_id":"t2",
+            "view":{"id":"V1","callback_id":"portconfig","private_metadata":"emea 2-114","hash":"h1",
+            "state":{"values":{"vlan":{"vlan":{"type":"plain_text_input","value":"100"}},
+                "state":{"state":{"type":"static_select","selected_option":null}},
+                "until":{"until":{"type":"datepicker","selected_date":"2022-06-20"}}}}}}"#;
+        let payload: Interactive = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload.names(), vec!["portconfig"]);
+        match payload {
+            Interactive::ViewSubmission(submission) => {
+                let state = &submission.view.state;
+                assert_eq!(state.get("vlan", "vlan").unwrap().value(), Some("100"));
+                assert_eq!(state.get("state", "state").unwrap().value(), None);
+                let until = state.get("until", "until").unwrap();
+                assert_eq!(until.selected_date.as_deref(), Some("2022-06-20"));
+                assert!(state.get("vlan", "missing").is_none());
+            }
+            other => panic!("Unexpected payload {:?}", other),
+        }
+
+        let payload = r#"{"type":"view_closed","user":{"id":"U1"},"is_cleared":false,
+            "view":{"id":"V1","callback_id":"portconfig","state":{"values":{}}}}"#;
+        let payload: Interactive = serde_json::from_str(payload).unwrap();
+        assert!(matches!(payload, Interactive::ViewClosed(_)));
+        assert_eq!(payload.trigger_id(), "");
+
+        let payload =
+            r#"{"type":"shortcut","user":{"id":"U1"},"callback_id":"portcheck","trigger_id":"t3"}"#;
+        let payload: Interactive = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload.trigger_id(), "t3");
+
+        let payload = r#"{"type":"message_action","user":{"id":"U1"},"callback_id":"undo_message",
+            "trigger_id":"t4","channel":{"id":"C1"},"response_url":"https://hooks.slack.com/actions/2",
+            "message":{"ts":"1.2","text":"Shut down 2-114"}}"#;
+        let payload: Interactive = serde_json::from_str(payload).unwrap();
+        match payload {
+            Interactive::MessageAction(action) => {
+                assert_eq!(action.message.text, "Shut down 2-114")
+            }
+            other => panic!("Unexpected payload {:?}", other),
+        }
+    }
+    #[test]
+    fn test_router() {
+        let router: Router<String> = Router::new().on("echo", |prefix, payload, action| {
+            Box::pin(async move {
+                let text = format!("{} {} {}", prefix, payload.user.id, action.value());
+                Some(MessagePayload::new(text))
+            })
+        });
+        let payload = r#"{"type":"block_actions","user":{"id":"U1"},"trigger_id":"t1","actions":[
+            {"type":"button","action_id":"echo","block_id":"b1","value":"2-114","action_ts":"1.1"},
+            {"type":"button","action_id":"nope","block_id":"b1","action_ts":"1.2"}]}"#;
+        let payload: BlockActions = match serde_json::from_str(payload).unwrap() {
+            Interactive::BlockActions(payload) => payload,
+            other => panic!("Unexpected payload {:?}", other),
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let replies = runtime.block_on(router.dispatch(&"Hello".to_string(), &payload));
+        assert_eq!(replies.len(), 2);
+        assert_eq!(
+            replies[0].as_ref().unwrap().as_ref().unwrap().text,
+            "Hello U1 2-114"
+        );
+        assert_eq!(replies[1].as_ref().unwrap_err(), "nope");
+    }
+    fn option(text: &str, value: &str) -> OptionObject {
+        OptionObject::new(TextBlock::new_plain(text.to_string()), value.to_string())
+    }
+    #[test]
+    fn test_section_blocks() {
+        let button = Button::new(
+            TextBlock::new_plain("Shut".to_string()),
+            "port_shut".to_string(),
+            "2-114".to_string(),
+        )
+        .with_style(ButtonStyle::Danger)
+        .with_confirm(ConfirmObject::new(
+            "Shut down 2-114?".to_string(),
+            TextBlock::new_mrkdwn("Ethernet12 on *JPE1* goes down".to_string()),
+            "Shut".to_string(),
+            "Cancel".to_string(),
+        ));
+        let section = Section::new(TextBlock::new_mrkdwn("Wall jack *2-114*".to_string()))
+            .with_fields(vec![
+                TextBlock::new_mrkdwn("*Switch*\nJPE1".to_string()),
+                TextBlock::new_mrkdwn("*Interface*\nEthernet12".to_string()),
+            ])
+            .with_accessory(button)
+            .with_block_id("jack");
+        let blocks: Vec<Block> = vec![
+            Header::new("Wall jack".to_string()).into(),
+            section.into(),
+            Block::Divider,
+            ImageBlock::new(
+                "https://example.com/floor2.png".to_string(),
+                "Floor 2".to_string(),
+            )
+            .with_title("Floor 2".to_string())
+            .into(),
+            Context::new(vec![
+                ContextElement::from(Image::new(
+                    "https://example.com/arista.png".to_string(),
+                    "Arista".to_string(),
+                )),
+                TextBlock::new_mrkdwn("Last changed by <@U1>".to_string()).into(),
+            ])
+            .into(),
+        ];
+        let expected = serde_json::json!([
+            {"type": "header", "text": {"type": "plain_text", "text": "Wall jack"}},
+            {
+                "type": "section",
+                "text": {"type": "mrkdwn", "text": "Wall jack *2-114*"},
+                "fields": [
+                    {"type": "mrkdwn", "text": "*Switch*\nJPE1"},
+                    {"type": "mrkdwn", "text": "*Interface*\nEthernet12"}
+                ],
+                "accessory": {
+                    "type": "button",
+                    "text": {"type": "plain_text", "text": "Shut"},
+                    "action_id": "port_shut",
+                    "value": "2-114",
+                    "style": "danger",
+                    "confirm": {
+                        "title": {"type": "plain_text", "text": "Shut down 2-114?"},
+                        "text": {"type": "mrkdwn", "text": "Ethernet12 on *JPE1* goes down"},
+                        "confirm": {"type": "plain_text", "text": "Shut"},
+                        "deny": {"type": "plain_text", "text": "Cancel"}
+                    }
+                },
+                "block_id": "jack"
+            },
+            {"type": "divider"},
+            {
+                "type": "image",
+                "image_url": "https://example.com/floor2.png",
+                "alt_text": "Floor 2",
+                "title": {"type": "plain_text", "text": "Floor 2"}
+            },
+            {
+                "type": "context",
+                "elements": [
+                    {"type": "image", "image_url": "https://example.com/arista.png", "alt_text": "Arista"},
+                    {"type": "mrkdwn", "text": "Last changed by <@U1>"}
+                ]
+            }
+        ]);
+        assert_eq!(serde_json::to_value(&blocks).unwrap(), expected);
+        let parsed: Vec<Block> = serde_json::from_value(expected).unwrap();
+        assert_eq!(parsed, blocks);
+    }
+    #[test]
+    fn test_action_blocks() {
+        let groups = vec![
+            OptionGroup::new("Users".to_string(), vec![option("VLAN 100", "100")]),
+            OptionGroup::new("Voice".to_string(), vec![option("VLAN 200", "200")]),
+        ];
+        let placeholder = TextBlock::new_plain("Segments".to_string());
+        let actions = Actions::new(vec![
+            Element::from(
+                MultiStaticSelect::new_grouped(placeholder, "segments".to_string(), groups)
+                    .with_max_selected_items(2),
+            ),
+            Overflow::new(
+                "more".to_string(),
+                vec![option("Port details", "details").with_description("Ethernet12".to_string())],
+            )
+            .into(),
+            Datepicker::new("until".to_string())
+                .with_initial_date(NaiveDate::from_ymd(2022, 6, 20))
+                .into(),
+        ]);
+        let expected = serde_json::json!({
+            "type": "actions",
+            "elements": [
+                {
+                    "type": "multi_static_select",
+                    "placeholder": {"type": "plain_text", "text": "Segments"},
+                    "action_id": "segments",
+                    "option_groups": [
+                        {
+                            "label": {"type": "plain_text", "text": "Users"},
+                            "options": [{"text": {"type": "plain_text", "text": "VLAN 100"}, "value": "100"}]
+                        },
+                        {
+                            "label": {"type": "plain_text", "text": "Voice"},
+                            "options": [{"text": {"type": "plain_text", "text": "VLAN 200"}, "value": "200"}]
+                        }
+                    ],
+                    "max_selected_items": 2
+                },
+                {
+                    "type": "overflow",
+                    "action_id": "more",
+                    "options": [{
+                        "text": {"type": "plain_text", "text": "Port details"},
+                        "value": "details",
+                        "description": {"type": "plain_text", "text": "Ethernet12"}
+                    }]
+                },
+                {"type": "datepicker", "action_id": "until", "initial_date": "2022-06-20"}
+            ]
+        });
+        assert_eq!(
+            serde_json::to_value(Block::from(actions)).unwrap(),
+            expected
+        );
+    }
+    #[test]
+    fn test_input_block() {
+        let input = Input::new(
+            "Description".to_string(),
+            PlainTextInput::new("description".to_string())
+                .with_initial_value("Room 114".to_string())
+                .with_max_length(80),
+        )
+        .with_block_id("description")
+        .with_hint("Shown on the switch port".to_string())
+        .optional();
+        let expected = serde_json::json!({
+            "type": "input",
+            "label": {"type": "plain_text", "text": "Description"},
+            "element": {
+                "type": "plain_text_input",
+                "action_id": "description",
+                "initial_value": "Room 114",
+                "multiline": false,
+                "max_length": 80
+            },
+            "block_id": "description",
+            "hint": {"type": "plain_text", "text": "Shown on the switch port"},
+            "optional": true
+        });
+        assert_eq!(serde_json::to_value(Block::from(input)).unwrap(), expected);
+
+        let select = StaticSelect::new(
+            TextBlock::new_plain("Admin state".to_string()),
+            "state".to_string(),
+            vec![option("Up", "up"), option("Down", "down")],
+        )
+        .with_initial_option(option("Up", "up"));
+        let value = serde_json::to_value(Element::from(select)).unwrap();
+        assert_eq!(value["type"], "static_select");
+        assert_eq!(value["initial_option"]["value"], "up");
+        assert!(value.get("option_groups").is_none());
+    }
+}
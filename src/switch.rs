@@ -0,0 +1,318 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::prelude::*;
+use serde_json::Value;
+
+use crate::cvp::{self, CloudVisionError, Device, Notification};
+use crate::slack;
+use crate::tags;
+
+/// Interfaces per page of `/switchports`
+pub const PAGE_SIZE: usize = 40;
+
+const INTF_CONFIG: &str = "/Sysdb/interface/config/eth/phy/slice/1/intfConfig";
+const INTF_STATUS: &str = "/Sysdb/interface/status/eth/phy/slice/1/intfStatus";
+const SWITCHPORT: &str = "/Sysdb/bridging/switchIntfConfig/switchIntfConfig";
+
+/// An interface of a switch with its wall jacks and state, None where telemetry has no value
+#[derive(Debug, Default, PartialEq)]
+pub struct SwitchPort {
+    pub interface_id: String,
+    pub walljacks: Vec<String>,
+    pub enabled: Option<bool>,
+    pub oper_status: Option<String>,
+    pub vlan: Option<String>,
+    pub description: Option<String>,
+}
+
+/// Finds a device by serial number, hostname or FQDN, ignoring case for the names
+pub fn find_device<'a>(devices: &'a [Device], name: &str) -> Option<&'a Device> {
+    devices
+        .iter()
+        .find(|d| d.key.device_id == name)
+        .or_else(|| {
+            devices.iter().find(|d| {
+                d.hostname.eq_ignore_ascii_case(name) || d.fqdn.eq_ignore_ascii_case(name)
+            })
+        })
+}
+
+/// The hostname of a device, or its serial number when it has none
+pub fn name(device: &Device) -> &str {
+    if device.hostname.is_empty() {
+        &device.key.device_id
+    } else {
+        &device.hostname
+    }
+}
+
+/// A summary of the inventory fields of a device
+pub fn describe(device: &Device) -> String {
+    let boot_time = match DateTime::parse_from_rfc3339(&device.boot_time) {
+        Ok(time) => slack::format_date(&time.with_timezone(&Utc)),
+        Err(_) if device.boot_time.is_empty() => "unknown".to_string(),
+        Err(_) => device.boot_time.clone(),
+    };
+    let streaming = device
+        .streaming_status
+        .trim_start_matches("STREAMING_STATUS_")
+        .to_lowercase();
+    format!(
+        "*{}*\nSerial: {}\nModel: {}\nEOS: {}\nBooted: {}\nStreaming: {}",
+        name(device),
+        device.key.device_id,
+        device.model_name,
+        device.software_version,
+        boot_time,
+        if streaming.is_empty() {
+            "unknown"
+        } else {
+            &streaming
+        }
+    )
+}
+
+// Fields of every entity below `collection`, keyed by the entity name
+fn entities(
+    notifications: &[Notification],
+    collection: &str,
+) -> HashMap<String, HashMap<String, Value>> {
+    let mut result: HashMap<String, HashMap<String, Value>> = HashMap::new();
+    for notification in notifications {
+        if let Some(entity) = notification.entity(collection) {
+            let fields = result.entry(entity.to_string()).or_default();
+            for (key, update) in &notification.updates {
+                fields.insert(key.clone(), update.value.clone());
+            }
+        }
+    }
+    result
+}
+
+// Enum values come as {"Name": "intfOperUp", "Value": 1}
+fn enum_name(value: &Value) -> Option<&str> {
+    value.get("Name").and_then(Value::as_str)
+}
+
+// Collection name, the last element of a Sysdb path
+fn collection(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Joins interface telemetry with the wall jacks assigned on the device
+pub fn join_ports(
+    assignments: &[tags::Assignment],
+    config: &[Notification],
+    status: &[Notification],
+    switchport: &[Notification],
+) -> Vec<SwitchPort> {
+    let config = entities(config, collection(INTF_CONFIG));
+    let status = entities(status, collection(INTF_STATUS));
+    let switchport = entities(switchport, collection(SWITCHPORT));
+    let mut ports: BTreeMap<InterfaceOrder, SwitchPort> = BTreeMap::new();
+    let names = status
+        .keys()
+        .chain(config.keys())
+        .chain(assignments.iter().map(|a| &a.interface_id));
+    for interface_id in names {
+        ports
+            .entry(InterfaceOrder::new(interface_id))
+            .or_insert_with(|| SwitchPort {
+                interface_id: interface_id.clone(),
+                ..SwitchPort::default()
+            });
+    }
+    for port in ports.values_mut() {
+        let id = port.interface_id.as_str();
+        port.walljacks = assignments
+            .iter()
+            .filter(|a| a.interface_id == id)
+            .map(|a| a.value.clone())
+            .collect();
+        port.enabled = config
+            .get(id)
+            .and_then(|fields| fields.get("enabled"))
+            .and_then(Value::as_bool);
+        port.description = config
+            .get(id)
+            .and_then(|fields| fields.get("description"))
+            .and_then(Value::as_str)
+            .filter(|description| !description.is_empty())
+            .map(str::to_string);
+        port.oper_status = status
+            .get(id)
+            .and_then(|fields| fields.get("operStatus"))
+            .and_then(enum_name)
+            .map(|name| name.trim_start_matches("intfOper").to_lowercase());
+        port.vlan = switchport.get(id).map(|fields| {
+            match fields.get("switchportMode").and_then(enum_name) {
+                Some("trunk") => "trunk".to_string(),
+                Some("routed") => "routed".to_string(),
+                _ => fields
+                    .get("accessVlan")
+                    .map(|vlan| match vlan.get("value") {
+                        Some(value) => value.to_string(),
+                        None => vlan.to_string(),
+                    })
+                    .unwrap_or_default(),
+            }
+        });
+    }
+    ports.into_values().collect()
+}
+
+/// Every interface of a device, with its wall jacks, admin and oper state and VLAN
+pub async fn ports(cv: &cvp::Host, device_id: &str) -> Result<Vec<SwitchPort>, CloudVisionError> {
+    let assignments = tags::assignments(cv, tags::WALL_JACK, None, Some(device_id)).await?;
+    let config = cv.get_telemetry(device_id, INTF_CONFIG).await?;
+    let status = cv.get_telemetry(device_id, INTF_STATUS).await?;
+    let switchport = cv.get_telemetry(device_id, SWITCHPORT).await?;
+    Ok(join_ports(&assignments, &config, &status, &switchport))
+}
+
+/// One page of the port table, pages start at 0
+pub fn port_table(ports: &[SwitchPort], page: usize) -> String {
+    let mut table = format!(
+        "```{:<14} {:<12} {:<9} {:<11} {}\n",
+        "Interface", "Jack", "Admin", "Oper", "VLAN"
+    );
+    for port in ports.iter().skip(page * PAGE_SIZE).take(PAGE_SIZE) {
+        let admin = match port.enabled {
+            Some(true) => "enabled",
+            Some(false) => "shutdown",
+            None => "-",
+        };
+        let jacks = if port.walljacks.is_empty() {
+            "-".to_string()
+        } else {
+            port.walljacks.join(",")
+        };
+        table.push_str(&format!(
+            "{:<14} {:<12} {:<9} {:<11} {}\n",
+            port.interface_id,
+            jacks,
+            admin,
+            port.oper_status.as_deref().unwrap_or("-"),
+            port.vlan
+                .as_deref()
+                .filter(|v| !v.is_empty())
+                .unwrap_or("-")
+        ));
+    }
+    table.push_str("```");
+    table
+}
+
+pub fn page_count(ports: &[SwitchPort]) -> usize {
+    ports.len().div_ceil(PAGE_SIZE).max(1)
+}
+
+// Sorts Ethernet2 before Ethernet10 and Ethernet1/2 before Ethernet1/10
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct InterfaceOrder(String, Vec<u32>, String);
+
+impl InterfaceOrder {
+    fn new(interface_id: &str) -> Self {
+        let prefix_len = interface_id
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(interface_id.len());
+        let (prefix, numbers) = interface_id.split_at(prefix_len);
+        let numbers = numbers
+            .split(|c: char| !c.is_ascii_digit())
+            .filter_map(|n| n.parse().ok())
+            .collect();
+        InterfaceOrder(prefix.to_string(), numbers, interface_id.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn notification(collection: &str, interface: &str, updates: Value) -> Notification {
+        let path_elements = ["Sysdb", "x", collection, interface]
+            .iter()
+            .map(|e| format!("\"{}\"", e))
+            .collect();
+        let updates = updates
+            .as_object()
+            .unwrap()
+            .iter()
+            .map(|(key, value)| {
+                (
+                    key.clone(),
+                    cvp::Update {
+                        value: value.clone(),
+                    },
+                )
+            })
+            .collect();
+        Notification {
+            path_elements,
+            updates,
+        }
+    }
+    #[test]
+    fn test_join_ports() {
+        let assignments = vec![tags::Assignment::new("2-114", "JPE1", "Ethernet10")];
+        let config = vec![
+            notification(
+                "intfConfig",
+                "Ethernet10",
+                serde_json::json!({"enabled": false}),
+            ),
+            notification(
+                "intfConfig",
+                "Ethernet2",
+                serde_json::json!({"enabled": true, "description": "Room 114"}),
+            ),
+        ];
+        let status = vec![notification(
+            "intfStatus",
+            "Ethernet2",
+            serde_json::json!({"operStatus": {"Name": "intfOperUp", "Value": 1}}),
+        )];
+        let switchport = vec![
+            notification(
+                "switchIntfConfig",
+                "Ethernet2",
+                serde_json::json!({"switchportMode": {"Name": "access"}, "accessVlan": {"value": 20}}),
+            ),
+            notification(
+                "switchIntfConfig",
+                "Ethernet10",
+                serde_json::json!({"switchportMode": {"Name": "trunk"}}),
+            ),
+        ];
+        let ports = join_ports(&assignments, &config, &status, &switchport);
+        assert_eq!(ports.len(), 2);
+        assert_eq!(
+            ports[0],
+            SwitchPort {
+                interface_id: "Ethernet2".to_string(),
+                walljacks: vec![],
+                enabled: Some(true),
+                oper_status: Some("up".to_string()),
+                vlan: Some("20".to_string()),
+                description: Some("Room 114".to_string()),
+            }
+        );
+        assert_eq!(ports[1].walljacks, vec!["2-114"]);
+        assert_eq!(ports[1].enabled, Some(false));
+        assert_eq!(ports[1].vlan.as_deref(), Some("trunk"));
+        assert!(port_table(&ports, 0)
+            .contains("Ethernet10     2-114        shutdown  -           trunk"));
+    }
+    #[test]
+    fn test_find_device() {
+        let mut device = Device::default();
+        device.key.device_id = "JPE1".to_string();
+        device.hostname = "sw-2f-1".to_string();
+        device.fqdn = "sw-2f-1.example.com".to_string();
+        let devices = vec![device];
+        assert!(find_device(&devices, "JPE1").is_some());
+        assert!(find_device(&devices, "SW-2F-1").is_some());
+        assert!(find_device(&devices, "sw-2f-1.example.com").is_some());
+        assert!(find_device(&devices, "sw-3f-1").is_none());
+        assert!(describe(&devices[0]).contains("Booted: unknown\nStreaming: unknown"));
+    }
+}
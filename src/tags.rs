@@ -0,0 +1,262 @@
+use serde::Deserialize;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::cvp::{
+    self, CloudVisionError, ElementType, InterfaceKey, TagAssignmentConfig, TagConfig,
+    TagConfigKey, TagQuery,
+};
+
+/// The tag label the bot uses to find the interface behind a wall jack
+pub const WALL_JACK: &str = "wall_jack";
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct LookupConfig {
+    // Interface tag labels ports are looked up by. A jack given without a label tries each in
+    // order, and the first is the label /jackassign uses by default.
+    #[serde(default = "default_labels")]
+    pub labels: Vec<String>,
+}
+
+fn default_labels() -> Vec<String> {
+    vec![WALL_JACK.to_string()]
+}
+
+impl Default for LookupConfig {
+    fn default() -> Self {
+        LookupConfig {
+            labels: default_labels(),
+        }
+    }
+}
+
+/// An interface tag assignment, `value` is the wall jack
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Assignment {
+    pub value: String,
+    pub device_id: String,
+    pub interface_id: String,
+}
+
+impl Assignment {
+    pub fn new(value: &str, device_id: &str, interface_id: &str) -> Self {
+        Assignment {
+            value: value.to_string(),
+            device_id: device_id.to_string(),
+            interface_id: interface_id.to_string(),
+        }
+    }
+}
+
+/// Tag assignments to add and remove together in one workspace
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TagChanges {
+    pub label: String,
+    pub add: Vec<Assignment>,
+    pub remove: Vec<Assignment>,
+}
+
+impl TagChanges {
+    pub fn new(label: &str) -> Self {
+        TagChanges {
+            label: label.to_string(),
+            ..TagChanges::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.add.is_empty() && self.remove.is_empty()
+    }
+
+    /// One line per assignment added or removed
+    pub fn describe(&self) -> Vec<String> {
+        let removed = self.remove.iter().map(|a| {
+            format!(
+                "- {} {} from {} on {}",
+                self.label, a.value, a.interface_id, a.device_id
+            )
+        });
+        let added = self.add.iter().map(|a| {
+            format!(
+                "+ {} {} on {} on {}",
+                self.label, a.value, a.interface_id, a.device_id
+            )
+        });
+        removed.chain(added).collect()
+    }
+}
+
+/// Plans tagging an interface with a wall jack, along with the conflicts the user should see
+/// first: the jack already being on other interfaces, which makes this a move, or the
+/// interface already carrying another jack. `current` holds the existing assignments of the
+/// jack and of the interface.
+pub fn plan_assign(
+    label: &str,
+    target: Assignment,
+    current: &[Assignment],
+) -> (TagChanges, Vec<String>) {
+    let mut changes = TagChanges::new(label);
+    let mut conflicts = Vec::new();
+    if current.contains(&target) {
+        return (changes, conflicts);
+    }
+    for existing in current {
+        let same_port =
+            existing.device_id == target.device_id && existing.interface_id == target.interface_id;
+        if existing.value == target.value {
+            conflicts.push(format!(
+                "{} {} is currently on {} on {}, it will be moved",
+                label, existing.value, existing.interface_id, existing.device_id
+            ));
+        } else if same_port {
+            conflicts.push(format!(
+                "{} on {} is currently {} {}, that will be removed",
+                existing.interface_id, existing.device_id, label, existing.value
+            ));
+        } else {
+            continue;
+        }
+        changes.remove.push(existing.clone());
+    }
+    changes.add.push(target);
+    (changes, conflicts)
+}
+
+/// Plans removing every assignment of a wall jack
+pub fn plan_unassign(label: &str, value: &str, current: &[Assignment]) -> TagChanges {
+    let mut changes = TagChanges::new(label);
+    changes.remove = current
+        .iter()
+        .filter(|a| a.value == value)
+        .cloned()
+        .collect();
+    changes
+}
+
+/// Current interface assignments for `label`, narrowed by value and device when given
+pub async fn assignments(
+    cv: &cvp::Host,
+    label: &str,
+    value: Option<&str>,
+    device_id: Option<&str>,
+) -> Result<Vec<Assignment>, CloudVisionError> {
+    let mut query = TagQuery::interface().label(label);
+    if let Some(value) = value {
+        query = query.value(value);
+    }
+    if let Some(device_id) = device_id {
+        query = query.device_id(device_id);
+    }
+    let body = cv.get_tag_assignment_config(&query).await?;
+    let results: Vec<cvp::TagAssignmentConfigResponse> = cvp::parse_stream(&body)?;
+    // Only mainline assignments, not ones pending in a workspace
+    Ok(results
+        .into_iter()
+        .map(|r| r.result.value.key)
+        .filter(|key| key.workspace_id.is_empty() && !key.interface_id.is_empty())
+        .map(|key| Assignment::new(&key.value, &key.device_id, &key.interface_id))
+        .collect())
+}
+
+/// Makes the changes in a new workspace, then builds and submits it, returning the workspace id.
+/// A workspace that fails to build is abandoned.
+pub async fn apply(
+    cv: &cvp::Host,
+    changes: &TagChanges,
+    description: &str,
+) -> Result<String, CloudVisionError> {
+    let workspace_id = Uuid::new_v4().to_string();
+    let name = format!("{} tags {}", changes.label, &workspace_id[..8]);
+    cv.create_workspace(&workspace_id, &name, description)
+        .await?;
+    let result = edit_workspace(cv, &workspace_id, changes).await;
+    if let Err(err) = result {
+        if let Err(abandon_err) = cv.abandon_workspace(&workspace_id).await {
+            error!(
+                "Error abandoning workspace {}: {}",
+                workspace_id, abandon_err
+            );
+        }
+        return Err(err);
+    }
+    cv.submit_workspace(&workspace_id).await?;
+    Ok(workspace_id)
+}
+
+async fn edit_workspace(
+    cv: &cvp::Host,
+    workspace_id: &str,
+    changes: &TagChanges,
+) -> Result<(), CloudVisionError> {
+    let mut values: Vec<&str> = changes.add.iter().map(|a| a.value.as_str()).collect();
+    values.sort_unstable();
+    values.dedup();
+    // Creating a tag that already exists is a no-op
+    for value in values {
+        let key = TagConfigKey {
+            workspace_id: workspace_id.to_string(),
+            element_type: ElementType::Interface,
+            label: changes.label.clone(),
+            value: value.to_string(),
+        };
+        cv.set_tag(TagConfig { key }).await?;
+    }
+    let removes = changes.remove.iter().map(|a| (a, true));
+    let adds = changes.add.iter().map(|a| (a, false));
+    for (assignment, remove) in removes.chain(adds) {
+        let key = InterfaceKey {
+            workspace_id: workspace_id.to_string(),
+            element_type: ElementType::Interface,
+            label: changes.label.clone(),
+            value: assignment.value.clone(),
+            device_id: assignment.device_id.clone(),
+            interface_id: assignment.interface_id.clone(),
+        };
+        cv.set_tag_assignment(TagAssignmentConfig { key, remove })
+            .await?;
+    }
+    cv.build_workspace(workspace_id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_plan_assign() {
+        let target = Assignment::new("2-114", "JPE1", "Ethernet1");
+        let (changes, conflicts) = plan_assign(WALL_JACK, target.clone(), &[]);
+        assert_eq!(changes.add, vec![target.clone()]);
+        assert!(changes.remove.is_empty());
+        assert!(conflicts.is_empty());
+
+        // Already assigned, nothing to do
+        let (changes, _) = plan_assign(WALL_JACK, target.clone(), std::slice::from_ref(&target));
+        assert!(changes.is_empty());
+    }
+    #[test]
+    fn test_plan_assign_conflicts() {
+        let target = Assignment::new("2-114", "JPE1", "Ethernet1");
+        let moved = Assignment::new("2-114", "JPE2", "Ethernet7");
+        let replaced = Assignment::new("2-115", "JPE1", "Ethernet1");
+        let unrelated = Assignment::new("2-116", "JPE1", "Ethernet2");
+        let current = vec![moved.clone(), replaced.clone(), unrelated];
+        let (changes, conflicts) = plan_assign(WALL_JACK, target.clone(), &current);
+        assert_eq!(changes.remove, vec![moved, replaced]);
+        assert_eq!(changes.add, vec![target]);
+        assert_eq!(conflicts.len(), 2);
+        assert_eq!(
+            changes.describe()[0],
+            "- wall_jack 2-114 from Ethernet7 on JPE2"
+        );
+    }
+    #[test]
+    fn test_plan_unassign() {
+        let current = vec![
+            Assignment::new("2-114", "JPE1", "Ethernet1"),
+            Assignment::new("2-115", "JPE1", "Ethernet2"),
+        ];
+        let changes = plan_unassign(WALL_JACK, "2-114", &current);
+        assert_eq!(changes.remove, vec![current[0].clone()]);
+        assert!(changes.add.is_empty());
+    }
+}